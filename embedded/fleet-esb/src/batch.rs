@@ -0,0 +1,215 @@
+//! Packing several small messages into one frame
+//!
+//! Every frame pays for a nonce and tag, and a round of encryption, however
+//! small the message is. Keepalives, status messages and acks are often only
+//! a few bytes, so when several are waiting, they are sent together in a
+//! single batch frame instead.
+//!
+//! After the batch header, each message is prefixed with its length as a
+//! single byte. Only messages that fit into one frame are batched, larger
+//! messages are fragmented as usual.
+
+use crate::{
+    fragment::{max_plaintext, WHOLE_HEADER_SIZE},
+    frame::MAX_FRAME_SIZE,
+    Error,
+};
+
+/// The size of the length prefix of each message in a batch
+pub const BATCH_PREFIX_SIZE: usize = 1;
+
+/// Messages waiting to be sent together, in a single frame
+pub struct Batch {
+    buf: [u8; MAX_FRAME_SIZE],
+    used: usize,
+    count: usize,
+    pipe: u8,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Batch {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0u8; MAX_FRAME_SIZE],
+            used: 0,
+            count: 0,
+            pipe: 0,
+        }
+    }
+
+    /// The number of messages in the batch
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// The pipe the batch will be sent on
+    pub fn pipe(&self) -> u8 {
+        self.pipe
+    }
+
+    /// Could this message be added to the batch, which is sent in frames
+    /// of `frame_size` bytes?
+    pub fn fits(&self, msg: &[u8], frame_size: usize) -> bool {
+        let needed = WHOLE_HEADER_SIZE + self.used + BATCH_PREFIX_SIZE + msg.len();
+        needed <= max_plaintext(frame_size)
+    }
+
+    /// Add a message for the given pipe. Returns `false`, without adding the
+    /// message, if it doesn't fit, or the batch is for another pipe.
+    pub fn push(&mut self, msg: &[u8], pipe: u8, frame_size: usize) -> bool {
+        if (!self.is_empty() && pipe != self.pipe) || !self.fits(msg, frame_size) {
+            return false;
+        }
+
+        self.buf[self.used] = msg.len() as u8;
+        self.buf[self.used + BATCH_PREFIX_SIZE..][..msg.len()].copy_from_slice(msg);
+        self.used += BATCH_PREFIX_SIZE + msg.len();
+        self.count += 1;
+        self.pipe = pipe;
+        true
+    }
+
+    /// The messages, to be sent after a batch header
+    pub fn body(&self) -> &[u8] {
+        &self.buf[..self.used]
+    }
+
+    /// The message, if the batch contains exactly one. This is cheaper to
+    /// send as a whole message.
+    pub fn single(&self) -> Option<&[u8]> {
+        if self.count == 1 {
+            Some(&self.body()[BATCH_PREFIX_SIZE..])
+        } else {
+            None
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.used = 0;
+        self.count = 0;
+    }
+}
+
+/// A received batch, handed out one message at a time
+pub(crate) struct RxBatch {
+    buf: [u8; MAX_FRAME_SIZE],
+    len: usize,
+    pos: usize,
+    pipe: u8,
+}
+
+impl RxBatch {
+    pub(crate) const fn new() -> Self {
+        Self {
+            buf: [0u8; MAX_FRAME_SIZE],
+            len: 0,
+            pos: 0,
+            pipe: 0,
+        }
+    }
+
+    /// Replace any remaining messages with the body of a new batch frame
+    pub(crate) fn load(&mut self, pipe: u8, body: &[u8]) {
+        let len = body.len().min(MAX_FRAME_SIZE);
+        self.buf[..len].copy_from_slice(&body[..len]);
+        self.len = len;
+        self.pos = 0;
+        self.pipe = pipe;
+    }
+
+    /// The next message, and the pipe it was received on
+    pub(crate) fn next_message(&mut self) -> Option<Result<(u8, &[u8]), Error>> {
+        let body = &self.buf[self.pos..self.len];
+        match unbatch(body).next()? {
+            Ok(msg) => {
+                self.pos += BATCH_PREFIX_SIZE + msg.len();
+                Some(Ok((self.pipe, msg)))
+            }
+            Err(e) => {
+                self.pos = self.len;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Split the body of a batch frame back into messages
+pub fn unbatch(body: &[u8]) -> Unbatch<'_> {
+    Unbatch { body }
+}
+
+/// An iterator over the messages of a batch, created by `unbatch()`
+pub struct Unbatch<'a> {
+    body: &'a [u8],
+}
+
+impl<'a> Iterator for Unbatch<'a> {
+    type Item = Result<&'a [u8], Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (len, rest) = self.body.split_first()?;
+        let len = usize::from(*len);
+
+        if rest.len() < len {
+            // Don't return anything else from a malformed batch
+            self.body = &[];
+            return Some(Err(Error::BadFragment));
+        }
+
+        let (msg, rest) = rest.split_at(len);
+        self.body = rest;
+        Some(Ok(msg))
+    }
+}
+
+#[test]
+fn batch_roundtrip() {
+    let mut batch = Batch::new();
+    assert!(batch.single().is_none());
+
+    assert!(batch.push(b"hello", 2, 64));
+    assert_eq!(batch.single(), Some(&b"hello"[..]));
+    assert!(batch.push(b"", 2, 64));
+    assert!(batch.push(b"world", 2, 64));
+    assert_eq!(batch.count(), 3);
+    assert!(batch.single().is_none());
+
+    // Only messages for the same pipe are batched
+    assert!(!batch.push(b"other", 3, 64));
+
+    let mut msgs = unbatch(batch.body());
+    assert_eq!(msgs.next().unwrap().unwrap(), b"hello");
+    assert_eq!(msgs.next().unwrap().unwrap(), b"");
+    assert_eq!(msgs.next().unwrap().unwrap(), b"world");
+    assert!(msgs.next().is_none());
+
+    batch.clear();
+    assert!(batch.is_empty());
+    assert!(batch.push(b"other", 3, 64));
+    assert_eq!(batch.pipe(), 3);
+}
+
+#[test]
+fn batch_limits() {
+    // 64 byte frames leave 36 bytes of plaintext, 35 after the header
+    let mut batch = Batch::new();
+    assert!(batch.push(&[0u8; 20], 0, 64));
+    assert!(!batch.push(&[0u8; 14], 0, 64));
+    assert!(batch.push(&[0u8; 13], 0, 64));
+    assert_eq!(batch.body().len(), 35);
+
+    // A truncated batch returns an error, and nothing after it
+    let mut msgs = unbatch(&[2, 0xAA, 0xBB, 5, 0xCC]);
+    assert_eq!(msgs.next().unwrap().unwrap(), &[0xAA, 0xBB]);
+    assert!(msgs.next().unwrap().is_err());
+    assert!(msgs.next().is_none());
+}
@@ -0,0 +1,134 @@
+//! Selection of the AEAD used to protect fleet frames
+//!
+//! All peers in a fleet must use the same cipher suite. The suite is
+//! carried in the protocol version bits of every nonce, so a peer using
+//! a different suite is rejected with `Error::CipherSuiteMismatch`,
+//! rather than a generic decryption failure.
+
+use chacha20poly1305::aead::{
+    generic_array::{
+        typenum::consts::{U12, U16},
+        GenericArray,
+    },
+    AeadInPlace, NewAead,
+};
+
+pub use chacha20poly1305::{ChaCha20Poly1305, ChaCha8Poly1305};
+
+#[cfg(feature = "aes-gcm-siv")]
+pub use aes_gcm_siv::Aes128GcmSiv;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherSuite {
+    ChaCha8Poly1305 = 0,
+    ChaCha20Poly1305 = 1,
+    Aes128GcmSiv = 2,
+}
+
+impl CipherSuite {
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0 => Some(CipherSuite::ChaCha8Poly1305),
+            1 => Some(CipherSuite::ChaCha20Poly1305),
+            2 => Some(CipherSuite::Aes128GcmSiv),
+            _ => None,
+        }
+    }
+
+    pub fn bits(self) -> u8 {
+        self as u8
+    }
+}
+
+/// An AEAD that can be used by the fleet radios
+///
+/// This is implemented for the ciphers we support, and is not expected
+/// to be implemented outside of this crate.
+pub trait FleetCipher: AeadInPlace<NonceSize = U12, TagSize = U16> {
+    /// The suite identifier sent with each frame
+    const SUITE: CipherSuite;
+
+    /// Create the cipher from a 32 byte fleet key
+    fn from_key(key: &[u8; 32]) -> Self;
+}
+
+impl FleetCipher for chacha20poly1305::ChaCha8Poly1305 {
+    const SUITE: CipherSuite = CipherSuite::ChaCha8Poly1305;
+
+    fn from_key(key: &[u8; 32]) -> Self {
+        Self::new(&GenericArray::clone_from_slice(key))
+    }
+}
+
+impl FleetCipher for chacha20poly1305::ChaCha20Poly1305 {
+    const SUITE: CipherSuite = CipherSuite::ChaCha20Poly1305;
+
+    fn from_key(key: &[u8; 32]) -> Self {
+        Self::new(&GenericArray::clone_from_slice(key))
+    }
+}
+
+#[cfg(feature = "aes-gcm-siv")]
+impl FleetCipher for aes_gcm_siv::Aes128GcmSiv {
+    const SUITE: CipherSuite = CipherSuite::Aes128GcmSiv;
+
+    /// AES-128 only takes a 16 byte key, so the first half of the
+    /// fleet key is used
+    fn from_key(key: &[u8; 32]) -> Self {
+        Self::new(&GenericArray::clone_from_slice(&key[..16]))
+    }
+}
+
+#[test]
+fn suite_bits_roundtrip() {
+    for suite in &[
+        CipherSuite::ChaCha8Poly1305,
+        CipherSuite::ChaCha20Poly1305,
+        CipherSuite::Aes128GcmSiv,
+    ] {
+        assert_eq!(CipherSuite::from_bits(suite.bits()), Some(*suite));
+    }
+    assert_eq!(CipherSuite::from_bits(3), None);
+}
+
+#[cfg(test)]
+fn suite_roundtrip<C: FleetCipher>() {
+    use crate::LilBuf;
+
+    let key = [0x5A; 32];
+    let nonce = [0xA5; 12];
+    let nonce = GenericArray::from_slice(&nonce);
+
+    let mut storage = [0u8; 64];
+    storage[..5].copy_from_slice(b"hello");
+    let mut buf = LilBuf {
+        buf: &mut storage,
+        used: 5,
+    };
+
+    C::from_key(&key)
+        .encrypt_in_place(nonce, b"aad", &mut buf)
+        .unwrap();
+    assert_eq!(buf.used, 5 + 16);
+
+    C::from_key(&key)
+        .decrypt_in_place(nonce, b"aad", &mut buf)
+        .unwrap();
+    assert_eq!(&storage[..5], b"hello");
+}
+
+#[test]
+fn chacha8_roundtrip() {
+    suite_roundtrip::<chacha20poly1305::ChaCha8Poly1305>();
+}
+
+#[test]
+fn chacha20_roundtrip() {
+    suite_roundtrip::<chacha20poly1305::ChaCha20Poly1305>();
+}
+
+#[cfg(feature = "aes-gcm-siv")]
+#[test]
+fn aes128gcmsiv_roundtrip() {
+    suite_roundtrip::<aes_gcm_siv::Aes128GcmSiv>();
+}
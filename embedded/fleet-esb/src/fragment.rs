@@ -0,0 +1,581 @@
+//! Splitting messages across several frames
+//!
+//! The plaintext of every frame starts with a fragment header. A message
+//! that fits into a single frame only has a one byte header. Larger messages
+//! are split into up to `MAX_FRAGMENTS` numbered fragments, which are
+//! reassembled by the receiver into a fixed size buffer.
+//!
+//! Each fragment is sent as a separate frame, with its own nonce, so lost or
+//! replayed fragments are handled by the nonce checks like any other frame.
+//! A partially received message is dropped when a fragment of a different
+//! message arrives on the same pipe, or when it is not completed within the
+//! timeout.
+//!
+//! A frame may also carry no message at all. Such a poll frame only gives the
+//! PRX a chance to answer with its ACK. Or it may carry several small
+//! messages, see the `batch` module. Or a session handshake message, which
+//! is never fragmented, see `FleetRadio::send_handshake`. The top bit of the
+//! first header byte is set by a sender that has more frames waiting for the
+//! receiver.
+
+use core::{
+    cmp::min,
+    ops::{Deref, DerefMut},
+};
+
+use postcard::{to_slice, Error as PostcardError};
+use serde::Serialize;
+
+use crate::{frame::MAX_FRAME_SIZE, Error, MIN_CRYPT_SIZE};
+
+/// The largest serialized message that can be sent or received
+pub const MAX_MESSAGE_SIZE: usize = 1024;
+
+/// The largest number of fragments a message may be split into
+pub const MAX_FRAGMENTS: usize = 16;
+
+/// Set in the first header byte when the sender has more frames waiting,
+/// so the receiver should poll again soon
+pub const MORE_PENDING: u8 = 0x80;
+
+// The first header byte of a frame containing a batch of messages. This
+// can't be confused with a fragment count, which is at most MAX_FRAGMENTS.
+const BATCH_MARKER: u8 = 0x40;
+
+// The first header byte of a frame containing a session handshake message
+const HANDSHAKE_MARKER: u8 = 0x20;
+
+/// The size of the header of a message sent in a single frame
+pub const WHOLE_HEADER_SIZE: usize = 1;
+
+/// The size of the header of a fragment
+pub const FRAGMENT_HEADER_SIZE: usize = 4;
+
+/// The plaintext space available in a frame of the given size
+pub fn max_plaintext(frame_size: usize) -> usize {
+    min(frame_size, MAX_FRAME_SIZE).saturating_sub(MIN_CRYPT_SIZE)
+}
+
+/// Serialize a message into a scratch buffer, before it is split into frames.
+/// The buffer is usually `MAX_MESSAGE_SIZE` bytes long.
+pub fn serialize<'a, T: Serialize>(msg: &T, buf: &'a mut [u8]) -> Result<&'a [u8], Error> {
+    match to_slice(msg, buf) {
+        Ok(used) => Ok(used),
+        Err(PostcardError::SerializeBufferFull) => Err(Error::MessageTooLarge),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// One fragment of a larger message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fragment {
+    /// Identifies the message, chosen by the sender
+    pub msg_id: u8,
+
+    /// The position of this fragment in the message
+    pub index: u8,
+
+    /// The total number of fragments in the message
+    pub count: u8,
+
+    /// The length of every fragment except the last, which may be shorter
+    pub chunk_len: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentHeader {
+    /// The frame contains no message, and is only sent to poll the PRX
+    Poll,
+
+    /// The frame contains a complete message
+    Whole,
+
+    /// The frame contains several complete messages
+    Batch,
+
+    /// The frame contains a complete session handshake message
+    Handshake,
+
+    /// The frame contains part of a message
+    Part(Fragment),
+}
+
+impl FragmentHeader {
+    pub fn size(&self) -> usize {
+        match self {
+            FragmentHeader::Poll
+            | FragmentHeader::Whole
+            | FragmentHeader::Batch
+            | FragmentHeader::Handshake => WHOLE_HEADER_SIZE,
+            FragmentHeader::Part(_) => FRAGMENT_HEADER_SIZE,
+        }
+    }
+
+    /// Write the header to the start of `buf`, returning the size of the header
+    pub fn write(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() < self.size() {
+            return Err(Error::MessageTooLarge);
+        }
+
+        match self {
+            FragmentHeader::Poll => {
+                buf[0] = 0;
+            }
+            FragmentHeader::Whole => {
+                buf[0] = 1;
+            }
+            FragmentHeader::Batch => {
+                buf[0] = BATCH_MARKER;
+            }
+            FragmentHeader::Handshake => {
+                buf[0] = HANDSHAKE_MARKER;
+            }
+            FragmentHeader::Part(frag) => {
+                buf[0] = frag.count;
+                buf[1] = frag.msg_id;
+                buf[2] = frag.index;
+                buf[3] = frag.chunk_len;
+            }
+        }
+
+        Ok(self.size())
+    }
+
+    /// Parse the header at the start of `buf`, returning the header and
+    /// the rest of the plaintext. The `MORE_PENDING` flag is ignored.
+    pub fn parse(buf: &[u8]) -> Result<(Self, &[u8]), Error> {
+        match buf.first().map(|b| b & !MORE_PENDING) {
+            Some(0) if buf.len() == WHOLE_HEADER_SIZE => Ok((FragmentHeader::Poll, &[])),
+            Some(1) => Ok((FragmentHeader::Whole, &buf[WHOLE_HEADER_SIZE..])),
+            Some(BATCH_MARKER) => Ok((FragmentHeader::Batch, &buf[WHOLE_HEADER_SIZE..])),
+            Some(HANDSHAKE_MARKER) => Ok((FragmentHeader::Handshake, &buf[WHOLE_HEADER_SIZE..])),
+            Some(count)
+                if count > 1
+                    && usize::from(count) <= MAX_FRAGMENTS
+                    && buf.len() >= FRAGMENT_HEADER_SIZE
+                    && buf[2] < count
+                    && buf[3] != 0 =>
+            {
+                let frag = Fragment {
+                    count,
+                    msg_id: buf[1],
+                    index: buf[2],
+                    chunk_len: buf[3],
+                };
+                Ok((FragmentHeader::Part(frag), &buf[FRAGMENT_HEADER_SIZE..]))
+            }
+            _ => Err(Error::BadFragment),
+        }
+    }
+}
+
+/// Split a serialized message into frames with at most `max_plaintext`
+/// bytes each, including the header.
+///
+/// A message that fits into a single frame is not fragmented.
+pub fn fragments(msg: &[u8], msg_id: u8, max_plaintext: usize) -> Result<Fragments<'_>, Error> {
+    if msg.len() + WHOLE_HEADER_SIZE <= max_plaintext {
+        return Ok(Fragments {
+            msg,
+            msg_id,
+            chunk_len: msg.len(),
+            index: 0,
+            count: 1,
+        });
+    }
+
+    let chunk_len = min(max_plaintext.saturating_sub(FRAGMENT_HEADER_SIZE), 255);
+    if chunk_len == 0 {
+        return Err(Error::MessageTooLarge);
+    }
+
+    let count = msg.len().div_ceil(chunk_len);
+    if count > MAX_FRAGMENTS || msg.len() > MAX_MESSAGE_SIZE {
+        return Err(Error::MessageTooLarge);
+    }
+
+    Ok(Fragments {
+        msg,
+        msg_id,
+        chunk_len,
+        index: 0,
+        count: count as u8,
+    })
+}
+
+/// An iterator over the headers and contents of the frames of a message,
+/// created by `fragments()`
+pub struct Fragments<'a> {
+    msg: &'a [u8],
+    msg_id: u8,
+    chunk_len: usize,
+    index: u8,
+    count: u8,
+}
+
+impl<'a> Iterator for Fragments<'a> {
+    type Item = (FragmentHeader, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.count {
+            return None;
+        }
+
+        if self.count == 1 {
+            self.index = 1;
+            return Some((FragmentHeader::Whole, self.msg));
+        }
+
+        let start = usize::from(self.index) * self.chunk_len;
+        let end = min(start + self.chunk_len, self.msg.len());
+
+        let header = FragmentHeader::Part(Fragment {
+            msg_id: self.msg_id,
+            index: self.index,
+            count: self.count,
+            chunk_len: self.chunk_len as u8,
+        });
+
+        self.index += 1;
+
+        Some((header, &self.msg[start..end]))
+    }
+}
+
+/// A reassembled message, or one taken from a batch
+pub struct Message {
+    buf: [u8; MAX_MESSAGE_SIZE],
+    len: usize,
+}
+
+impl Message {
+    pub(crate) fn from_slice(data: &[u8]) -> Self {
+        let len = min(data.len(), MAX_MESSAGE_SIZE);
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        buf[..len].copy_from_slice(&data[..len]);
+        Self { buf, len }
+    }
+}
+
+impl Deref for Message {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl DerefMut for Message {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.len]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Partial {
+    msg_id: u8,
+    count: u8,
+    chunk_len: u8,
+
+    // One bit per received fragment
+    received: u16,
+
+    // The total length, once the last fragment has been received
+    len: usize,
+
+    // The tick of the first fragment received
+    started: u32,
+}
+
+/// Reassembles the fragments of one message at a time
+pub struct Reassembler {
+    buf: [u8; MAX_MESSAGE_SIZE],
+    partial: Option<Partial>,
+    timeout: u32,
+}
+
+impl Reassembler {
+    /// Create a new reassembler. A message will be dropped if it is not
+    /// complete `timeout` ticks after its first fragment was received.
+    pub const fn new(timeout: u32) -> Self {
+        Self {
+            buf: [0u8; MAX_MESSAGE_SIZE],
+            partial: None,
+            timeout,
+        }
+    }
+
+    /// Is there a partially received message?
+    pub fn is_pending(&self) -> bool {
+        self.partial.is_some()
+    }
+
+    /// Drop the partially received message, if any
+    pub fn reset(&mut self) {
+        self.partial = None;
+    }
+
+    /// Drop the partially received message if it has timed out
+    pub fn expire(&mut self, now: u32) {
+        if let Some(partial) = self.partial {
+            if expired(partial.started, now, self.timeout) {
+                self.partial = None;
+            }
+        }
+    }
+
+    /// Add a received fragment. `tick` is the time the fragment was received,
+    /// or sent, as long as the same clock is used for every fragment.
+    ///
+    /// Once all fragments of a message have been received, the complete
+    /// message is returned.
+    pub fn push(
+        &mut self,
+        frag: Fragment,
+        data: &[u8],
+        tick: u32,
+    ) -> Result<Option<Message>, Error> {
+        let chunk_len = usize::from(frag.chunk_len);
+        let offset = usize::from(frag.index) * chunk_len;
+        let last = frag.index + 1 == frag.count;
+
+        let bad = frag.index >= frag.count
+            || usize::from(frag.count) > MAX_FRAGMENTS
+            || data.len() > chunk_len
+            || (!last && data.len() != chunk_len)
+            || offset + data.len() > MAX_MESSAGE_SIZE;
+
+        if bad {
+            return Err(Error::BadFragment);
+        }
+
+        // Start over if this is a fragment of a different message
+        let fresh = match self.partial {
+            Some(p) => {
+                p.msg_id != frag.msg_id
+                    || p.count != frag.count
+                    || p.chunk_len != frag.chunk_len
+                    || expired(p.started, tick, self.timeout)
+            }
+            None => true,
+        };
+
+        if fresh {
+            self.partial = Some(Partial {
+                msg_id: frag.msg_id,
+                count: frag.count,
+                chunk_len: frag.chunk_len,
+                received: 0,
+                len: 0,
+                started: tick,
+            });
+        }
+
+        let complete = match self.partial {
+            Some(ref mut partial) => {
+                let bit = 1u16 << frag.index;
+                if partial.received & bit == 0 {
+                    self.buf[offset..][..data.len()].copy_from_slice(data);
+                    partial.received |= bit;
+
+                    if last {
+                        partial.len = offset + data.len();
+                    }
+                }
+
+                if partial.received.count_ones() == u32::from(partial.count) {
+                    Some(partial.len)
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+
+        Ok(complete.map(|len| {
+            self.partial = None;
+            Message { buf: self.buf, len }
+        }))
+    }
+}
+
+fn expired(started: u32, now: u32, timeout: u32) -> bool {
+    let age = now.wrapping_sub(started);
+
+    // Ticks from "before" the start (e.g. a reordered frame) are not expired
+    age > timeout && age < 0x8000_0000
+}
+
+#[cfg(test)]
+fn collect_fragments(frags: Fragments<'_>) -> ([(FragmentHeader, &[u8]); MAX_FRAGMENTS], usize) {
+    let mut out = [(FragmentHeader::Whole, &[][..]); MAX_FRAGMENTS];
+    let mut count = 0;
+    for (slot, frag) in out.iter_mut().zip(frags) {
+        *slot = frag;
+        count += 1;
+    }
+    (out, count)
+}
+
+#[test]
+fn fragment_small_message_whole() {
+    let msg = [0xAAu8; 10];
+    let mut frags = fragments(&msg, 0, 64).unwrap();
+    assert_eq!(frags.next(), Some((FragmentHeader::Whole, &msg[..])));
+    assert_eq!(frags.next(), None);
+
+    let mut buf = [0u8; 16];
+    let used = FragmentHeader::Whole.write(&mut buf).unwrap();
+    buf[used..][..msg.len()].copy_from_slice(&msg);
+    let (header, rest) = FragmentHeader::parse(&buf[..used + msg.len()]).unwrap();
+    assert_eq!(header, FragmentHeader::Whole);
+    assert_eq!(rest, &msg[..]);
+
+    // Handshakes only differ in their header
+    assert_eq!(FragmentHeader::Handshake.write(&mut buf).unwrap(), used);
+    let (header, rest) = FragmentHeader::parse(&buf[..used + msg.len()]).unwrap();
+    assert_eq!(header, FragmentHeader::Handshake);
+    assert_eq!(rest, &msg[..]);
+}
+
+#[test]
+fn fragment_roundtrip() {
+    let mut msg = [0u8; 600];
+    for (i, b) in msg.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+
+    let (frames, count) = collect_fragments(fragments(&msg, 7, 128).unwrap());
+    assert_eq!(count, 5);
+
+    // Deliver them out of order
+    let mut reasm = Reassembler::new(100);
+    for &i in &[4, 0, 2, 1] {
+        let (header, data) = frames[i];
+        let frag = match header {
+            FragmentHeader::Part(frag) => frag,
+            _ => panic!(),
+        };
+        assert!(reasm.push(frag, data, 10).unwrap().is_none());
+        assert!(reasm.is_pending());
+    }
+
+    let (header, data) = frames[3];
+    let frag = match header {
+        FragmentHeader::Part(frag) => frag,
+        _ => panic!(),
+    };
+
+    // Write and parse the header, as it would be sent
+    let mut buf = [0u8; 128];
+    let used = header.write(&mut buf).unwrap();
+    buf[used..][..data.len()].copy_from_slice(data);
+    let (parsed, rest) = FragmentHeader::parse(&buf[..used + data.len()]).unwrap();
+    assert_eq!(parsed, FragmentHeader::Part(frag));
+
+    let done = reasm.push(frag, rest, 11).unwrap().unwrap();
+    assert_eq!(&done[..], &msg[..]);
+    assert!(!reasm.is_pending());
+}
+
+#[test]
+fn fragment_timeout_and_new_message() {
+    let msg = [0x55u8; 300];
+    let (frames, _) = collect_fragments(fragments(&msg, 1, 128).unwrap());
+    let part = |i: usize| match frames[i] {
+        (FragmentHeader::Part(frag), data) => (frag, data),
+        _ => panic!(),
+    };
+
+    let mut reasm = Reassembler::new(100);
+    let (frag, data) = part(0);
+    assert!(reasm.push(frag, data, 1000).unwrap().is_none());
+
+    // Too late, the message starts over
+    let (frag, data) = part(1);
+    assert!(reasm.push(frag, data, 1101).unwrap().is_none());
+    let (frag, data) = part(2);
+    assert!(reasm.push(frag, data, 1102).unwrap().is_none());
+
+    // The first fragment again completes it
+    let (frag, data) = part(0);
+    assert_eq!(
+        &reasm.push(frag, data, 1103).unwrap().unwrap()[..],
+        &msg[..]
+    );
+
+    // A fragment of another message drops the partial one
+    let (frag, data) = part(0);
+    assert!(reasm.push(frag, data, 2000).unwrap().is_none());
+    let (mut frag, data) = part(1);
+    frag.msg_id = 2;
+    assert!(reasm.push(frag, data, 2001).unwrap().is_none());
+    let (frag, data) = part(2);
+    assert!(reasm.push(frag, data, 2002).unwrap().is_none());
+
+    // Expiry without a new fragment
+    assert!(reasm.is_pending());
+    reasm.expire(2103);
+    assert!(!reasm.is_pending());
+}
+
+#[test]
+fn fragment_limits() {
+    let msg = [0u8; MAX_MESSAGE_SIZE + 1];
+    match fragments(&msg, 0, 227) {
+        Err(Error::MessageTooLarge) => {}
+        _ => panic!(),
+    }
+
+    // Inconsistent fragments are rejected
+    let mut reasm = Reassembler::new(100);
+    let frag = Fragment {
+        msg_id: 0,
+        index: 0,
+        count: 2,
+        chunk_len: 10,
+    };
+    match reasm.push(frag, &[0u8; 9], 0) {
+        Err(Error::BadFragment) => {}
+        _ => panic!(),
+    }
+
+    match FragmentHeader::parse(&[0, 1, 2, 3]) {
+        Err(Error::BadFragment) => {}
+        _ => panic!(),
+    }
+    match FragmentHeader::parse(&[17, 1, 2, 3]) {
+        Err(Error::BadFragment) => {}
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn fragment_poll_and_pending_flag() {
+    let mut buf = [0u8; 8];
+    assert_eq!(FragmentHeader::Poll.write(&mut buf).unwrap(), 1);
+    assert_eq!(
+        FragmentHeader::parse(&buf[..1]).unwrap(),
+        (FragmentHeader::Poll, &[][..])
+    );
+
+    // A poll never carries a message
+    assert!(FragmentHeader::parse(&buf[..2]).is_err());
+
+    // The flag doesn't change the header
+    buf[0] |= MORE_PENDING;
+    assert_eq!(
+        FragmentHeader::parse(&buf[..1]).unwrap().0,
+        FragmentHeader::Poll
+    );
+
+    let frag = FragmentHeader::Part(Fragment {
+        msg_id: 7,
+        index: 1,
+        count: 3,
+        chunk_len: 100,
+    });
+    frag.write(&mut buf).unwrap();
+    buf[0] |= MORE_PENDING;
+    assert_eq!(FragmentHeader::parse(&buf[..6]).unwrap().0, frag);
+}
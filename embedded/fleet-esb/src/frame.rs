@@ -0,0 +1,295 @@
+//! The fleet frame format, independent of the radio hardware
+//!
+//! Every frame on the air is laid out as `payload || tag || FleetNonce`,
+//! where the payload is encrypted, and the pipe, payload length and network
+//! ID are authenticated as associated data.
+//!
+//! The radios use these functions directly on their ESB grants. On a host,
+//! `FrameCodec` can be used to build or check frames without any radio,
+//! e.g. to decode sniffer captures.
+
+use chacha20poly1305::aead::{generic_array::GenericArray, Buffer};
+
+#[cfg(feature = "std")]
+use crate::fragment::FragmentHeader;
+
+use crate::{
+    associated_data,
+    cipher::FleetCipher,
+    nonce::{FleetNonce, NonceWindow},
+    Error, LilBuf, CRYPT_SIZE, MIN_CRYPT_SIZE, NONCE_SIZE,
+};
+
+#[cfg(feature = "std")]
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(feature = "std")]
+use std::vec::Vec;
+
+/// The largest frame supported by the radio
+pub const MAX_FRAME_SIZE: usize = 255;
+
+/// Encrypt a payload in place, and append the tag and nonce.
+///
+/// `buf[..used]` should contain the serialized payload. On success, the
+/// total length of the frame is returned.
+pub fn seal_in_place<C: FleetCipher>(
+    crypt: &C,
+    network_id: u32,
+    pipe: u8,
+    nonce: &FleetNonce,
+    buf: &mut [u8],
+    used: usize,
+) -> Result<usize, Error> {
+    if used > (MAX_FRAME_SIZE - MIN_CRYPT_SIZE) || used > buf.len() {
+        return Err(Error::MessageTooLarge);
+    }
+
+    let nonce_bytes = nonce.to_bytes(C::SUITE);
+    let ga_nonce = GenericArray::from_slice(&nonce_bytes);
+
+    let mut buf = LilBuf {
+        buf,
+        used: used as u8,
+    };
+
+    // Encrypt
+    let aad = associated_data(network_id, pipe, buf.used);
+    crypt.encrypt_in_place(ga_nonce, &aad, &mut buf)?;
+
+    // Add nonce to payload
+    buf.extend_from_slice(&nonce_bytes)?;
+
+    Ok(buf.used.into())
+}
+
+/// Split a received frame into the encrypted payload (including the tag),
+/// and the parsed nonce. The nonce is NOT checked for freshness.
+pub fn split_frame<C: FleetCipher>(frame: &mut [u8]) -> Result<(&mut [u8], FleetNonce), Error> {
+    // We didn't even get enough bytes for the crypto
+    // header (and a 1 byte payload)
+    if frame.len() <= MIN_CRYPT_SIZE || frame.len() > MAX_FRAME_SIZE {
+        return Err(Error::PacketTooSmol);
+    }
+
+    let payload_len = frame.len() - NONCE_SIZE;
+    let (payload, nonce_bytes) = frame.split_at_mut(payload_len);
+    let nonce = FleetNonce::try_from_bytes(nonce_bytes, C::SUITE)?;
+
+    Ok((payload, nonce))
+}
+
+/// Decrypt a payload (as returned by `split_frame`) in place.
+///
+/// On success, the length of the plaintext is returned. On failure, the
+/// contents of `payload` are unspecified.
+pub fn open_in_place<C: FleetCipher>(
+    crypt: &C,
+    network_id: u32,
+    pipe: u8,
+    nonce: &FleetNonce,
+    payload: &mut [u8],
+) -> Result<usize, Error> {
+    if payload.len() <= CRYPT_SIZE {
+        return Err(Error::PacketTooSmol);
+    }
+
+    let nonce_bytes = nonce.to_bytes(C::SUITE);
+    let ga_nonce = GenericArray::from_slice(&nonce_bytes);
+    let aad = associated_data(network_id, pipe, (payload.len() - CRYPT_SIZE) as u8);
+
+    let mut buf = LilBuf {
+        used: payload.len() as u8,
+        buf: payload,
+    };
+
+    crypt.decrypt_in_place(ga_nonce, &aad, &mut buf)?;
+
+    Ok(buf.used.into())
+}
+
+/// Builds and checks fleet frames, without a radio
+pub struct FrameCodec<C: FleetCipher> {
+    crypt: C,
+    network_id: u32,
+}
+
+impl<C: FleetCipher> FrameCodec<C> {
+    pub fn new(key: &[u8; 32], network_id: u32) -> Self {
+        Self {
+            crypt: C::from_key(key),
+            network_id,
+        }
+    }
+
+    /// See `seal_in_place`
+    pub fn seal(
+        &self,
+        pipe: u8,
+        nonce: &FleetNonce,
+        buf: &mut [u8],
+        used: usize,
+    ) -> Result<usize, Error> {
+        seal_in_place(&self.crypt, self.network_id, pipe, nonce, buf, used)
+    }
+
+    /// Decrypt a complete frame in place. The nonce is NOT checked for freshness.
+    ///
+    /// On success, the nonce and the length of the plaintext at the start
+    /// of `frame` are returned.
+    pub fn open(&self, pipe: u8, frame: &mut [u8]) -> Result<(FleetNonce, usize), Error> {
+        let (payload, nonce) = split_frame::<C>(frame)?;
+        let len = open_in_place(&self.crypt, self.network_id, pipe, &nonce, payload)?;
+        Ok((nonce, len))
+    }
+
+    /// Decrypt a complete frame in place, checking the nonce against (and
+    /// updating) the given window. See `NonceWindow::check` for the meaning
+    /// of `now`.
+    pub fn open_checked(
+        &self,
+        pipe: u8,
+        frame: &mut [u8],
+        window: &mut NonceWindow,
+        now: Option<u32>,
+    ) -> Result<(FleetNonce, usize), Error> {
+        let (payload, nonce) = split_frame::<C>(frame)?;
+        window.check(&nonce, now)?;
+        let len = open_in_place(&self.crypt, self.network_id, pipe, &nonce, payload)?;
+        window.accept(&nonce);
+        Ok((nonce, len))
+    }
+
+    /// Serialize and encrypt a message into a new frame. The message
+    /// must fit into a single frame.
+    #[cfg(feature = "std")]
+    pub fn encode<T: Serialize>(
+        &self,
+        pipe: u8,
+        nonce: &FleetNonce,
+        msg: &T,
+    ) -> Result<Vec<u8>, Error> {
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let start = FragmentHeader::Whole.write(&mut buf)?;
+        let used = start + postcard::to_slice(msg, &mut buf[start..])?.len();
+        let len = self.seal(pipe, nonce, &mut buf, used)?;
+        Ok(buf[..len].to_vec())
+    }
+
+    /// Decrypt and deserialize a frame containing a complete message. The
+    /// nonce is NOT checked for freshness.
+    #[cfg(feature = "std")]
+    pub fn decode<T: DeserializeOwned>(
+        &self,
+        pipe: u8,
+        frame: &[u8],
+    ) -> Result<(FleetNonce, T), Error> {
+        let mut buf = frame.to_vec();
+        let (nonce, len) = self.open(pipe, &mut buf)?;
+        let msg = match FragmentHeader::parse(&buf[..len])? {
+            (FragmentHeader::Whole, payload) | (FragmentHeader::Handshake, payload) => {
+                postcard::from_bytes(payload)?
+            }
+            (FragmentHeader::Poll, _)
+            | (FragmentHeader::Batch, _)
+            | (FragmentHeader::Part(_), _) => return Err(Error::BadFragment),
+        };
+        Ok((nonce, msg))
+    }
+}
+
+#[test]
+fn frame_roundtrip() {
+    use chacha20poly1305::ChaCha8Poly1305;
+
+    let codec = FrameCodec::<ChaCha8Poly1305>::new(&[0x42; 32], 0x1234_5678);
+    let nonce = FleetNonce::new(100, 200);
+
+    let mut buf = [0u8; MAX_FRAME_SIZE];
+    buf[..4].copy_from_slice(b"ping");
+    let len = codec.seal(3, &nonce, &mut buf, 4).unwrap();
+    assert_eq!(len, 4 + MIN_CRYPT_SIZE);
+
+    let mut frame = buf;
+    let (rx_nonce, rx_len) = codec.open(3, &mut frame[..len]).unwrap();
+    assert_eq!(rx_len, 4);
+    assert_eq!(&frame[..4], b"ping");
+    assert_eq!(rx_nonce.tick(), 100);
+    assert_eq!(rx_nonce.msg_count(), 200);
+
+    // The wrong pipe fails
+    let mut frame = buf;
+    assert!(codec.open(4, &mut frame[..len]).is_err());
+
+    // Truncated frames fail
+    let mut frame = buf;
+    match codec.open(3, &mut frame[..MIN_CRYPT_SIZE]) {
+        Err(Error::PacketTooSmol) => {}
+        _ => panic!(),
+    }
+
+    // Payloads that don't fit in a frame aren't sealed
+    let mut frame = buf;
+    let too_large = MAX_FRAME_SIZE - MIN_CRYPT_SIZE + 1;
+    match codec.seal(3, &nonce, &mut frame, too_large) {
+        Err(Error::MessageTooLarge) => {}
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn frame_from_newer_minor_version() {
+    use crate::version::PROTOCOL_VERSION;
+    use chacha20poly1305::ChaCha8Poly1305;
+
+    let codec = FrameCodec::<ChaCha8Poly1305>::new(&[0x42; 32], 0x1234_5678);
+    let mut nonce = FleetNonce::new(100, 200);
+    nonce.version.minor = PROTOCOL_VERSION.minor + 1;
+
+    let mut buf = [0u8; MAX_FRAME_SIZE];
+    buf[..4].copy_from_slice(b"ping");
+    let len = codec.seal(3, &nonce, &mut buf, 4).unwrap();
+
+    // The nonce is authenticated as sent, not with our own version
+    let (rx_nonce, rx_len) = codec.open(3, &mut buf[..len]).unwrap();
+    assert_eq!(&buf[..rx_len], b"ping");
+    assert_eq!(rx_nonce.version(), nonce.version());
+}
+
+#[test]
+fn frame_open_checked_rejects_replay() {
+    use chacha20poly1305::ChaCha8Poly1305;
+
+    let codec = FrameCodec::<ChaCha8Poly1305>::new(&[0x42; 32], 0x1234_5678);
+    let mut window = NonceWindow::new(1000);
+
+    let mut buf = [0u8; MAX_FRAME_SIZE];
+    buf[0] = 0xAB;
+    let len = codec
+        .seal(0, &FleetNonce::new(10, 20), &mut buf, 1)
+        .unwrap();
+
+    let mut frame = buf;
+    assert!(codec
+        .open_checked(0, &mut frame[..len], &mut window, None)
+        .is_ok());
+
+    let mut frame = buf;
+    match codec.open_checked(0, &mut frame[..len], &mut window, None) {
+        Err(Error::InvalidNonce) => {}
+        _ => panic!(),
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn frame_encode_decode() {
+    use chacha20poly1305::ChaCha20Poly1305;
+
+    let codec = FrameCodec::<ChaCha20Poly1305>::new(&[0x24; 32], 7);
+    let frame = codec
+        .encode(1, &FleetNonce::new(1, 2), &(123u32, 45u8))
+        .unwrap();
+    let (_nonce, msg): (_, (u32, u8)) = codec.decode(1, &frame).unwrap();
+    assert_eq!(msg, (123, 45));
+}
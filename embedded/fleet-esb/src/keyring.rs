@@ -0,0 +1,134 @@
+//! Keys for rolling over from one key epoch to the next
+//!
+//! Every frame carries the epoch of the key it was encrypted with, see
+//! `FleetNonce::epoch()`. To change keys without reflashing every device
+//! at once, each radio holds up to two keys: the current key, and the key
+//! of the next epoch. Frames in either epoch are accepted during the
+//! rollover window.
+//!
+//! 1. The next key is provisioned on every device, then on the modem,
+//!    with `add_master_key()`.
+//! 2. The modem switches to the next epoch with `switch_epoch()`. Devices
+//!    switch as soon as they receive an authentic frame in that epoch.
+//! 3. Once all devices have switched, the old key is dropped with
+//!    `retire_keys()`.
+
+use crate::cipher::FleetCipher;
+
+/// The number of distinct key epochs. Epochs are carried in four bits of
+/// the nonce, and roll over.
+pub const NUM_EPOCHS: u8 = 16;
+
+struct EpochKey<Cipher> {
+    epoch: u8,
+    key: [u8; 32],
+    crypt: Cipher,
+}
+
+/// Up to two keys, each for a different epoch
+pub(crate) struct KeyRing<Cipher: FleetCipher> {
+    keys: [Option<EpochKey<Cipher>>; 2],
+}
+
+impl<Cipher: FleetCipher> KeyRing<Cipher> {
+    pub(crate) fn new() -> Self {
+        Self { keys: [None, None] }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.keys.iter().all(|k| k.is_none())
+    }
+
+    pub(crate) fn contains(&self, epoch: u8) -> bool {
+        self.get(epoch).is_some()
+    }
+
+    /// The cipher for the given epoch
+    pub(crate) fn get(&self, epoch: u8) -> Option<&Cipher> {
+        self.find(epoch).map(|k| &k.crypt)
+    }
+
+    /// The raw key for the given epoch
+    pub(crate) fn key(&self, epoch: u8) -> Option<&[u8; 32]> {
+        self.find(epoch).map(|k| &k.key)
+    }
+
+    /// Add the key of an epoch, replacing any key for the same epoch. If
+    /// the ring is full, the key of the other epoch than `keep` is replaced.
+    pub(crate) fn insert(&mut self, epoch: u8, key: &[u8; 32], keep: u8) {
+        let epoch = epoch % NUM_EPOCHS;
+        let idx = self
+            .position(|k| k.map(|k| k.epoch == epoch).unwrap_or(false))
+            .or_else(|| self.position(|k| k.is_none()))
+            .or_else(|| self.position(|k| k.map(|k| k.epoch != keep).unwrap_or(false)))
+            .unwrap_or(0);
+
+        self.keys[idx] = Some(EpochKey {
+            epoch,
+            key: *key,
+            crypt: Cipher::from_key(key),
+        });
+    }
+
+    /// Drop every key, except the one for the given epoch
+    pub(crate) fn retain(&mut self, epoch: u8) {
+        for slot in self.keys.iter_mut() {
+            if slot.as_ref().map(|k| k.epoch != epoch).unwrap_or(false) {
+                *slot = None;
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, epoch: u8) {
+        for slot in self.keys.iter_mut() {
+            if slot.as_ref().map(|k| k.epoch == epoch).unwrap_or(false) {
+                *slot = None;
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.keys = [None, None];
+    }
+
+    fn find(&self, epoch: u8) -> Option<&EpochKey<Cipher>> {
+        self.keys
+            .iter()
+            .filter_map(|k| k.as_ref())
+            .find(|k| k.epoch == epoch)
+    }
+
+    fn position<F: Fn(Option<&EpochKey<Cipher>>) -> bool>(&self, f: F) -> Option<usize> {
+        self.keys.iter().position(|k| f(k.as_ref()))
+    }
+}
+
+#[test]
+fn keyring_rollover() {
+    use chacha20poly1305::ChaCha8Poly1305;
+
+    let mut ring = KeyRing::<ChaCha8Poly1305>::new();
+    assert!(ring.is_empty());
+
+    ring.insert(3, &[3; 32], 3);
+    ring.insert(4, &[4; 32], 3);
+    assert_eq!(ring.key(3), Some(&[3; 32]));
+    assert_eq!(ring.key(4), Some(&[4; 32]));
+
+    // A new next key replaces the old next key, not the current one
+    ring.insert(5, &[5; 32], 3);
+    assert!(ring.contains(3));
+    assert!(!ring.contains(4));
+    assert_eq!(ring.key(5), Some(&[5; 32]));
+
+    // Epochs roll over
+    ring.insert(NUM_EPOCHS + 5, &[6; 32], 3);
+    assert_eq!(ring.key(5), Some(&[6; 32]));
+
+    ring.retain(5);
+    assert!(!ring.contains(3));
+    assert!(ring.get(5).is_some());
+
+    ring.remove(5);
+    assert!(ring.is_empty());
+}
@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 #[cfg(feature = "51")]
 use nrf51_hal as hal;
@@ -11,15 +11,41 @@ use nrf52840_hal as hal;
 
 use chacha20poly1305::aead::{Buffer, Error as AeadError};
 
-use core::cmp::min;
+pub use cipher::{CipherSuite, FleetCipher};
+pub use stats::RadioStats;
+pub use version::{ProtocolVersion, PROTOCOL_VERSION};
 
+use core::{cmp::min, ops::Deref};
+
+#[cfg(feature = "radio")]
 use esb::Error as EsbError;
-use postcard::Error as PostcardError;
+use postcard::{from_bytes, Error as PostcardError};
 use serde::de::{Deserialize, DeserializeOwned};
 
+use crate::{
+    fragment::{FragmentHeader, Message},
+    transport::RxFrame,
+};
+
+pub mod batch;
+pub mod cipher;
+pub mod fragment;
+pub mod frame;
+pub mod keyring;
 pub mod nonce;
+pub mod pairing;
+pub mod poll;
 pub mod prx;
 pub mod ptx;
+pub mod queue;
+pub mod radio;
+pub mod session;
+pub mod stats;
+pub mod transport;
+pub mod version;
+
+#[cfg(feature = "std")]
+pub mod loopback;
 
 #[derive(Debug)]
 pub enum Error {
@@ -27,12 +53,42 @@ pub enum Error {
     BadNonce,
     InvalidNonce,
     NoData,
+    BadFragment,
+    MessageTooLarge,
+    QueueFull,
+    /// There is no pipe with this number
+    InvalidPipe,
+    /// No master key is provisioned, so radio traffic is refused
+    NoKey,
+    /// The frame was sent in a key epoch we have no key for
+    UnknownEpoch(u8),
+    CipherSuiteMismatch {
+        ours: CipherSuite,
+        /// The raw suite bits sent by the peer, which may not be a suite we know
+        theirs: u8,
+    },
+    /// The peer speaks a protocol with a different major version
+    IncompatibleVersion {
+        ours: ProtocolVersion,
+        theirs: ProtocolVersion,
+    },
+    /// A pairing message arrived before the ones it depends on
+    PairingOutOfOrder,
+    /// The modem's pairing nonce doesn't match its commitment
+    BadCommitment,
+    /// The pairing code entered by the user doesn't match ours
+    WrongPairingCode,
+    /// A frame sealed with the master key while a session is up, that
+    /// isn't a handshake
+    OutsideSession,
 
+    #[cfg(feature = "radio")]
     Esb(EsbError),
     Postcard(PostcardError),
     Crypt(AeadError),
 }
 
+#[cfg(feature = "radio")]
 impl From<EsbError> for Error {
     fn from(err: EsbError) -> Self {
         Error::Esb(err)
@@ -80,6 +136,105 @@ pub struct MessageMetadata {
     pub pipe: u8,
 }
 
+/// A received message, either still in the radio's receive buffer, or
+/// reassembled from several fragments. Frames are released when dropped.
+pub struct GrantWrap<F>
+where
+    F: RxFrame,
+{
+    contents: Contents<F>,
+}
+
+// There is no allocator to box the reassembled message in. A `GrantWrap` is
+// short lived, so the size is only paid for while a message is handled.
+#[allow(clippy::large_enum_variant)]
+enum Contents<F> {
+    Frame { frame: F, start: usize, end: usize },
+    Reassembled { msg: Message, pipe: u8 },
+}
+
+impl<F> GrantWrap<F>
+where
+    F: RxFrame,
+{
+    pub(crate) fn frame(frame: F, start: usize, end: usize) -> Self {
+        Self {
+            contents: Contents::Frame { frame, start, end },
+        }
+    }
+
+    pub(crate) fn reassembled(msg: Message, pipe: u8) -> Self {
+        Self {
+            contents: Contents::Reassembled { msg, pipe },
+        }
+    }
+
+    /// The serialized message
+    pub fn payload(&self) -> &[u8] {
+        match self.contents {
+            Contents::Frame {
+                ref frame,
+                start,
+                end,
+            } => &frame[start..end],
+            Contents::Reassembled { ref msg, .. } => msg,
+        }
+    }
+
+    /// Was this message sent with `FleetRadio::send_handshake()`?
+    pub fn is_handshake(&self) -> bool {
+        match self.contents {
+            Contents::Frame { ref frame, end, .. } => matches!(
+                FragmentHeader::parse(&frame[..end]),
+                Ok((FragmentHeader::Handshake, _))
+            ),
+            Contents::Reassembled { .. } => false,
+        }
+    }
+
+    pub fn pipe(&self) -> u8 {
+        match self.contents {
+            Contents::Frame { ref frame, .. } => frame.pipe(),
+            Contents::Reassembled { pipe, .. } => pipe,
+        }
+    }
+
+    pub fn view_with<'a: 'de, 'de, T, FUN, R>(&'a self, fun: FUN) -> Result<R, Error>
+    where
+        T: 'de + Deserialize<'de>,
+        FUN: FnOnce(BorrowRxMessage<'de, T>) -> R,
+        R: 'static,
+    {
+        let msg = from_bytes(self.payload())?;
+
+        Ok(fun(BorrowRxMessage {
+            msg,
+            meta: MessageMetadata { pipe: self.pipe() },
+            marker: core::marker::PhantomData,
+        }))
+    }
+
+    /// Release the message, making room in the radio's receive buffer.
+    ///
+    /// This is the same as dropping the message.
+    pub fn release(self) {
+        if let Contents::Frame { frame, .. } = self.contents {
+            frame.release();
+        }
+    }
+}
+
+impl<F> Deref for GrantWrap<F>
+where
+    F: RxFrame,
+{
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.payload()
+    }
+}
+
 impl<'a> AsRef<[u8]> for LilBuf<'a> {
     fn as_ref(&self) -> &[u8] {
         &self.buf[..self.used.into()]
@@ -125,15 +280,67 @@ impl<'a> Buffer for LilBuf<'a> {
 }
 
 //                            vv vv v    - magic
-pub const MAGIC_WORD: u32 = 0xF1_33_70_01;
+pub const MAGIC_WORD: u32 = 0xF1_33_74_00;
+//                                ^       - sender, the low bit XORed into the magic
+//                                 ^      - key epoch, XORed into the magic
 //                                   ^ ^^ - protocol version
 //                                   ^    - major
 //                                     ^  - minor
 //                                      ^ - trivial
+//
+// The low two bits of the major version carry the cipher suite, as
+// peers using different suites can never talk to each other. The upper
+// two bits are the major version itself.
+//
+// As the key epoch is XORed into the magic, frames in epoch 0 look the
+// same as frames from before key epochs were added.
+//
+// Frames sent by the PRX have the sender bit flipped, so the PTX and PRX
+// can never seal a frame with the same nonce, even when their message
+// counts and ticks line up.
+//
+// Major version 1 authenticates the frame metadata and adds fragments,
+// so nodes still sending the baseline magic `0xF1337001` are refused.
+pub(crate) const MAGIC_MASK: u32 = 0xFFFF_F000;
+pub(crate) const SENDER_BIT: u32 = 1 << 16;
+pub(crate) const EPOCH_SHIFT: u32 = 12;
+pub(crate) const EPOCH_MASK: u32 = 0xF << EPOCH_SHIFT;
+pub(crate) const MAJOR_SHIFT: u32 = 10;
+pub(crate) const MAJOR_MASK: u32 = 0b11 << MAJOR_SHIFT;
+pub(crate) const SUITE_SHIFT: u32 = 8;
+pub(crate) const SUITE_MASK: u32 = 0b11 << SUITE_SHIFT;
+pub(crate) const MINOR_SHIFT: u32 = 4;
+pub(crate) const MINOR_MASK: u32 = 0xF << MINOR_SHIFT;
+pub(crate) const TRIVIAL_MASK: u32 = 0xF;
+
+/// The magic word sent by peers using the given cipher suite
+pub fn magic_word(suite: CipherSuite) -> u32 {
+    PROTOCOL_VERSION.magic_word(suite)
+}
+
+/// The number of pipes supported by the ESB radio
+pub const NUM_PIPES: usize = 8;
 
 pub const NONCE_SIZE: usize = 12;
 pub const CRYPT_SIZE: usize = 16;
 pub const MIN_CRYPT_SIZE: usize = NONCE_SIZE + CRYPT_SIZE;
+pub const AAD_SIZE: usize = 6;
+
+/// Build the associated data for a frame.
+///
+/// These fields are not sent as part of the encrypted payload, but are
+/// authenticated along with it. A frame captured on one pipe or fleet
+/// network will fail to decrypt if it is injected on another.
+///
+/// `payload_len` is the length of the plaintext payload, not including
+/// the tag or nonce.
+pub fn associated_data(network_id: u32, pipe: u8, payload_len: u8) -> [u8; AAD_SIZE] {
+    let mut aad = [0u8; AAD_SIZE];
+    aad[0..4].copy_from_slice(&network_id.to_le_bytes());
+    aad[4] = pipe;
+    aad[5] = payload_len;
+    aad
+}
 
 /// This trait decribes a monotonically incrementing timer that
 /// is expected to roll over.
@@ -148,3 +355,61 @@ pub trait RollingTimer {
     /// Get the current unitless tick
     fn get_current_tick(&self) -> u32;
 }
+
+/// A source of random numbers, used by the PTX to pick its initial
+/// message count and tick offset
+pub trait Entropy {
+    fn random_u32(&mut self) -> u32;
+}
+
+#[cfg(feature = "radio")]
+impl Entropy for hal::Rng {
+    fn random_u32(&mut self) -> u32 {
+        hal::Rng::random_u32(self)
+    }
+}
+
+#[test]
+fn aad_binds_frame_metadata() {
+    use chacha20poly1305::aead::{generic_array::GenericArray, AeadInPlace, NewAead};
+    use chacha20poly1305::ChaCha8Poly1305;
+
+    const NETWORK: u32 = 0x1234_5678;
+
+    let crypt = ChaCha8Poly1305::new(&GenericArray::clone_from_slice(&[0x42; 32]));
+    let nonce = [0x24; NONCE_SIZE];
+    let nonce = GenericArray::from_slice(&nonce);
+    let plaintext = b"relay 0 on";
+    let pt_len = plaintext.len() as u8;
+
+    let mut storage = [0u8; 64];
+    storage[..plaintext.len()].copy_from_slice(plaintext);
+    let mut buf = LilBuf {
+        buf: &mut storage,
+        used: pt_len,
+    };
+    crypt
+        .encrypt_in_place(nonce, &associated_data(NETWORK, 1, pt_len), &mut buf)
+        .unwrap();
+    let sealed_len = buf.used;
+    let sealed = storage;
+
+    let try_open = |network: u32, pipe: u8, len: u8| {
+        let mut storage = sealed;
+        let mut buf = LilBuf {
+            buf: &mut storage,
+            used: sealed_len,
+        };
+        crypt
+            .decrypt_in_place(nonce, &associated_data(network, pipe, len), &mut buf)
+            .map(|_| buf.as_ref() == &plaintext[..])
+    };
+
+    // Untampered metadata decrypts correctly
+    assert_eq!(try_open(NETWORK, 1, pt_len), Ok(true));
+
+    // Moving the frame to another pipe, network, or changing its length fails
+    assert!(try_open(NETWORK, 2, pt_len).is_err());
+    assert!(try_open(NETWORK + 1, 1, pt_len).is_err());
+    assert!(try_open(NETWORK, 1, pt_len - 1).is_err());
+}
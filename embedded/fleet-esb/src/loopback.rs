@@ -0,0 +1,863 @@
+//! An in-memory radio link, for testing without hardware
+//!
+//! `pair()` creates two connected `Loopback` transports. Frames committed on
+//! one end can be read from the other, e.g. by a `FleetRadioPtx` and a
+//! `FleetRadioPrx` running in the same test.
+//!
+//! Each direction of the link can be configured to lose, duplicate or reorder
+//! frames. This is driven by a simple seeded PRNG, so a failing test can
+//! always be reproduced. Duplicated frames keep their PID, like an ESB
+//! retransmission after a lost ACK.
+//!
+//! Unlike ESB, frames sent by the PRX end are delivered immediately, rather
+//! than being carried by the ACK of the next frame from the PTX.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    ops::{Deref, DerefMut},
+    rc::Rc,
+    vec::Vec,
+};
+
+use crate::{
+    frame::MAX_FRAME_SIZE,
+    transport::{RxFrame, Transport, TxGrant},
+    Error,
+};
+
+/// How one direction of the link behaves. The default is a perfect link.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkConfig {
+    /// Chance of a frame being lost, in percent
+    pub loss: u8,
+
+    /// Chance of a frame being delivered twice, in percent
+    pub duplicate: u8,
+
+    /// Chance of a frame being delivered before the previous one, in percent
+    pub reorder: u8,
+
+    /// Seed for the PRNG
+    pub seed: u32,
+
+    /// Reported as the RSSI of every frame
+    pub rssi: Option<u8>,
+}
+
+/// What has happened to frames sent in one direction of the link
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    pub committed: u32,
+    pub lost: u32,
+    pub duplicated: u32,
+    pub reordered: u32,
+}
+
+#[derive(Clone)]
+struct Queued {
+    pipe: u8,
+    pid: u8,
+    buf: Vec<u8>,
+}
+
+struct Channel {
+    frames: VecDeque<Queued>,
+    last: Option<Queued>,
+    config: LinkConfig,
+    rng: u32,
+    stats: LinkStats,
+}
+
+impl Channel {
+    fn new(config: LinkConfig) -> Self {
+        Self {
+            frames: VecDeque::new(),
+            last: None,
+            config,
+            // xorshift gets stuck at zero
+            rng: if config.seed == 0 {
+                0x2545_F491
+            } else {
+                config.seed
+            },
+            stats: LinkStats::default(),
+        }
+    }
+
+    fn roll(&mut self, percent: u8) -> bool {
+        if percent == 0 {
+            return false;
+        }
+
+        // xorshift32
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng = x;
+
+        (x % 100) < u32::from(percent)
+    }
+
+    fn push(&mut self, frame: Queued) {
+        self.stats.committed += 1;
+        self.last = Some(frame.clone());
+
+        if self.roll(self.config.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+
+        if self.roll(self.config.duplicate) {
+            self.stats.duplicated += 1;
+            self.frames.push_back(frame.clone());
+        }
+
+        self.frames.push_back(frame);
+
+        let len = self.frames.len();
+        if len >= 2 && self.roll(self.config.reorder) {
+            self.stats.reordered += 1;
+            self.frames.swap(len - 1, len - 2);
+        }
+    }
+}
+
+/// One end of an in-memory link. A clone is another handle to the same
+/// end, e.g. for a radio that is restarted.
+#[derive(Clone)]
+pub struct Loopback {
+    tx: Rc<RefCell<Channel>>,
+    rx: Rc<RefCell<Channel>>,
+}
+
+/// Create a connected pair of transports, `a` and `b`, with the given
+/// behavior for each direction
+pub fn pair(a_to_b: LinkConfig, b_to_a: LinkConfig) -> (Loopback, Loopback) {
+    let a_to_b = Rc::new(RefCell::new(Channel::new(a_to_b)));
+    let b_to_a = Rc::new(RefCell::new(Channel::new(b_to_a)));
+
+    let a = Loopback {
+        tx: a_to_b.clone(),
+        rx: b_to_a.clone(),
+    };
+    let b = Loopback {
+        tx: b_to_a,
+        rx: a_to_b,
+    };
+
+    (a, b)
+}
+
+impl Loopback {
+    /// What has happened to the frames sent from this end
+    pub fn tx_stats(&self) -> LinkStats {
+        self.tx.borrow().stats
+    }
+
+    /// The number of frames waiting to be read by this end
+    pub fn pending(&self) -> usize {
+        self.rx.borrow().frames.len()
+    }
+
+    /// Send the last frame sent from this end again, with the given PID,
+    /// as an attacker replaying a captured frame might. This bypasses the
+    /// configured loss, duplication and reordering.
+    pub fn replay_last(&self, pid: u8) {
+        let mut link = self.tx.borrow_mut();
+        if let Some(mut frame) = link.last.clone() {
+            frame.pid = pid;
+            link.frames.push_back(frame);
+        }
+    }
+}
+
+impl Transport for Loopback {
+    type Grant = LoopbackGrant;
+    type Frame = LoopbackFrame;
+
+    fn maximum_payload_size(&self) -> usize {
+        MAX_FRAME_SIZE
+    }
+
+    fn grant(&mut self, pipe: u8, pid: u8) -> Result<Self::Grant, Error> {
+        Ok(LoopbackGrant {
+            pipe,
+            pid,
+            buf: vec![0u8; MAX_FRAME_SIZE],
+            link: self.tx.clone(),
+        })
+    }
+
+    fn read(&mut self) -> Option<Self::Frame> {
+        let mut link = self.rx.borrow_mut();
+        let rssi = link.config.rssi;
+
+        link.frames
+            .pop_front()
+            .map(|Queued { pipe, pid, buf }| LoopbackFrame {
+                pipe,
+                pid,
+                buf,
+                rssi,
+            })
+    }
+
+    fn start_tx(&mut self) {}
+}
+
+pub struct LoopbackGrant {
+    pipe: u8,
+    pid: u8,
+    buf: Vec<u8>,
+    link: Rc<RefCell<Channel>>,
+}
+
+impl Deref for LoopbackGrant {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for LoopbackGrant {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl TxGrant for LoopbackGrant {
+    fn commit(mut self, used: usize) {
+        self.buf.truncate(used);
+        self.link.borrow_mut().push(Queued {
+            pipe: self.pipe,
+            pid: self.pid,
+            buf: self.buf,
+        });
+    }
+}
+
+pub struct LoopbackFrame {
+    pipe: u8,
+    pid: u8,
+    rssi: Option<u8>,
+    buf: Vec<u8>,
+}
+
+impl Deref for LoopbackFrame {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for LoopbackFrame {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl RxFrame for LoopbackFrame {
+    fn pipe(&self) -> u8 {
+        self.pipe
+    }
+
+    fn pid(&self) -> u8 {
+        self.pid
+    }
+
+    fn rssi(&self) -> Option<u8> {
+        self.rssi
+    }
+
+    fn release(self) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{prx::FleetRadioPrx, ptx::FleetRadioPtx, Entropy, RollingTimer};
+    use std::cell::Cell;
+
+    const KEY: [u8; 32] = [0x42; 32];
+    const NETWORK: u32 = 0x1234_5678;
+
+    #[derive(Clone, Default)]
+    struct FakeTimer(Rc<Cell<u32>>);
+
+    impl RollingTimer for FakeTimer {
+        fn get_current_tick(&self) -> u32 {
+            self.0.get()
+        }
+    }
+
+    struct FakeRng(u32);
+
+    impl Entropy for FakeRng {
+        fn random_u32(&mut self) -> u32 {
+            self.0 = self.0.wrapping_mul(0x0019_660D).wrapping_add(0x3C6E_F35F);
+            self.0
+        }
+    }
+
+    fn radios(
+        to_prx: LinkConfig,
+        to_ptx: LinkConfig,
+    ) -> (FleetRadioPtx<Loopback, FakeTimer>, FleetRadioPrx<Loopback>) {
+        let (a, b) = pair(to_prx, to_ptx);
+        let ptx = FleetRadioPtx::new(
+            a,
+            Some(&KEY),
+            NETWORK,
+            FakeTimer::default(),
+            1000,
+            &mut FakeRng(1),
+        );
+        let prx = FleetRadioPrx::new(b, Some(&KEY), NETWORK, 1000, &mut FakeRng(2));
+        (ptx, prx)
+    }
+
+    #[test]
+    fn loopback_roundtrip() {
+        let (mut ptx, mut prx) = radios(LinkConfig::default(), LinkConfig::default());
+
+        ptx.send(&0x1234_5678u32, 3).unwrap();
+        let msg = prx.receive::<u32>().unwrap().unwrap();
+        assert_eq!(msg.msg, 0x1234_5678);
+        assert_eq!(msg.meta.pipe, 3);
+        assert!(prx.receive::<u32>().unwrap().is_none());
+
+        prx.send(&0xABCDu32, 3).unwrap();
+        let msg = ptx.receive::<u32>().unwrap().unwrap();
+        assert_eq!(msg.msg, 0xABCD);
+        assert!(ptx.receive::<u32>().unwrap().is_none());
+    }
+
+    #[test]
+    fn loopback_ptx_restart() {
+        let (a, b) = pair(LinkConfig::default(), LinkConfig::default());
+        let timer = FakeTimer::default();
+        let mut ptx: FleetRadioPtx<Loopback, FakeTimer> = FleetRadioPtx::new(
+            a.clone(),
+            Some(&KEY),
+            NETWORK,
+            timer.clone(),
+            1000,
+            &mut FakeRng(1),
+        );
+        let mut prx: FleetRadioPrx<Loopback> =
+            FleetRadioPrx::new(b, Some(&KEY), NETWORK, 1000, &mut FakeRng(2));
+
+        for i in 0..3u32 {
+            ptx.send(&i, 0).unwrap();
+            assert_eq!(prx.receive::<u32>().unwrap().unwrap().msg, i);
+        }
+        let replay = prx.nonce_rejects(0);
+
+        // The device restarts mid-stream, and its new ticks are behind the
+        // old ones, so its frames look stale to the modem
+        timer.0.set(10);
+        let mut ptx: FleetRadioPtx<Loopback, FakeTimer> =
+            FleetRadioPtx::new(a, Some(&KEY), NETWORK, timer, 1000, &mut FakeRng(7));
+        ptx.send(&10u32, 0).unwrap();
+        match prx.receive::<u32>() {
+            Err(Error::InvalidNonce) => {}
+            _ => panic!(),
+        }
+
+        // Its handshake is accepted anyway, and answered
+        ptx.send_handshake(&11u32, 0).unwrap();
+        assert_eq!(prx.receive::<u32>().unwrap().unwrap().msg, 11);
+        prx.send_handshake(&12u32, 0).unwrap();
+        assert_eq!(ptx.receive::<u32>().unwrap().unwrap().msg, 12);
+
+        // And traffic flows again, without replays getting through
+        ptx.send(&13u32, 0).unwrap();
+        assert_eq!(prx.receive::<u32>().unwrap().unwrap().msg, 13);
+        ptx.transport().replay_last(3);
+        assert!(prx.receive::<u32>().is_err());
+        assert_eq!(prx.nonce_rejects(0), replay + 2);
+    }
+
+    #[test]
+    fn loopback_session_refuses_master_key() {
+        use crate::session::{accept_session, DeviceHandshake, DEFAULT_SESSION_MESSAGES};
+
+        let (mut ptx, mut prx) = radios(LinkConfig::default(), LinkConfig::default());
+
+        let handshake = DeviceHandshake::new([0x11; 16]);
+        ptx.send_handshake(handshake.device_nonce(), 0).unwrap();
+        let rx = prx.receive_with().unwrap();
+        assert!(rx.is_handshake());
+        let device_nonce: [u8; 16] = postcard::from_bytes(rx.payload()).unwrap();
+        drop(rx);
+
+        let host_nonce = [0x22; 16];
+        let key = *prx.pipe_key(0).unwrap();
+        prx.start_session(
+            0,
+            accept_session(&key, &device_nonce, &host_nonce, DEFAULT_SESSION_MESSAGES),
+        );
+        prx.send_handshake(&host_nonce, 0).unwrap();
+        let rx = ptx.receive::<[u8; 16]>().unwrap().unwrap();
+        let key = *ptx.master_key().unwrap();
+        ptx.start_session(handshake.complete(&key, &rx.msg, DEFAULT_SESSION_MESSAGES));
+
+        ptx.send(&1u32, 0).unwrap();
+        let rx = prx.receive_with().unwrap();
+        assert!(!rx.is_handshake());
+        assert_eq!(postcard::from_bytes::<u32>(rx.payload()).unwrap(), 1);
+        drop(rx);
+        assert_eq!(prx.session(0).unwrap().messages(), 1);
+
+        // Once the session is up, the master key only carries handshakes,
+        // and neither ends the session
+        ptx.end_session();
+        ptx.send(&2u32, 0).unwrap();
+        match prx.receive::<u32>() {
+            Err(Error::OutsideSession) => {}
+            _ => panic!(),
+        }
+        assert!(prx.session(0).is_some());
+
+        ptx.send_handshake(&3u32, 0).unwrap();
+        assert_eq!(prx.receive::<u32>().unwrap().unwrap().msg, 3);
+        assert!(prx.session(0).is_some());
+    }
+
+    #[test]
+    fn loopback_unkeyed_refused() {
+        let (mut ptx, mut prx) = radios(LinkConfig::default(), LinkConfig::default());
+        prx.set_master_key(0, None);
+        assert!(prx.master_key().is_none());
+
+        // Frames are dropped without a key, and nothing can be sent
+        ptx.send(&1u32, 0).unwrap();
+        match prx.receive::<u32>() {
+            Err(Error::NoKey) => {}
+            _ => panic!(),
+        }
+        assert!(prx.receive::<u32>().unwrap().is_none());
+        assert!(prx.send(&2u32, 0).is_err());
+
+        prx.set_master_key(0, Some(&KEY));
+        ptx.send(&3u32, 0).unwrap();
+        assert_eq!(prx.receive::<u32>().unwrap().unwrap().msg, 3);
+    }
+
+    #[test]
+    fn loopback_device_keys() {
+        let (mut ptx, mut prx) = radios(LinkConfig::default(), LinkConfig::default());
+        let device_key = [0x24; 32];
+        ptx.set_master_key(0, Some(&device_key));
+        prx.set_device_key(2, 0, Some(&device_key));
+
+        ptx.send(&1u32, 2).unwrap();
+        assert_eq!(prx.receive::<u32>().unwrap().unwrap().msg, 1);
+        prx.send(&2u32, 2).unwrap();
+        assert_eq!(ptx.receive::<u32>().unwrap().unwrap().msg, 2);
+
+        // Other pipes still use the master key
+        ptx.send(&3u32, 3).unwrap();
+        assert!(prx.receive::<u32>().is_err());
+
+        prx.set_device_key(2, 0, None);
+        ptx.send(&4u32, 2).unwrap();
+        assert!(prx.receive::<u32>().is_err());
+        assert_eq!(prx.stats().decrypt_failures, 2);
+    }
+
+    #[test]
+    fn loopback_key_rollover() {
+        let (mut ptx, mut prx) = radios(LinkConfig::default(), LinkConfig::default());
+        let next = [0x24; 32];
+
+        // Epochs we have no key for are refused
+        assert!(prx.switch_epoch(1).is_err());
+
+        // The device gets the next key first, but keeps sending in epoch 0
+        ptx.add_master_key(1, &next);
+        ptx.send(&1u32, 0).unwrap();
+        assert_eq!(prx.receive::<u32>().unwrap().unwrap().msg, 1);
+        assert_eq!(ptx.key_epoch(), 0);
+
+        // The modem switches, and the device follows its reply
+        prx.add_master_key(1, &next);
+        prx.switch_epoch(1).unwrap();
+        assert_eq!(prx.master_key(), Some(&next));
+        prx.send(&2u32, 0).unwrap();
+        let msg = ptx.receive::<u32>().unwrap().unwrap();
+        assert_eq!(msg.msg, 2);
+        assert_eq!(ptx.key_epoch(), 1);
+
+        ptx.send(&3u32, 0).unwrap();
+        assert_eq!(prx.receive::<u32>().unwrap().unwrap().msg, 3);
+
+        // Once the old key is retired, its frames are rejected
+        prx.retire_keys();
+        ptx.set_master_key(0, Some(&KEY));
+        ptx.send(&4u32, 0).unwrap();
+        match prx.receive::<u32>() {
+            Err(Error::UnknownEpoch(0)) => {}
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn loopback_pairing_pipe() {
+        use crate::pairing::{PAIRING_KEY, PAIRING_PIPE};
+
+        let (mut ptx, mut prx) = radios(LinkConfig::default(), LinkConfig::default());
+
+        // The modem has moved on to another epoch, and opens the pairing pipe
+        prx.add_master_key(2, &[0x24; 32]);
+        prx.switch_epoch(2).unwrap();
+        prx.set_device_key(PAIRING_PIPE, 0, Some(&PAIRING_KEY));
+
+        // A fresh device only knows the pairing key, in epoch 0
+        ptx.set_master_key(0, Some(&PAIRING_KEY));
+        ptx.send(&1u32, PAIRING_PIPE).unwrap();
+        assert_eq!(prx.receive::<u32>().unwrap().unwrap().msg, 1);
+
+        // It is answered in the epoch it used
+        prx.send(&2u32, PAIRING_PIPE).unwrap();
+        assert_eq!(ptx.receive::<u32>().unwrap().unwrap().msg, 2);
+        assert_eq!(ptx.key_epoch(), 0);
+
+        // Other pipes still use the current epoch, and the device can't read them
+        prx.send(&3u32, 0).unwrap();
+        assert!(ptx.receive::<u32>().is_err());
+    }
+
+    #[test]
+    fn loopback_fragmented() {
+        let (mut ptx, mut prx) = radios(LinkConfig::default(), LinkConfig::default());
+
+        let mut big = [[0u8; 32]; 20];
+        for (i, row) in big.iter_mut().enumerate() {
+            row[0] = i as u8;
+            row[31] = !(i as u8);
+        }
+
+        ptx.send(&big, 2).unwrap();
+        assert_eq!(ptx.transport().tx_stats().committed, 3);
+
+        let msg = prx.receive::<[[u8; 32]; 20]>().unwrap().unwrap();
+        assert_eq!(msg.msg, big);
+        assert_eq!(msg.meta.pipe, 2);
+        assert!(prx.receive::<[[u8; 32]; 20]>().unwrap().is_none());
+
+        prx.send(&big, 2).unwrap();
+        let msg = ptx.receive::<[[u8; 32]; 20]>().unwrap().unwrap();
+        assert_eq!(msg.msg, big);
+    }
+
+    #[test]
+    fn loopback_retransmits_dropped() {
+        let dupes = LinkConfig {
+            duplicate: 100,
+            ..LinkConfig::default()
+        };
+        let (mut ptx, mut prx) = radios(dupes, LinkConfig::default());
+
+        // The duplicate has the same PID, and is quietly dropped
+        ptx.send(&1u32, 0).unwrap();
+        assert_eq!(prx.receive::<u32>().unwrap().unwrap().msg, 1);
+        assert!(prx.receive::<u32>().unwrap().is_none());
+        assert_eq!(prx.retransmits(0), 1);
+        assert_eq!(prx.nonce_rejects(0), 0);
+
+        // A replay with a different PID is still caught by the nonce check
+        ptx.transport().replay_last(2);
+        match prx.receive::<u32>() {
+            Err(Error::InvalidNonce) => {}
+            _ => panic!(),
+        }
+        assert!(prx.receive::<u32>().unwrap().is_none());
+        assert_eq!(prx.nonce_rejects(0), 1);
+    }
+
+    #[test]
+    fn loopback_stats() {
+        let link = LinkConfig {
+            duplicate: 100,
+            rssi: Some(42),
+            ..LinkConfig::default()
+        };
+        let (mut ptx, mut prx) = radios(link, LinkConfig::default());
+
+        ptx.send(&1u32, 0).unwrap();
+        ptx.send(&2u32, 0).unwrap();
+        while prx.receive::<u32>().unwrap().is_some() {}
+
+        ptx.transport().replay_last(3);
+        assert!(prx.receive::<u32>().is_err());
+
+        crate::stats::record_max_attempts();
+
+        let stats = prx.stats();
+        assert_eq!(stats.frames_received, 2);
+        assert_eq!(stats.retransmits, 2);
+        assert_eq!(stats.nonce_rejects, 1);
+        assert_eq!(stats.decrypt_failures, 0);
+        assert_eq!(stats.last_rssi, Some(42));
+        assert!(stats.max_attempts >= 1);
+
+        let stats = ptx.stats();
+        assert_eq!(stats.frames_sent, 2);
+        assert_eq!(stats.last_rssi, None);
+
+        // A PRX with the wrong key can't decrypt anything
+        let (a, b) = pair(LinkConfig::default(), LinkConfig::default());
+        let mut ptx: FleetRadioPtx<Loopback, FakeTimer> = FleetRadioPtx::new(
+            a,
+            Some(&KEY),
+            NETWORK,
+            FakeTimer::default(),
+            1000,
+            &mut FakeRng(1),
+        );
+        let mut prx: FleetRadioPrx<Loopback> =
+            FleetRadioPrx::new(b, Some(&[0x24; 32]), NETWORK, 1000, &mut FakeRng(2));
+        ptx.send(&1u32, 0).unwrap();
+        assert!(prx.receive::<u32>().is_err());
+        assert_eq!(prx.stats().decrypt_failures, 1);
+        assert_eq!(prx.stats().frames_received, 0);
+    }
+
+    #[test]
+    fn loopback_reordered_accepted() {
+        let reorder = LinkConfig {
+            reorder: 100,
+            ..LinkConfig::default()
+        };
+        let (mut ptx, mut prx) = radios(reorder, LinkConfig::default());
+
+        for i in 0..4u32 {
+            ptx.send(&i, 0).unwrap();
+        }
+
+        let mut got = Vec::new();
+        while let Some(msg) = prx.receive::<u32>().unwrap() {
+            got.push(msg.msg);
+        }
+
+        assert_ne!(got, [0, 1, 2, 3]);
+        got.sort();
+        assert_eq!(got, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn loopback_lossy_link() {
+        let lossy = LinkConfig {
+            loss: 30,
+            duplicate: 10,
+            reorder: 10,
+            seed: 0xC0FF_EE00,
+            ..LinkConfig::default()
+        };
+        let (mut ptx, mut prx) = radios(lossy, LinkConfig::default());
+
+        for i in 0..100u32 {
+            ptx.send(&i, 1).unwrap();
+        }
+
+        let stats = ptx.transport().tx_stats();
+        assert_eq!(stats.committed, 100);
+        assert!(stats.lost > 0);
+
+        // Every frame that made it is delivered exactly once
+        let mut got = Vec::new();
+        loop {
+            match prx.receive::<u32>() {
+                Ok(Some(msg)) => got.push(msg.msg),
+                Ok(None) => break,
+                Err(Error::InvalidNonce) => {}
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+
+        let len = got.len();
+        got.sort();
+        got.dedup();
+        assert_eq!(got.len(), len);
+        assert_eq!(len as u32, stats.committed - stats.lost);
+    }
+
+    #[test]
+    fn loopback_prx_queue() {
+        use crate::queue::Priority;
+
+        let (mut ptx, mut prx) = radios(LinkConfig::default(), LinkConfig::default());
+
+        // Nothing is sent until the device has polled
+        ptx.send(&0u32, 1).unwrap();
+        prx.enqueue(&1u32, 1, Priority::Routine, None, 0).unwrap();
+        prx.enqueue(&2u32, 1, Priority::Safety, None, 0).unwrap();
+        prx.enqueue(&3u32, 1, Priority::Routine, Some(100), 0)
+            .unwrap();
+        assert_eq!(prx.queued(1), 3);
+        assert_eq!(prx.receive::<u32>().unwrap().unwrap().msg, 0);
+
+        // Small messages are sent together, and the safety message jumps ahead
+        assert_eq!(prx.service(10).unwrap(), 0);
+        assert_eq!(prx.queued(1), 0);
+        assert_eq!(prx.stats().frames_sent, 1);
+        assert_eq!(ptx.receive::<u32>().unwrap().unwrap().msg, 2);
+        assert_eq!(ptx.receive::<u32>().unwrap().unwrap().msg, 1);
+        assert_eq!(ptx.receive::<u32>().unwrap().unwrap().msg, 3);
+        assert!(ptx.receive::<u32>().unwrap().is_none());
+
+        // Only one frame is in flight, until the device polls again
+        prx.enqueue(&4u32, 1, Priority::Routine, Some(100), 20)
+            .unwrap();
+        assert_eq!(prx.service(20).unwrap(), 0);
+        assert_eq!(prx.queued(1), 1);
+        assert!(ptx.receive::<u32>().unwrap().is_none());
+
+        ptx.send(&0u32, 1).unwrap();
+        assert!(prx.receive::<u32>().unwrap().is_some());
+        assert_eq!(prx.service(30).unwrap(), 0);
+        assert_eq!(ptx.receive::<u32>().unwrap().unwrap().msg, 4);
+
+        // The device went quiet for too long, so the next command expires
+        prx.enqueue(&5u32, 1, Priority::Routine, Some(100), 30)
+            .unwrap();
+        assert_eq!(prx.service(500).unwrap(), 1);
+        assert_eq!(prx.queued(1), 0);
+        assert!(ptx.receive::<u32>().unwrap().is_none());
+        assert_eq!(prx.stats().expired, 1);
+    }
+
+    #[test]
+    fn loopback_prx_queue_error_on_one_pipe() {
+        use crate::queue::Priority;
+
+        let (mut ptx, mut prx) = radios(LinkConfig::default(), LinkConfig::default());
+
+        // Only pipe 1 has a key, so nothing can be sent on pipe 0
+        prx.set_master_key(0, None);
+        prx.set_device_key(1, 0, Some(&KEY));
+        ptx.send(&0u32, 1).unwrap();
+        assert_eq!(prx.receive::<u32>().unwrap().unwrap().msg, 0);
+
+        prx.enqueue(&1u32, 0, Priority::Routine, None, 0).unwrap();
+        prx.enqueue(&2u32, 1, Priority::Routine, None, 0).unwrap();
+        prx.enqueue(&3u32, 2, Priority::Routine, Some(100), 0)
+            .unwrap();
+
+        // Pipe 1 is still served, and the expired message is still counted
+        assert!(matches!(prx.service(500), Err(Error::NoKey)));
+        assert_eq!(prx.queued(0), 1);
+        assert_eq!(prx.queued(1), 0);
+        assert_eq!(prx.queued(2), 0);
+        assert_eq!(prx.stats().expired, 1);
+        assert_eq!(ptx.receive::<u32>().unwrap().unwrap().msg, 2);
+    }
+
+    #[test]
+    fn loopback_adaptive_polling() {
+        use crate::queue::Priority;
+
+        let timer = FakeTimer::default();
+        let (a, b) = pair(LinkConfig::default(), LinkConfig::default());
+        let mut ptx: FleetRadioPtx<Loopback, FakeTimer> =
+            FleetRadioPtx::new(a, Some(&KEY), NETWORK, timer.clone(), 1000, &mut FakeRng(1));
+        let mut prx: FleetRadioPrx<Loopback> =
+            FleetRadioPrx::new(b, Some(&KEY), NETWORK, 1000, &mut FakeRng(2));
+        ptx.set_poll_intervals(10, 80);
+
+        // Idle polls back off, and aren't seen by the PRX application
+        assert!(!ptx.poll(0).unwrap());
+        assert_eq!(ptx.ticks_until_poll(), 10);
+        for &now in [10, 30, 70, 150].iter() {
+            timer.0.set(now);
+            assert!(ptx.poll(0).unwrap());
+            assert!(prx.receive::<u32>().unwrap().is_none());
+        }
+        assert_eq!(ptx.poll_interval(), 80);
+
+        // The PRX has two messages too large to send together, and says
+        // so with the first one
+        prx.enqueue(&[[1u8; 30]; 4], 0, Priority::Routine, None, 0)
+            .unwrap();
+        prx.enqueue(&[[2u8; 30]; 4], 0, Priority::Routine, None, 0)
+            .unwrap();
+        prx.service(0).unwrap();
+        assert_eq!(
+            ptx.receive::<[[u8; 30]; 4]>().unwrap().unwrap().msg[0][0],
+            1
+        );
+        assert_eq!(ptx.poll_interval(), 10);
+
+        timer.0.set(160);
+        assert!(ptx.poll(0).unwrap());
+        assert_eq!(ptx.poll_interval(), 10);
+        assert!(prx.receive::<u32>().unwrap().is_none());
+        prx.service(0).unwrap();
+        assert_eq!(
+            ptx.receive::<[[u8; 30]; 4]>().unwrap().unwrap().msg[0][0],
+            2
+        );
+
+        // Nothing more is pending, so polling backs off again
+        timer.0.set(170);
+        assert!(ptx.poll(0).unwrap());
+        assert_eq!(ptx.poll_interval(), 20);
+
+        // Sending a message speeds polling up, as a response is likely
+        ptx.send(&3u32, 0).unwrap();
+        assert_eq!(ptx.poll_interval(), 10);
+        assert_eq!(prx.receive::<u32>().unwrap().unwrap().msg, 3);
+    }
+
+    #[test]
+    fn loopback_batching() {
+        let (mut ptx, mut prx) = radios(LinkConfig::default(), LinkConfig::default());
+
+        for i in 0..10u32 {
+            ptx.batch(&i, 2).unwrap();
+        }
+        assert_eq!(ptx.batched(), 10);
+        assert_eq!(ptx.stats().frames_sent, 0);
+
+        // A message for another pipe sends the batch first
+        ptx.batch(&100u32, 3).unwrap();
+        assert_eq!(ptx.stats().frames_sent, 1);
+        ptx.flush().unwrap();
+        assert_eq!(ptx.batched(), 0);
+        assert_eq!(ptx.stats().frames_sent, 2);
+
+        for i in 0..10u32 {
+            let msg = prx.receive::<u32>().unwrap().unwrap();
+            assert_eq!((msg.msg, msg.meta.pipe), (i, 2));
+        }
+        let msg = prx.receive::<u32>().unwrap().unwrap();
+        assert_eq!((msg.msg, msg.meta.pipe), (100, 3));
+        assert!(prx.receive::<u32>().unwrap().is_none());
+
+        // Once a frame is full, it is sent, and a new batch is started
+        ptx.send(&0u32, 0).unwrap();
+        assert!(prx.receive::<u32>().unwrap().is_some());
+        let row = [0xA5u8; 30];
+        for _ in 0..8 {
+            prx.batch(&row, 0).unwrap();
+        }
+        assert_eq!(prx.stats().frames_sent, 1);
+        assert_eq!(prx.batched(), 1);
+
+        // Sending a message sends the batch first, keeping the order
+        prx.send(&[[0x5Au8; 30]; 20], 0).unwrap();
+        assert_eq!(prx.batched(), 0);
+        for _ in 0..8 {
+            assert_eq!(ptx.receive::<[u8; 30]>().unwrap().unwrap().msg, row);
+        }
+        assert_eq!(
+            ptx.receive::<[[u8; 30]; 20]>().unwrap().unwrap().msg[19],
+            [0x5A; 30]
+        );
+    }
+}
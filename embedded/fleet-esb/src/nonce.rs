@@ -1,27 +1,121 @@
-use crate::{Error, MAGIC_WORD, NONCE_SIZE};
+use crate::{
+    CipherSuite, Error, ProtocolVersion, EPOCH_MASK, EPOCH_SHIFT, MAGIC_MASK, MAGIC_WORD,
+    NONCE_SIZE, PROTOCOL_VERSION, SENDER_BIT, SUITE_MASK, SUITE_SHIFT,
+};
 
+/// The end of the link a frame was sent from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sender {
+    Ptx,
+    Prx,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct FleetNonce {
     pub(crate) tick: u32,
     pub(crate) msg_count: u32,
+
+    /// The protocol version of the sender
+    pub(crate) version: ProtocolVersion,
+
+    /// The epoch of the key the frame is encrypted with
+    pub(crate) epoch: u8,
+
+    pub(crate) sender: Sender,
 }
 
 impl FleetNonce {
-    pub fn to_bytes(&self) -> [u8; NONCE_SIZE] {
+    /// A nonce for a frame sent by the PTX
+    pub fn new(tick: u32, msg_count: u32) -> Self {
+        Self {
+            tick,
+            msg_count,
+            version: PROTOCOL_VERSION,
+            epoch: 0,
+            sender: Sender::Ptx,
+        }
+    }
+
+    /// A nonce for a frame sent by the PRX
+    pub fn from_prx(tick: u32, msg_count: u32) -> Self {
+        Self {
+            sender: Sender::Prx,
+            ..Self::new(tick, msg_count)
+        }
+    }
+
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    pub fn msg_count(&self) -> u32 {
+        self.msg_count
+    }
+
+    /// The protocol version of the sender. This may differ from ours in
+    /// its minor and trivial versions.
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// The key epoch of the frame. See the `keyring` module.
+    pub fn epoch(&self) -> u8 {
+        self.epoch
+    }
+
+    pub fn sender(&self) -> Sender {
+        self.sender
+    }
+
+    pub fn to_bytes(&self, suite: CipherSuite) -> [u8; NONCE_SIZE] {
         let mut nonce = [0u8; NONCE_SIZE];
         nonce[0..4].copy_from_slice(&self.msg_count.to_le_bytes());
         nonce[4..8].copy_from_slice(&self.tick.to_le_bytes());
-        nonce[8..12].copy_from_slice(&MAGIC_WORD.to_le_bytes());
+        let epoch = (u32::from(self.epoch) << EPOCH_SHIFT) & EPOCH_MASK;
+        let sender = match self.sender {
+            Sender::Ptx => 0,
+            Sender::Prx => SENDER_BIT,
+        };
+        let magic = self.version.magic_word(suite) ^ epoch ^ sender;
+        nonce[8..12].copy_from_slice(&magic.to_le_bytes());
         nonce
     }
 
-    pub fn try_from_bytes(buf: &[u8]) -> Result<Self, Error> {
+    pub fn try_from_bytes(buf: &[u8], suite: CipherSuite) -> Result<Self, Error> {
         if buf.len() != NONCE_SIZE {
             return Err(Error::BadNonce);
         }
 
-        if buf[8..12] != MAGIC_WORD.to_le_bytes() {
+        let mut magic_buf = [0u8; 4];
+        magic_buf.copy_from_slice(&buf[8..12]);
+        let magic = u32::from_le_bytes(magic_buf);
+
+        let magic_bits = MAGIC_MASK & !EPOCH_MASK & !SENDER_BIT;
+        if (magic & magic_bits) != (MAGIC_WORD & magic_bits) {
             return Err(Error::BadNonce);
         }
+        let epoch = (((magic ^ MAGIC_WORD) & EPOCH_MASK) >> EPOCH_SHIFT) as u8;
+        let sender = if ((magic ^ MAGIC_WORD) & SENDER_BIT) != 0 {
+            Sender::Prx
+        } else {
+            Sender::Ptx
+        };
+
+        // Any minor version is fine, as long as the major version matches
+        let version = ProtocolVersion::from_magic(magic);
+        if !PROTOCOL_VERSION.is_compatible(&version) {
+            return Err(Error::IncompatibleVersion {
+                ours: PROTOCOL_VERSION,
+                theirs: version,
+            });
+        }
+
+        if (magic & SUITE_MASK) != (u32::from(suite.bits()) << SUITE_SHIFT) {
+            return Err(Error::CipherSuiteMismatch {
+                ours: suite,
+                theirs: ((magic & SUITE_MASK) >> SUITE_SHIFT) as u8,
+            });
+        }
 
         let mut m_ct_buf = [0u8; 4];
         let mut tick_buf = [0u8; 4];
@@ -32,6 +126,538 @@ impl FleetNonce {
         Ok(Self {
             msg_count: u32::from_le_bytes(m_ct_buf),
             tick: u32::from_le_bytes(tick_buf),
+            version,
+            epoch,
+            sender,
         })
     }
 }
+
+/// Half of the u32 range. Rolling values that are ahead of a reference
+/// by no more than this amount are considered "newer" than it.
+const HALF_RANGE: u32 = u32::MAX / 2;
+
+/// The number of message counts behind the newest received count that
+/// are still accepted, as long as they have not been seen before
+pub const REPLAY_WINDOW_SIZE: u32 = 32;
+
+/// An IPsec-style anti-replay window over rolling message counts
+///
+/// The newest accepted count is tracked, along with a bitmap of which
+/// of the `REPLAY_WINDOW_SIZE` counts before it have been seen. Counts
+/// ahead of the newest (by less than half the u32 range) are always
+/// accepted, which allows for lost frames. Counts inside the window are
+/// accepted once, which allows for reordered frames. Everything else is
+/// rejected.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReplayWindow {
+    synced: bool,
+    highest: u32,
+
+    // Bit N set means `highest - N` has been seen
+    bitmap: u32,
+}
+
+impl ReplayWindow {
+    pub const fn new() -> Self {
+        Self {
+            synced: false,
+            highest: 0,
+            bitmap: 0,
+        }
+    }
+
+    /// The newest message count accepted, if any
+    pub fn highest(&self) -> Option<u32> {
+        if self.synced {
+            Some(self.highest)
+        } else {
+            None
+        }
+    }
+
+    /// Check whether a message count would be accepted, without updating
+    /// the window. This should be done before decryption.
+    pub fn check(&self, count: u32) -> Result<(), Error> {
+        if !self.synced {
+            // Nothing to compare against yet
+            return Ok(());
+        }
+
+        let ahead = count.wrapping_sub(self.highest);
+
+        if ahead == 0 {
+            // Exactly the newest count, replayed
+            return Err(Error::InvalidNonce);
+        }
+
+        if ahead <= HALF_RANGE {
+            return Ok(());
+        }
+
+        let behind = self.highest.wrapping_sub(count);
+
+        if behind >= REPLAY_WINDOW_SIZE {
+            // Too old to tell if we've seen it
+            return Err(Error::InvalidNonce);
+        }
+
+        if (self.bitmap & (1 << behind)) != 0 {
+            return Err(Error::InvalidNonce);
+        }
+
+        Ok(())
+    }
+
+    /// Mark a message count as seen. This should only be done once
+    /// `check` has passed AND the frame has been authenticated.
+    pub fn accept(&mut self, count: u32) {
+        if !self.synced {
+            self.resync(count);
+            return;
+        }
+
+        let ahead = count.wrapping_sub(self.highest);
+
+        if (ahead != 0) && (ahead <= HALF_RANGE) {
+            self.bitmap = if ahead < REPLAY_WINDOW_SIZE {
+                (self.bitmap << ahead) | 1
+            } else {
+                1
+            };
+            self.highest = count;
+        } else {
+            let behind = self.highest.wrapping_sub(count);
+            if behind < REPLAY_WINDOW_SIZE {
+                self.bitmap |= 1 << behind;
+            }
+        }
+    }
+
+    /// Discard all history, and treat `count` as the newest seen count
+    pub fn resync(&mut self, count: u32) {
+        self.synced = true;
+        self.highest = count;
+        self.bitmap = 1;
+    }
+
+    /// Discard all history. The next count will be accepted unconditionally.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+/// A staleness check over rolling ticks
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TickWindow {
+    window: u32,
+    last: Option<u32>,
+}
+
+impl TickWindow {
+    pub const fn new(window: u32) -> Self {
+        Self { window, last: None }
+    }
+
+    /// The newest tick accepted, if any
+    pub fn last(&self) -> Option<u32> {
+        self.last
+    }
+
+    /// Check whether a tick is fresh enough to be accepted.
+    ///
+    /// If `now` is provided, the tick must have been generated from the
+    /// same clock (e.g. the PTX checking ticks echoed back by the PRX). It
+    /// must be no more than `window` ticks in the past, not in the future,
+    /// and not older than the newest tick accepted so far.
+    ///
+    /// Otherwise, the tick may not be more than `window` ticks older than
+    /// the newest tick accepted so far.
+    pub fn check(&self, tick: u32, now: Option<u32>) -> Result<(), Error> {
+        let good = match (now, self.last) {
+            (Some(now), Some(last)) => {
+                (now.wrapping_sub(tick) <= self.window) && Self::not_older(last, tick, 0)
+            }
+            (Some(now), None) => now.wrapping_sub(tick) <= self.window,
+            (None, Some(last)) => Self::not_older(last, tick, self.window),
+            (None, None) => true,
+        };
+
+        if good {
+            Ok(())
+        } else {
+            Err(Error::InvalidNonce)
+        }
+    }
+
+    /// Is this tick strictly newer than any tick accepted so far?
+    pub fn is_newer(&self, tick: u32) -> bool {
+        match self.last {
+            Some(last) => {
+                let ahead = tick.wrapping_sub(last);
+                (ahead != 0) && (ahead <= HALF_RANGE)
+            }
+            None => true,
+        }
+    }
+
+    /// Record a tick as accepted
+    pub fn accept(&mut self, tick: u32) {
+        if self.is_newer(tick) {
+            self.last = Some(tick);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+
+    fn not_older(last: u32, tick: u32, window: u32) -> bool {
+        (tick.wrapping_sub(last) <= HALF_RANGE) || (last.wrapping_sub(tick) <= window)
+    }
+}
+
+/// The complete nonce check used by both the PTX and PRX: a replay
+/// window over the message count, and a staleness window over the tick
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NonceWindow {
+    counts: ReplayWindow,
+    ticks: TickWindow,
+}
+
+impl NonceWindow {
+    pub const fn new(tick_window: u32) -> Self {
+        Self {
+            counts: ReplayWindow::new(),
+            ticks: TickWindow::new(tick_window),
+        }
+    }
+
+    /// The newest tick accepted, if any
+    pub fn last_tick(&self) -> Option<u32> {
+        self.ticks.last()
+    }
+
+    /// The newest message count accepted, if any
+    pub fn last_count(&self) -> Option<u32> {
+        self.counts.highest()
+    }
+
+    /// Check whether a nonce would be accepted, without updating the
+    /// window. See `TickWindow::check` for the meaning of `now`.
+    ///
+    /// When checking against our own clock, a tick newer than anything seen
+    /// before paired with an old message count means the peer has restarted
+    /// and started counting from scratch. A replayed frame can never carry
+    /// one of our ticks that is newer than all the others, so this is
+    /// accepted, and the count window is resynchronized on `accept`.
+    pub fn check(&self, nonce: &FleetNonce, now: Option<u32>) -> Result<(), Error> {
+        self.ticks.check(nonce.tick, now)?;
+
+        match self.counts.check(nonce.msg_count) {
+            Err(_) if now.is_some() && self.ticks.is_newer(nonce.tick) => Ok(()),
+            res => res,
+        }
+    }
+
+    /// Record a nonce as accepted. This should only be done once `check`
+    /// has passed AND the frame has been authenticated.
+    pub fn accept(&mut self, nonce: &FleetNonce) {
+        if self.counts.check(nonce.msg_count).is_ok() {
+            self.counts.accept(nonce.msg_count);
+        } else {
+            self.counts.resync(nonce.msg_count);
+        }
+        self.ticks.accept(nonce.tick);
+    }
+
+    pub fn reset(&mut self) {
+        self.counts.reset();
+        self.ticks.reset();
+    }
+}
+
+#[test]
+fn nonce_suite_mismatch() {
+    let nonce = FleetNonce::new(1234, 5678);
+
+    // The existing ChaCha8 wire format is unchanged
+    let bytes = nonce.to_bytes(CipherSuite::ChaCha8Poly1305);
+    assert_eq!(bytes[8..12], MAGIC_WORD.to_le_bytes());
+
+    let bytes = nonce.to_bytes(CipherSuite::Aes128GcmSiv);
+    let parsed = FleetNonce::try_from_bytes(&bytes, CipherSuite::Aes128GcmSiv).unwrap();
+    assert_eq!(parsed.tick, 1234);
+    assert_eq!(parsed.msg_count, 5678);
+
+    match FleetNonce::try_from_bytes(&bytes, CipherSuite::ChaCha8Poly1305) {
+        Err(Error::CipherSuiteMismatch { ours, theirs }) => {
+            assert_eq!(ours, CipherSuite::ChaCha8Poly1305);
+            assert_eq!(theirs, CipherSuite::Aes128GcmSiv.bits());
+        }
+        _ => panic!(),
+    }
+
+    // Garbage is still just a bad nonce
+    let mut bytes = bytes;
+    bytes[11] ^= 0xFF;
+    match FleetNonce::try_from_bytes(&bytes, CipherSuite::Aes128GcmSiv) {
+        Err(Error::BadNonce) => {}
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn nonce_version_negotiation() {
+    let suite = CipherSuite::ChaCha8Poly1305;
+
+    // A peer on a newer minor version is accepted, and its version kept
+    let newer = ProtocolVersion {
+        minor: PROTOCOL_VERSION.minor + 1,
+        ..PROTOCOL_VERSION
+    };
+    let mut nonce = FleetNonce::new(1234, 5678);
+    nonce.version = newer;
+    let bytes = nonce.to_bytes(suite);
+    let parsed = FleetNonce::try_from_bytes(&bytes, suite).unwrap();
+    assert_eq!(parsed, nonce);
+    assert_eq!(parsed.version(), newer);
+    assert_eq!(parsed.to_bytes(suite), bytes);
+
+    // A different major version is rejected, whatever the cipher suite
+    let other = ProtocolVersion {
+        major: PROTOCOL_VERSION.major + 1,
+        ..PROTOCOL_VERSION
+    };
+    nonce.version = other;
+    let bytes = nonce.to_bytes(CipherSuite::Aes128GcmSiv);
+    match FleetNonce::try_from_bytes(&bytes, suite) {
+        Err(Error::IncompatibleVersion { ours, theirs }) => {
+            assert_eq!(ours, PROTOCOL_VERSION);
+            assert_eq!(theirs, other);
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn nonce_sender() {
+    let suite = CipherSuite::ChaCha8Poly1305;
+    let ptx = FleetNonce::new(1, 2);
+    let prx = FleetNonce::from_prx(1, 2);
+
+    // The same tick and count give a different nonce in each direction
+    assert_ne!(ptx.to_bytes(suite), prx.to_bytes(suite));
+
+    for nonce in &[ptx, prx] {
+        let parsed = FleetNonce::try_from_bytes(&nonce.to_bytes(suite), suite).unwrap();
+        assert_eq!(parsed, *nonce);
+    }
+    assert_eq!(prx.sender(), Sender::Prx);
+}
+
+#[test]
+fn nonce_rejects_baseline_version() {
+    // Nodes from before protocol versions were negotiated
+    const BASELINE_MAGIC: u32 = 0xF133_7001;
+
+    let mut bytes = FleetNonce::new(1234, 5678).to_bytes(CipherSuite::ChaCha8Poly1305);
+    bytes[8..12].copy_from_slice(&BASELINE_MAGIC.to_le_bytes());
+    match FleetNonce::try_from_bytes(&bytes, CipherSuite::ChaCha8Poly1305) {
+        Err(Error::IncompatibleVersion { ours, theirs }) => {
+            assert_eq!(ours, PROTOCOL_VERSION);
+            assert_eq!(theirs, ProtocolVersion::from_magic(BASELINE_MAGIC));
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn nonce_key_epoch() {
+    let suite = CipherSuite::ChaCha8Poly1305;
+
+    // Epoch 0 is the plain magic word
+    let mut nonce = FleetNonce::new(1, 2);
+    assert_eq!(nonce.to_bytes(suite)[8..12], MAGIC_WORD.to_le_bytes());
+
+    for epoch in 0..16 {
+        nonce.epoch = epoch;
+        let bytes = nonce.to_bytes(suite);
+        let parsed = FleetNonce::try_from_bytes(&bytes, suite).unwrap();
+        assert_eq!(parsed.epoch(), epoch);
+        assert_eq!(parsed, nonce);
+    }
+}
+
+#[test]
+fn replay_window_rejects_duplicates_across_rollover() {
+    // Start a bit before the rollover, and walk well past it
+    let start = u32::MAX - 100;
+    let mut window = ReplayWindow::new();
+
+    for i in 0..200u32 {
+        let count = start.wrapping_add(i);
+        assert!(window.check(count).is_ok(), "count {} rejected", count);
+        window.accept(count);
+
+        // Every count in the window (including the newest) is now a replay
+        for back in 0..REPLAY_WINDOW_SIZE.min(i + 1) {
+            assert!(window.check(count.wrapping_sub(back)).is_err());
+        }
+
+        // And the next one is always fine
+        assert!(window.check(count.wrapping_add(1)).is_ok());
+    }
+}
+
+#[test]
+fn replay_window_out_of_order_across_rollover() {
+    // For every position of the rollover relative to the window, accept
+    // even counts first, then the odd ones late
+    for offset in 0..(2 * REPLAY_WINDOW_SIZE) {
+        let start = 0u32.wrapping_sub(offset);
+        let mut window = ReplayWindow::new();
+
+        for i in (0..REPLAY_WINDOW_SIZE).step_by(2) {
+            let count = start.wrapping_add(i);
+            assert!(window.check(count).is_ok());
+            window.accept(count);
+        }
+
+        for i in (1..REPLAY_WINDOW_SIZE - 1).step_by(2) {
+            let count = start.wrapping_add(i);
+            assert!(window.check(count).is_ok(), "offset {} i {}", offset, i);
+            window.accept(count);
+            assert!(window.check(count).is_err());
+        }
+
+        assert_eq!(
+            window.highest(),
+            Some(start.wrapping_add(REPLAY_WINDOW_SIZE - 2))
+        );
+    }
+}
+
+#[test]
+fn replay_window_too_old() {
+    for &start in &[0, 1, u32::MAX, u32::MAX - 5, HALF_RANGE] {
+        let mut window = ReplayWindow::new();
+        window.accept(start);
+
+        // Just outside of the window, or anywhere in the "behind" half
+        for &back in &[REPLAY_WINDOW_SIZE, REPLAY_WINDOW_SIZE + 1, HALF_RANGE] {
+            assert!(window.check(start.wrapping_sub(back)).is_err());
+        }
+
+        // Anywhere in the "ahead" half is fine
+        for &ahead in &[1, REPLAY_WINDOW_SIZE, HALF_RANGE] {
+            assert!(window.check(start.wrapping_add(ahead)).is_ok());
+        }
+    }
+}
+
+#[test]
+fn replay_window_large_jump_clears_history() {
+    let mut window = ReplayWindow::new();
+    window.accept(u32::MAX - 1);
+    window.accept(u32::MAX);
+
+    // Jump past the window, across the rollover
+    window.accept(REPLAY_WINDOW_SIZE + 10);
+
+    assert!(window.check(u32::MAX).is_err());
+    assert!(window.check(REPLAY_WINDOW_SIZE + 10).is_err());
+    assert!(window.check(REPLAY_WINDOW_SIZE + 9).is_ok());
+    assert!(window.check(11).is_ok());
+    assert!(window.check(10).is_err());
+}
+
+#[test]
+fn tick_window_with_clock() {
+    let window = 1000;
+
+    for &now in &[0u32, 10, u32::MAX, u32::MAX - 10] {
+        let tw = TickWindow::new(window);
+
+        assert!(tw.check(now, Some(now)).is_ok());
+        assert!(tw.check(now.wrapping_sub(window), Some(now)).is_ok());
+        assert!(tw.check(now.wrapping_sub(window + 1), Some(now)).is_err());
+
+        // Ticks from the future are never valid
+        assert!(tw.check(now.wrapping_add(1), Some(now)).is_err());
+    }
+}
+
+#[test]
+fn tick_window_without_clock() {
+    let window = 1000;
+
+    for &last in &[0u32, 10, u32::MAX, u32::MAX - 10] {
+        let mut tw = TickWindow::new(window);
+        tw.accept(last);
+
+        assert!(tw.check(last.wrapping_add(HALF_RANGE), None).is_ok());
+        assert!(tw.check(last.wrapping_sub(window), None).is_ok());
+        assert!(tw.check(last.wrapping_sub(window + 1), None).is_err());
+
+        // Older ticks are fine, but don't move the window back
+        assert!(!tw.is_newer(last));
+        tw.accept(last.wrapping_sub(1));
+        assert_eq!(tw.last(), Some(last));
+
+        tw.accept(last.wrapping_add(1));
+        assert_eq!(tw.last(), Some(last.wrapping_add(1)));
+    }
+}
+
+#[test]
+fn tick_window_with_clock_is_monotonic() {
+    let mut tw = TickWindow::new(1000);
+    tw.accept(u32::MAX);
+
+    // Still in the staleness window, but older than the last accepted tick
+    assert!(tw.check(u32::MAX - 1, Some(5)).is_err());
+    assert!(tw.check(u32::MAX, Some(5)).is_ok());
+    assert!(tw.check(2, Some(5)).is_ok());
+}
+
+#[test]
+fn nonce_window_resyncs_on_newer_tick() {
+    let mut nw = NonceWindow::new(100);
+    let now = 10;
+    let first = FleetNonce::new(u32::MAX - 2, 5000);
+
+    assert!(nw.check(&first, Some(now)).is_ok());
+    nw.accept(&first);
+
+    // Replays are rejected
+    assert!(nw.check(&first, Some(now)).is_err());
+
+    // Peer restarted with a lower message count, answering a newer tick
+    let restarted = FleetNonce::new(3, 12);
+    assert!(nw.check(&restarted, Some(now)).is_ok());
+    nw.accept(&restarted);
+    assert_eq!(nw.last_count(), Some(12));
+    assert_eq!(nw.last_tick(), Some(3));
+
+    // Frames from before the restart are still rejected
+    assert!(nw.check(&first, Some(now)).is_err());
+
+    // Same tick, stale count is rejected
+    let stale = FleetNonce::new(3, 11u32.wrapping_sub(REPLAY_WINDOW_SIZE));
+    assert!(nw.check(&stale, Some(now)).is_err());
+}
+
+#[test]
+fn nonce_window_no_resync_without_clock() {
+    let mut nw = NonceWindow::new(100);
+    let first = FleetNonce::new(50, 5000);
+    nw.accept(&first);
+
+    // Without a clock, a newer tick is not proof of freshness
+    let restarted = FleetNonce::new(60, 12);
+    assert!(nw.check(&restarted, None).is_err());
+
+    nw.reset();
+    assert!(nw.check(&restarted, None).is_ok());
+}
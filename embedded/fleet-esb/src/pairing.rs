@@ -0,0 +1,542 @@
+//! Pairing new devices with the modem
+//!
+//! A fresh device has no key, and no pipe of its own. In pairing mode, it
+//! talks to the modem on `PAIRING_PIPE` using `PAIRING_KEY`. That key is not
+//! a secret, and only gets frames through the radio: the exchange below is
+//! what keeps pairing secure.
+//!
+//! 1. The device sends its X25519 public key and hardware ID.
+//! 2. The modem sends its own public key, and a commitment to a random
+//!    nonce.
+//! 3. The device sends its own random nonce.
+//! 4. The modem reveals its nonce, which the device checks against the
+//!    commitment.
+//! 5. Both sides compute a six digit code over everything exchanged so far.
+//!    The device prints its code over RTT, and the user enters it into
+//!    `fleet-cli pair`. The modem only carries on if the codes match.
+//! 6. The modem sends the device its pipe, address and key, encrypted with
+//!    a key derived from the X25519 shared secret. The device confirms it
+//!    has them, and both sides store the result.
+//!
+//! As the modem commits to its nonce before it sees the device's nonce,
+//! someone in the middle of the exchange can't steer both sides to the
+//! same code, and has one chance in a million of guessing it.
+//!
+//! Once a nonce has been revealed, it must never be used again: otherwise
+//! someone in the middle could restart the exchange, and pick their own
+//! nonce to match a code they already know. Each restart takes a fresh key
+//! pair and nonce for the modem, and a fresh nonce for the device.
+
+use chacha20poly1305::{
+    aead::{generic_array::GenericArray, AeadInPlace},
+    ChaCha20Poly1305,
+};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{cipher::FleetCipher, Error, CRYPT_SIZE, NONCE_SIZE, NUM_PIPES};
+
+/// The pipe used by devices that haven't been paired yet
+pub const PAIRING_PIPE: u8 = (NUM_PIPES - 1) as u8;
+
+/// The key used on `PAIRING_PIPE`. This is public, see the module docs.
+pub const PAIRING_KEY: [u8; 32] = *b"fleet-esb pairing, not a secret!";
+
+pub const PAIRING_NONCE_SIZE: usize = 16;
+
+/// Pairing codes are below this, and shown as six digits
+pub const CODE_RANGE: u32 = 1_000_000;
+
+const COMMIT_LABEL: &[u8] = b"fleet-esb pairing commit v1";
+const CODE_LABEL: &[u8] = b"fleet-esb pairing code v1";
+const KEY_INFO: &[u8] = b"fleet-esb pairing key v1";
+
+const ASSIGN_NONCE: [u8; NONCE_SIZE] = [0; NONCE_SIZE];
+const CONFIRM_NONCE: [u8; NONCE_SIZE] = [1; NONCE_SIZE];
+
+/// What the modem gives a newly paired device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Assignment {
+    pub pipe: u8,
+
+    /// The base address and prefix of the pipe
+    pub base: [u8; 4],
+    pub prefix: u8,
+
+    /// The device's key, and its ID. The low four bits of the ID are the
+    /// key epoch.
+    pub key_id: u32,
+    pub key: [u8; 32],
+}
+
+/// An `Assignment`, with the key encrypted for the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SealedAssignment {
+    pub pipe: u8,
+    pub base: [u8; 4],
+    pub prefix: u8,
+    pub key_id: u32,
+    pub key: [u8; 32],
+    pub tag: [u8; CRYPT_SIZE],
+}
+
+/// Everything both sides know once the nonces have been exchanged
+struct Transcript {
+    device_id: u64,
+    device_public: [u8; 32],
+    host_public: [u8; 32],
+    device_nonce: [u8; PAIRING_NONCE_SIZE],
+    host_nonce: [u8; PAIRING_NONCE_SIZE],
+}
+
+impl Transcript {
+    fn code(&self) -> u32 {
+        let hash = Sha256::new()
+            .chain(CODE_LABEL)
+            .chain(self.device_id.to_le_bytes())
+            .chain(self.device_public)
+            .chain(self.host_public)
+            .chain(self.device_nonce)
+            .chain(self.host_nonce)
+            .result();
+
+        let mut word = [0u8; 4];
+        word.copy_from_slice(&hash[..4]);
+        u32::from_le_bytes(word) % CODE_RANGE
+    }
+
+    /// The key protecting the assignment, from the X25519 shared secret
+    fn key(&self, shared: &[u8; 32]) -> ChaCha20Poly1305 {
+        let mut salt = [0u8; 2 * PAIRING_NONCE_SIZE];
+        salt[..PAIRING_NONCE_SIZE].copy_from_slice(&self.device_nonce);
+        salt[PAIRING_NONCE_SIZE..].copy_from_slice(&self.host_nonce);
+
+        let mut info = [0u8; 24 + 64];
+        info[..KEY_INFO.len()].copy_from_slice(KEY_INFO);
+        info[24..56].copy_from_slice(&self.device_public);
+        info[56..].copy_from_slice(&self.host_public);
+
+        let hk = Hkdf::<Sha256>::new(Some(&salt), shared);
+        let mut key = [0u8; 32];
+
+        // 32 bytes is always a valid output length for SHA256
+        hk.expand(&info, &mut key).ok();
+
+        ChaCha20Poly1305::from_key(&key)
+    }
+}
+
+fn commitment(
+    host_public: &[u8; 32],
+    device_public: &[u8; 32],
+    host_nonce: &[u8; PAIRING_NONCE_SIZE],
+) -> [u8; 32] {
+    let hash = Sha256::new()
+        .chain(COMMIT_LABEL)
+        .chain(host_public)
+        .chain(device_public)
+        .chain(host_nonce)
+        .result();
+
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&hash);
+    out
+}
+
+/// The fields of the assignment that are sent in the clear, but authenticated
+fn assignment_aad(pipe: u8, base: &[u8; 4], prefix: u8, key_id: u32) -> [u8; 10] {
+    let mut aad = [0u8; 10];
+    aad[0] = pipe;
+    aad[1..5].copy_from_slice(base);
+    aad[5] = prefix;
+    aad[6..].copy_from_slice(&key_id.to_le_bytes());
+    aad
+}
+
+/// The tag the device sends to show it received its assignment
+fn confirmation(crypt: &ChaCha20Poly1305) -> Result<[u8; CRYPT_SIZE], Error> {
+    let tag = crypt.encrypt_in_place_detached(
+        GenericArray::from_slice(&CONFIRM_NONCE),
+        b"paired",
+        &mut [],
+    )?;
+
+    let mut out = [0u8; CRYPT_SIZE];
+    out.copy_from_slice(&tag);
+    Ok(out)
+}
+
+/// The device side of pairing
+pub struct DevicePairing {
+    device_id: u64,
+    secret: StaticSecret,
+    public: [u8; 32],
+
+    // The nonce sent back for the last commitment
+    nonce: [u8; PAIRING_NONCE_SIZE],
+
+    // The modem's public key and commitment, once received
+    host: Option<([u8; 32], [u8; 32])>,
+
+    // The key protecting the assignment, once the commitment is checked
+    crypt: Option<ChaCha20Poly1305>,
+}
+
+impl DevicePairing {
+    /// Start pairing. `secret` MUST be freshly generated from a hardware
+    /// RNG.
+    pub fn new(device_id: u64, secret: [u8; 32]) -> Self {
+        let secret = StaticSecret::from(secret);
+        let public = *PublicKey::from(&secret).as_bytes();
+
+        Self {
+            device_id,
+            secret,
+            public,
+            nonce: [0; PAIRING_NONCE_SIZE],
+            host: None,
+            crypt: None,
+        }
+    }
+
+    pub fn device_id(&self) -> u64 {
+        self.device_id
+    }
+
+    /// The public key to send to the modem
+    pub fn public_key(&self) -> &[u8; 32] {
+        &self.public
+    }
+
+    /// Take the modem's public key and commitment, returning the nonce to
+    /// send back. `nonce` MUST be freshly generated from a hardware RNG, for
+    /// every commitment, see the module docs.
+    pub fn commit(
+        &mut self,
+        host_public: &[u8; 32],
+        commitment: &[u8; 32],
+        nonce: [u8; PAIRING_NONCE_SIZE],
+    ) -> &[u8; PAIRING_NONCE_SIZE] {
+        self.host = Some((*host_public, *commitment));
+        self.nonce = nonce;
+        self.crypt = None;
+        &self.nonce
+    }
+
+    /// Check the nonce revealed by the modem, returning the pairing code to
+    /// show to the user
+    pub fn reveal(&mut self, host_nonce: &[u8; PAIRING_NONCE_SIZE]) -> Result<u32, Error> {
+        let (host_public, commit) = self.host.ok_or(Error::PairingOutOfOrder)?;
+        if commitment(&host_public, &self.public, host_nonce) != commit {
+            return Err(Error::BadCommitment);
+        }
+
+        let transcript = Transcript {
+            device_id: self.device_id,
+            device_public: self.public,
+            host_public,
+            device_nonce: self.nonce,
+            host_nonce: *host_nonce,
+        };
+        let shared = self.secret.diffie_hellman(&PublicKey::from(host_public));
+        self.crypt = Some(transcript.key(shared.as_bytes()));
+
+        Ok(transcript.code())
+    }
+
+    /// Decrypt our assignment, returning it along with the confirmation to
+    /// send back to the modem
+    pub fn open(&self, sealed: &SealedAssignment) -> Result<(Assignment, [u8; CRYPT_SIZE]), Error> {
+        let crypt = self.crypt.as_ref().ok_or(Error::PairingOutOfOrder)?;
+
+        let mut key = sealed.key;
+        let aad = assignment_aad(sealed.pipe, &sealed.base, sealed.prefix, sealed.key_id);
+        crypt.decrypt_in_place_detached(
+            GenericArray::from_slice(&ASSIGN_NONCE),
+            &aad,
+            &mut key,
+            GenericArray::from_slice(&sealed.tag),
+        )?;
+
+        let assignment = Assignment {
+            pipe: sealed.pipe,
+            base: sealed.base,
+            prefix: sealed.prefix,
+            key_id: sealed.key_id,
+            key,
+        };
+        Ok((assignment, confirmation(crypt)?))
+    }
+}
+
+/// Our side of one exchange with the device, from its request
+struct HostExchange {
+    secret: StaticSecret,
+    public: [u8; 32],
+    nonce: [u8; PAIRING_NONCE_SIZE],
+    device_id: u64,
+    device_public: [u8; 32],
+}
+
+/// The modem side of pairing
+#[derive(Default)]
+pub struct HostPairing {
+    // The current exchange, once the device has asked
+    exchange: Option<HostExchange>,
+
+    // The code and assignment key, once the device's nonce is received
+    agreed: Option<(u32, ChaCha20Poly1305)>,
+}
+
+impl HostPairing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The ID of the device being paired, once it has asked
+    pub fn device_id(&self) -> Option<u64> {
+        self.exchange.as_ref().map(|ex| ex.device_id)
+    }
+
+    /// Take the device's request, returning our public key and commitment
+    /// to send back. This starts the exchange over, so `secret` and `nonce`
+    /// MUST be freshly generated from a hardware RNG, for every request.
+    pub fn request(
+        &mut self,
+        device_id: u64,
+        device_public: &[u8; 32],
+        secret: [u8; 32],
+        nonce: [u8; PAIRING_NONCE_SIZE],
+    ) -> ([u8; 32], [u8; 32]) {
+        let secret = StaticSecret::from(secret);
+        let public = *PublicKey::from(&secret).as_bytes();
+
+        self.exchange = Some(HostExchange {
+            secret,
+            public,
+            nonce,
+            device_id,
+            device_public: *device_public,
+        });
+        self.agreed = None;
+        (public, commitment(&public, device_public, &nonce))
+    }
+
+    /// Take the device's nonce, returning our nonce to reveal. Our nonce is
+    /// only revealed once: the device has to send a new request to try
+    /// another nonce.
+    pub fn reveal(
+        &mut self,
+        device_nonce: &[u8; PAIRING_NONCE_SIZE],
+    ) -> Result<&[u8; PAIRING_NONCE_SIZE], Error> {
+        let ex = self.exchange.as_ref().ok_or(Error::PairingOutOfOrder)?;
+        if self.agreed.is_some() {
+            return Err(Error::PairingOutOfOrder);
+        }
+
+        let transcript = Transcript {
+            device_id: ex.device_id,
+            device_public: ex.device_public,
+            host_public: ex.public,
+            device_nonce: *device_nonce,
+            host_nonce: ex.nonce,
+        };
+        let shared = ex.secret.diffie_hellman(&PublicKey::from(ex.device_public));
+        self.agreed = Some((transcript.code(), transcript.key(shared.as_bytes())));
+
+        Ok(&ex.nonce)
+    }
+
+    /// Encrypt the device's assignment, if `code` matches the one the device
+    /// is showing
+    pub fn seal(&self, code: u32, assignment: &Assignment) -> Result<SealedAssignment, Error> {
+        let (expected, ref crypt) = *self.agreed.as_ref().ok_or(Error::PairingOutOfOrder)?;
+        if code != expected {
+            return Err(Error::WrongPairingCode);
+        }
+
+        let mut key = assignment.key;
+        let aad = assignment_aad(
+            assignment.pipe,
+            &assignment.base,
+            assignment.prefix,
+            assignment.key_id,
+        );
+        let tag = crypt.encrypt_in_place_detached(
+            GenericArray::from_slice(&ASSIGN_NONCE),
+            &aad,
+            &mut key,
+        )?;
+
+        let mut sealed = SealedAssignment {
+            pipe: assignment.pipe,
+            base: assignment.base,
+            prefix: assignment.prefix,
+            key_id: assignment.key_id,
+            key,
+            tag: [0u8; CRYPT_SIZE],
+        };
+        sealed.tag.copy_from_slice(&tag);
+        Ok(sealed)
+    }
+
+    /// Check the device's confirmation that it received its assignment
+    pub fn finish(&self, confirm: &[u8; CRYPT_SIZE]) -> Result<(), Error> {
+        let (_, ref crypt) = *self.agreed.as_ref().ok_or(Error::PairingOutOfOrder)?;
+
+        // Checking the tag by decrypting compares it in constant time
+        crypt.decrypt_in_place_detached(
+            GenericArray::from_slice(&CONFIRM_NONCE),
+            b"paired",
+            &mut [],
+            GenericArray::from_slice(confirm),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn assignment() -> Assignment {
+    Assignment {
+        pipe: 3,
+        base: [0xC2; 4],
+        prefix: 0xC3,
+        key_id: 0x11,
+        key: [0x55; 32],
+    }
+}
+
+#[test]
+fn pairing_in_memory() {
+    let mut device = DevicePairing::new(0x0123_4567_89AB_CDEF, [0x22; 32]);
+    let mut host = HostPairing::new();
+
+    let (host_public, commit) = host.request(
+        device.device_id(),
+        device.public_key(),
+        [0x44; 32],
+        [0x55; 16],
+    );
+    assert_eq!(host.device_id(), Some(0x0123_4567_89AB_CDEF));
+
+    let device_nonce = *device.commit(&host_public, &commit, [0x33; 16]);
+    let host_nonce = *host.reveal(&device_nonce).unwrap();
+    let code = device.reveal(&host_nonce).unwrap();
+    assert!(code < CODE_RANGE);
+
+    // The user enters the code shown by the device
+    let sealed = host.seal(code, &assignment()).unwrap();
+    assert_ne!(sealed.key, assignment().key);
+
+    let (opened, confirm) = device.open(&sealed).unwrap();
+    assert_eq!(opened, assignment());
+    host.finish(&confirm).unwrap();
+}
+
+#[test]
+fn pairing_rejects_tampering() {
+    let mut device = DevicePairing::new(7, [0x22; 32]);
+    let mut host = HostPairing::new();
+
+    // Nothing can happen out of order
+    assert!(matches!(
+        device.reveal(&[0; 16]),
+        Err(Error::PairingOutOfOrder)
+    ));
+    assert!(matches!(
+        host.seal(0, &assignment()),
+        Err(Error::PairingOutOfOrder)
+    ));
+
+    let (host_public, commit) = host.request(7, device.public_key(), [0x44; 32], [0x55; 16]);
+    let device_nonce = *device.commit(&host_public, &commit, [0x33; 16]);
+    let host_nonce = *host.reveal(&device_nonce).unwrap();
+
+    // The modem can't change its nonce after committing to it
+    let mut other_nonce = host_nonce;
+    other_nonce[0] ^= 1;
+    assert!(matches!(
+        device.reveal(&other_nonce),
+        Err(Error::BadCommitment)
+    ));
+
+    let code = device.reveal(&host_nonce).unwrap();
+
+    // The assignment is only handed out with the right code
+    let wrong = (code + 1) % CODE_RANGE;
+    assert!(matches!(
+        host.seal(wrong, &assignment()),
+        Err(Error::WrongPairingCode)
+    ));
+
+    // Moving the device to another pipe fails
+    let mut sealed = host.seal(code, &assignment()).unwrap();
+    sealed.pipe = 4;
+    assert!(device.open(&sealed).is_err());
+    assert!(host.finish(&[0; CRYPT_SIZE]).is_err());
+}
+
+#[test]
+fn pairing_detects_man_in_the_middle() {
+    let mut device = DevicePairing::new(7, [0x22; 32]);
+    let mut host = HostPairing::new();
+
+    // Someone in the middle pairs with both sides, using their own keys
+    let mut fake_host = HostPairing::new();
+    let mut fake_device = DevicePairing::new(7, [0x88; 32]);
+
+    let (host_public, commit) = fake_host.request(7, device.public_key(), [0x66; 32], [0x77; 16]);
+    let device_nonce = *device.commit(&host_public, &commit, [0x33; 16]);
+    let host_nonce = *fake_host.reveal(&device_nonce).unwrap();
+    let device_code = device.reveal(&host_nonce).unwrap();
+
+    let (host_public, commit) = host.request(7, fake_device.public_key(), [0x44; 32], [0x55; 16]);
+    let device_nonce = *fake_device.commit(&host_public, &commit, [0x99; 16]);
+    let host_nonce = *host.reveal(&device_nonce).unwrap();
+    fake_device.reveal(&host_nonce).unwrap();
+
+    // The code shown by the real device isn't accepted by the real modem
+    assert!(matches!(
+        host.seal(device_code, &assignment()),
+        Err(Error::WrongPairingCode)
+    ));
+}
+
+#[test]
+fn pairing_restarts_with_fresh_nonces() {
+    let mut device = DevicePairing::new(7, [0x22; 32]);
+    let mut host = HostPairing::new();
+
+    let (host_public, commit) = host.request(7, device.public_key(), [0x44; 32], [0x55; 16]);
+    let device_nonce = *device.commit(&host_public, &commit, [0x33; 16]);
+    let host_nonce = *host.reveal(&device_nonce).unwrap();
+
+    // Once its nonce is out, the modem won't reveal it again for another
+    // nonce from the device
+    assert!(matches!(
+        host.reveal(&[0x34; 16]),
+        Err(Error::PairingOutOfOrder)
+    ));
+
+    // The device asks again, and both sides start over
+    let (new_public, new_commit) = host.request(7, device.public_key(), [0x46; 32], [0x57; 16]);
+    assert_ne!(new_public, host_public);
+    assert_ne!(new_commit, commit);
+
+    let new_device_nonce = *device.commit(&new_public, &new_commit, [0x35; 16]);
+    assert_ne!(new_device_nonce, device_nonce);
+    let new_host_nonce = *host.reveal(&new_device_nonce).unwrap();
+    assert_ne!(new_host_nonce, host_nonce);
+
+    // The old nonce doesn't match the new commitment
+    assert!(matches!(
+        device.reveal(&host_nonce),
+        Err(Error::BadCommitment)
+    ));
+
+    let code = device.reveal(&new_host_nonce).unwrap();
+    let sealed = host.seal(code, &assignment()).unwrap();
+    let (_, confirm) = device.open(&sealed).unwrap();
+    host.finish(&confirm).unwrap();
+}
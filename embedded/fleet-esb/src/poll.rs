@@ -0,0 +1,119 @@
+//! Scheduling polls of the PRX
+//!
+//! A PRX can only send to a PTX as part of the ACK to one of its frames, so
+//! a PTX with nothing to send has to poll the PRX regularly. Polling quickly
+//! keeps the latency down, but keeps the radio busy.
+//!
+//! `PollScheduler` polls quickly while messages are flowing, or while the
+//! PRX says it has more frames waiting, and backs off towards a slow interval
+//! when the link is idle. In between polls, the radio is idle, and the device
+//! can sleep.
+
+use core::cmp::{max, min};
+
+#[derive(Debug, Clone, Copy)]
+pub struct PollScheduler {
+    fast: u32,
+    slow: u32,
+    interval: u32,
+    pending: bool,
+}
+
+impl PollScheduler {
+    /// Poll every `fast` ticks while messages are flowing, backing off
+    /// to every `slow` ticks when idle
+    pub const fn new(fast: u32, slow: u32) -> Self {
+        Self {
+            fast,
+            slow,
+            interval: fast,
+            pending: false,
+        }
+    }
+
+    /// The current poll interval, in ticks
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    /// Should we poll, after `idle_ticks` ticks without sending anything?
+    pub fn is_due(&self, idle_ticks: u32) -> bool {
+        idle_ticks >= self.interval
+    }
+
+    /// The number of ticks until the next poll, after `idle_ticks` ticks
+    /// without sending anything
+    pub fn ticks_until_due(&self, idle_ticks: u32) -> u32 {
+        self.interval.saturating_sub(idle_ticks)
+    }
+
+    /// Record that a message was sent. A response is likely, so poll quickly.
+    pub fn sent(&mut self) {
+        self.interval = self.fast;
+    }
+
+    /// Record a frame from the PRX. `more_pending` is set if the PRX has
+    /// more frames waiting for us.
+    pub fn received(&mut self, more_pending: bool) {
+        self.interval = self.fast;
+        self.pending = more_pending;
+    }
+
+    /// Record that a poll was sent. Unless the PRX has more frames waiting,
+    /// the next poll is a little later.
+    pub fn polled(&mut self) {
+        if self.pending {
+            self.pending = false;
+            return;
+        }
+
+        let next = max(self.interval.saturating_mul(2), 1);
+        self.interval = max(min(next, self.slow), self.fast);
+    }
+}
+
+#[test]
+fn poll_backs_off_when_idle() {
+    let mut poll = PollScheduler::new(10, 100);
+    assert!(!poll.is_due(9));
+    assert!(poll.is_due(10));
+    assert_eq!(poll.ticks_until_due(4), 6);
+
+    let mut intervals = [0; 6];
+    for i in intervals.iter_mut() {
+        poll.polled();
+        *i = poll.interval();
+    }
+    assert_eq!(intervals, [20, 40, 80, 100, 100, 100]);
+
+    // Traffic in either direction speeds polling back up
+    poll.received(false);
+    assert_eq!(poll.interval(), 10);
+    poll.polled();
+    poll.polled();
+    assert_eq!(poll.interval(), 40);
+    poll.sent();
+    assert_eq!(poll.interval(), 10);
+}
+
+#[test]
+fn poll_stays_fast_while_pending() {
+    let mut poll = PollScheduler::new(10, 100);
+    poll.polled();
+    poll.polled();
+
+    poll.received(true);
+    assert_eq!(poll.interval(), 10);
+
+    // The PRX may need one more poll before the next frame is ready
+    poll.polled();
+    assert_eq!(poll.interval(), 10);
+    poll.polled();
+    assert_eq!(poll.interval(), 20);
+
+    // A zero interval still backs off
+    let mut poll = PollScheduler::new(0, 5);
+    poll.polled();
+    poll.polled();
+    assert_eq!(poll.interval(), 2);
+}
@@ -1,211 +1,401 @@
-use esb::payload::PayloadR;
-use esb::{ArrayLength, EsbApp, EsbHeader};
+use core::cmp::max;
 
-use serde::{
-    de::{Deserialize, DeserializeOwned},
-    Serialize,
-};
-
-use chacha20poly1305::aead::{generic_array::GenericArray, Aead, Buffer, NewAead};
-use chacha20poly1305::ChaCha8Poly1305; // Or `XChaCha20Poly1305`
+use serde::Serialize;
 
-use postcard::{from_bytes, to_slice};
+use chacha20poly1305::ChaCha8Poly1305;
 
 use crate::{
-    nonce::FleetNonce, BorrowRxMessage, Error, LilBuf, MessageMetadata, RxMessage, MIN_CRYPT_SIZE,
-    NONCE_SIZE,
+    batch::Batch,
+    fragment::{serialize, Reassembler},
+    nonce::{FleetNonce, NonceWindow, Sender},
+    queue::{OutgoingQueue, Priority, MAX_QUEUED_SIZE, QUEUE_SLOTS},
+    radio::{FleetRadio, Role},
+    session::Session,
+    transport::Transport,
+    Entropy, Error, FleetCipher,
 };
 
-pub struct GrantWrap<N>
-where
-    N: ArrayLength<u8>,
-{
-    // TODO
-    pub fgr: PayloadR<N>,
-}
+pub use crate::GrantWrap;
 
-impl<N> GrantWrap<N>
-where
-    N: ArrayLength<u8>,
-{
-    pub fn view_with<'a: 'de, 'de, T, F, R>(&'a mut self, fun: F) -> Result<R, Error>
-    where
-        T: 'de + Deserialize<'de>,
-        F: FnOnce(BorrowRxMessage<'de, T>) -> R,
-        R: 'static,
-    {
-        match process_rx_frame::<T, N>(&mut self.fgr) {
-            Ok(msg) => Ok(fun(msg)),
-            Err(e) => Err(e),
-        }
-    }
-}
+pub use crate::NUM_PIPES;
 
-fn process_rx_frame<'de, T: 'de + Deserialize<'de>, N>(
-    frame: &'de mut PayloadR<N>,
-) -> Result<BorrowRxMessage<'de, T>, Error>
-where
-    N: ArrayLength<u8>,
-{
-    let pipe = frame.pipe();
-    let len = frame.payload_len();
-    let payload_len = len - NONCE_SIZE;
-
-    from_bytes(&frame[..payload_len])
-        .map(|pkt| BorrowRxMessage {
-            msg: pkt,
-            meta: MessageMetadata { pipe },
-            marker: core::marker::PhantomData,
-        })
-        .map_err(|e| e.into())
+/// A PRX radio, which answers the PTX devices on up to eight pipes.
+/// Outgoing messages are sent with the next ACK on their pipe.
+pub type FleetRadioPrx<Radio, Cipher = ChaCha8Poly1305> = FleetRadio<Radio, Prx, Cipher>;
+
+/// Nonce tracking for a single pipe, and the device on the other
+/// end of it
+#[derive(Debug, Clone, Copy)]
+struct PipeNonces {
+    rx_window: NonceWindow,
+
+    // Our own message count for frames sent on this pipe. Our frames echo
+    // the ticks of the device, so the count is all that keeps our nonces
+    // apart. See `Prx::new`.
+    tx_count: u32,
 }
 
-pub struct FleetRadioPrx<OutgoingLen, IncomingLen>
-where
-    OutgoingLen: ArrayLength<u8>,
-    IncomingLen: ArrayLength<u8>,
-{
-    app: EsbApp<OutgoingLen, IncomingLen>,
-    crypt: ChaCha8Poly1305,
+/// The part of the message count range used by each pipe
+const PIPE_COUNT_SPAN: u32 = (u32::MAX / NUM_PIPES as u32) + 1;
+
+/// The PRX role. Each pipe is tracked separately, and has its own session.
+pub struct Prx {
+    pipes: [PipeNonces; NUM_PIPES],
+
+    // Fragmented messages are timed out using the ticks of the sending
+    // device, as the PRX has no clock of its own
+    reassembly: [Reassembler; NUM_PIPES],
+
+    queue: OutgoingQueue,
 
-    last_rx_tick: u32,
-    last_rx_count: u32,
+    // The number of frames from the queue handed to the radio for each
+    // pipe, that haven't been picked up by the device yet
+    in_flight: [usize; NUM_PIPES],
 }
 
-impl<OutgoingLen, IncomingLen> FleetRadioPrx<OutgoingLen, IncomingLen>
-where
-    OutgoingLen: 'static + ArrayLength<u8>,
-    IncomingLen: 'static + ArrayLength<u8>,
-{
-    pub fn new(app: EsbApp<OutgoingLen, IncomingLen>, key: &[u8; 32]) -> Self {
-        let ga_key = GenericArray::clone_from_slice(key);
-        let crypt = ChaCha8Poly1305::new(ga_key);
+impl Prx {
+    /// All pipes share the master key, and we have no clock to tell our
+    /// restarts apart. So the message counts start at a random point,
+    /// picked on every boot, and each pipe counts through its own part of
+    /// the range from there. No two pipes send the same nonce, short of one
+    /// pipe sending `PIPE_COUNT_SPAN` frames in a single boot, and a
+    /// restarted PRX is very unlikely to repeat one from before the restart.
+    fn new<R: Entropy>(tick_window: u32, rng: &mut R) -> Self {
+        let start = rng.random_u32();
+        let mut pipes = [PipeNonces {
+            rx_window: NonceWindow::new(tick_window),
+            tx_count: 0,
+        }; NUM_PIPES];
+        for (i, p) in pipes.iter_mut().enumerate() {
+            p.tx_count = start.wrapping_add(PIPE_COUNT_SPAN.wrapping_mul(i as u32));
+        }
 
         Self {
-            app,
-            crypt,
+            pipes,
+            reassembly: [
+                Reassembler::new(tick_window),
+                Reassembler::new(tick_window),
+                Reassembler::new(tick_window),
+                Reassembler::new(tick_window),
+                Reassembler::new(tick_window),
+                Reassembler::new(tick_window),
+                Reassembler::new(tick_window),
+                Reassembler::new(tick_window),
+            ],
+            queue: OutgoingQueue::new(),
+            in_flight: [0; NUM_PIPES],
+        }
+    }
 
-            last_rx_count: 0,
-            last_rx_tick: 0,
+    /// Forget the nonces and partial messages of a device that restarted
+    fn reset(&mut self, pipe: u8) {
+        let pipe = usize::from(pipe);
+        if let Some(p) = self.pipes.get_mut(pipe) {
+            p.rx_window.reset();
+        }
+        if let Some(n) = self.in_flight.get_mut(pipe) {
+            *n = 0;
+        }
+        if let Some(r) = self.reassembly.get_mut(pipe) {
+            r.reset();
         }
     }
+}
 
-    pub fn send<T: Serialize>(&mut self, msg: &T, pipe: u8) -> Result<(), Error> {
-        let header = EsbHeader::build()
-            .max_payload(self.app.maximum_payload_size() as u8)
-            .pid(0) // todo
-            .pipe(pipe)
-            .no_ack(false)
-            .check()?;
+impl Role for Prx {
+    const START_TX: bool = false;
+    const FOLLOW_EPOCH: bool = false;
 
-        let mut grant = self.app.grant_packet(header)?;
+    fn session_slot(pipe: u8) -> usize {
+        usize::from(pipe)
+    }
 
-        // serialize directly to buffer
-        let used = to_slice(msg, &mut grant)?.len();
+    fn tx_nonce(&mut self, pipe: u8) -> Result<FleetNonce, Error> {
+        let state = self
+            .pipes
+            .get_mut(usize::from(pipe))
+            .ok_or(Error::InvalidPipe)?;
+
+        // Echo back the newest tick from the PTX, which allows it to check
+        // the freshness of our response against its own clock
+        state.tx_count = state.tx_count.wrapping_add(1);
+        Ok(FleetNonce::from_prx(
+            state.rx_window.last_tick().unwrap_or(0),
+            state.tx_count,
+        ))
+    }
 
-        let nonce_bytes = FleetNonce {
-            tick: self.last_rx_tick,
-            msg_count: self.last_rx_count,
+    fn check_rx(&self, pipe: u8, nonce: &FleetNonce) -> Result<(), Error> {
+        // Our own frames, reflected back at us
+        if nonce.sender != Sender::Ptx {
+            return Err(Error::InvalidNonce);
         }
-        .to_bytes();
 
-        // Create nonce
-        let ga_nonce = GenericArray::from_slice(&nonce_bytes);
+        self.pipes
+            .get(usize::from(pipe))
+            .ok_or(Error::InvalidPipe)?
+            .rx_window
+            .check(nonce, None)
+    }
 
-        let mut buf = LilBuf {
-            buf: &mut grant,
-            used: used as u8,
-        };
+    // A restarted device has picked a new random tick offset and message
+    // count, which may well be behind the old ones
+    const RESYNC: bool = true;
 
-        // Encrypt
-        self.crypt.encrypt_in_place(&ga_nonce, b"", &mut buf)?;
+    fn resync_rx(&mut self, pipe: u8, nonce: &FleetNonce) -> bool {
+        if nonce.sender != Sender::Ptx || usize::from(pipe) >= NUM_PIPES {
+            return false;
+        }
 
-        // Add nonce to payload
-        buf.extend_from_slice(&ga_nonce)?;
+        self.reset(pipe);
+        true
+    }
 
-        // Extract the bytes used of the LilBuf
-        let used = buf.used.into();
+    fn accept_rx(&mut self, pipe: u8, nonce: &FleetNonce) {
+        if let Some(p) = self.pipes.get_mut(usize::from(pipe)) {
+            p.rx_window.accept(nonce);
+        }
+    }
 
-        // Commit payload
-        grant.commit(used);
+    fn reassembler(&mut self, pipe: u8) -> Option<&mut Reassembler> {
+        self.reassembly.get_mut(usize::from(pipe))
+    }
 
-        Ok(())
+    fn rx_tick(&self, nonce: &FleetNonce) -> u32 {
+        nonce.tick
+    }
+
+    fn has_queued(&self, pipe: u8) -> bool {
+        self.queue.len(pipe) != 0
     }
 
-    pub fn receive<T: 'static + DeserializeOwned>(
+    fn frame_read(&mut self, pipe: u8) {
+        // Every frame from the device was answered with an ACK, carrying
+        // one of our frames if any were waiting
+        if let Some(n) = self.in_flight.get_mut(usize::from(pipe)) {
+            *n = n.saturating_sub(1);
+        }
+    }
+}
+
+impl<Radio, Cipher> FleetRadio<Radio, Prx, Cipher>
+where
+    Radio: Transport,
+    Cipher: FleetCipher,
+{
+    /// Create a new PRX radio.
+    ///
+    /// `network_id` must match the PTX devices, and is authenticated as part
+    /// of every frame. `tick_window` is the number of ticks (of the PTX devices'
+    /// timers) that an incoming frame may lag behind the newest frame received
+    /// on the same pipe. Without a `key`, all traffic is refused until
+    /// `set_master_key()` is called.
+    ///
+    /// `rng` picks where our message counts start, which must differ on
+    /// every boot.
+    pub fn new<R: Entropy>(
+        app: Radio,
+        key: Option<&[u8; 32]>,
+        network_id: u32,
+        tick_window: u32,
+        rng: &mut R,
+    ) -> Self {
+        let role = Prx::new(tick_window, rng);
+
+        Self::from_role(app, key, network_id, role)
+    }
+
+    /// Switch the given pipe to a newly established session key. See the
+    /// `session` module for how to establish a session.
+    ///
+    /// Until a session is established, or after it is ended, the pipe uses
+    /// the master key.
+    pub fn start_session(&mut self, pipe: u8, session: Session) {
+        self.set_session(pipe, Some(session));
+    }
+
+    /// Return the given pipe to the master key
+    pub fn end_session(&mut self, pipe: u8) {
+        self.set_session(pipe, None);
+    }
+
+    /// The current session for the given pipe, if any
+    pub fn session(&self, pipe: u8) -> Option<&Session> {
+        self.get_session(pipe)
+    }
+
+    /// Talk to the device on the given pipe with its own key for the given
+    /// epoch, see `fleet_keys::derive`, instead of the master key. `None`
+    /// removes the key of that epoch. Once a pipe has no device keys left,
+    /// it returns to the master key.
+    pub fn set_device_key(&mut self, pipe: u8, epoch: u8, key: Option<&[u8; 32]>) {
+        self.set_pipe_key(pipe, epoch, key);
+    }
+
+    /// Forget the nonce state of the given pipe. The next valid frame
+    /// received on this pipe will be accepted as the new baseline. Any
+    /// partially received message is dropped.
+    ///
+    /// This should be used when the device on the other end of the pipe
+    /// is known to have restarted, as it will have picked a new random
+    /// tick offset and message count. A device that restarts a session
+    /// with `send_handshake()` does this for us.
+    pub fn reset_pipe(&mut self, pipe: u8) {
+        self.role.reset(pipe);
+        self.reset_pipe_state(pipe);
+    }
+
+    /// Queue a message for the given pipe, to be sent by `service()`.
+    ///
+    /// Unlike `send()`, which hands the message to the radio straight away,
+    /// queued messages are only handed over once the device has picked up
+    /// the previous one. More urgent messages are sent first, and messages
+    /// still queued `ttl` ticks after `now` are dropped. Ticks may come from
+    /// any timer, as long as `service()` is given the same one.
+    ///
+    /// If the queue is full, a less urgent message may be dropped to make
+    /// room. This is counted in `RadioStats::evicted`.
+    pub fn enqueue<T: Serialize>(
         &mut self,
-    ) -> Result<Option<RxMessage<T>>, Error> {
-        let mut with_rgr = match self.receive_with() {
-            Ok(rgr) => rgr,
-            Err(Error::NoData) => return Ok(None),
-            Err(e) => return Err(e),
-        };
-
-        let view = with_rgr.view_with(|msg: BorrowRxMessage<T>| RxMessage {
-            msg: msg.msg,
-            meta: msg.meta,
-        });
-
-        // TODO - Auto Release?
-        with_rgr.fgr.release();
-
-        match view {
-            Ok(msg) => Ok(Some(msg)),
-            Err(e) => Err(e),
+        msg: &T,
+        pipe: u8,
+        priority: Priority,
+        ttl: Option<u32>,
+        now: u32,
+    ) -> Result<(), Error> {
+        let mut scratch = [0u8; MAX_QUEUED_SIZE];
+        let msg = serialize(msg, &mut scratch)?;
+
+        if self.role.queue.push(pipe, msg, priority, ttl, now)? {
+            self.stats.evicted = self.stats.evicted.wrapping_add(1);
         }
+
+        Ok(())
     }
 
-    pub fn just_gimme_frame(&mut self) -> Result<PayloadR<IncomingLen>, Error> {
-        let mut frame = loop {
-            match self.app.read_packet().map(|mut frame| {
-                frame.auto_release(true);
-                frame
-            }) {
-                // No packet ready
-                None => return Err(Error::NoData),
+    /// The number of messages queued for the given pipe
+    pub fn queued(&self, pipe: u8) -> usize {
+        self.role.queue.len(pipe)
+    }
 
-                // Empty ACK, release and get the next packet
-                Some(pkt) if pkt.payload_len() == 0 => {
-                    continue;
-                }
+    /// Drop all messages queued for the given pipe
+    pub fn clear_queue(&mut self, pipe: u8) {
+        self.role.queue.clear(pipe);
+    }
 
-                // We got a potentially good one!
-                Some(pkt) => {
-                    break pkt;
+    /// Drop expired messages, and hand the next queued messages for each
+    /// idle pipe to the radio. Small messages are sent together in a
+    /// single frame, see the `batch` module. This should be called regularly, e.g. after
+    /// every `receive()`.
+    ///
+    /// Returns the number of messages dropped because they expired. If
+    /// sending fails on a pipe, e.g. because the radio is full, its messages
+    /// stay queued and the other pipes are still served, and the first error
+    /// is returned at the end.
+    pub fn service(&mut self, now: u32) -> Result<usize, Error> {
+        let mut expired = 0;
+        self.role.queue.expire(now, |_pipe, _priority| expired += 1);
+        self.stats.expired = self.stats.expired.wrapping_add(expired as u32);
+
+        let mut error = None;
+        for pipe in 0..(NUM_PIPES as u8) {
+            if self.role.in_flight[usize::from(pipe)] != 0 {
+                continue;
+            }
+
+            // Pack as many of the most urgent messages as fit into one frame
+            let frame_size = self.transport().maximum_payload_size();
+            let mut batch = Batch::new();
+            let mut taken = [0usize; QUEUE_SLOTS];
+            let mut count = 0;
+            while let Some((idx, msg)) = self.role.queue.peek_excluding(pipe, &taken[..count]) {
+                if !batch.push(msg, pipe, frame_size) {
+                    break;
+                }
+                taken[count] = idx;
+                count += 1;
+            }
+
+            // If the radio is full, the messages stay queued for next time.
+            // The device is told to keep polling while more messages wait.
+            let more_pending = self.role.queue.len(pipe) > max(count, 1);
+            let sent = match batch.single() {
+                Some(msg) => self.send_bytes(msg, pipe, more_pending),
+                None if count > 1 => self
+                    .send_batch(pipe, batch.body(), more_pending)
+                    .map(|()| 1),
+                None => {
+                    // Too large to batch, so this may be fragmented
+                    let mut scratch = [0u8; MAX_QUEUED_SIZE];
+                    let msg = match self.role.queue.peek(pipe) {
+                        Some((idx, msg)) => {
+                            taken[0] = idx;
+                            count = 1;
+                            scratch[..msg.len()].copy_from_slice(msg);
+                            &scratch[..msg.len()]
+                        }
+                        None => continue,
+                    };
+                    self.send_bytes(msg, pipe, more_pending)
+                }
+            };
+            let frames = match sent {
+                Ok(frames) => frames,
+                Err(e) => {
+                    error = error.or(Some(e));
+                    continue;
                 }
             };
-        };
 
-        // We didn't even get enough bytes for the crypto
-        // header (and a 1 byte payload). Release packet and
-        // return error
-        if frame.payload_len() <= MIN_CRYPT_SIZE {
-            return Err(Error::PacketTooSmol);
+            for &idx in taken[..count].iter() {
+                self.role.queue.remove(idx);
+            }
+            self.role.in_flight[usize::from(pipe)] = frames;
         }
 
-        let len = frame.payload_len();
-        let payload_len = len - NONCE_SIZE;
-        let (payload, nonce_bytes) = frame.split_at_mut(payload_len);
-        let fleet_nonce = FleetNonce::try_from_bytes(nonce_bytes)?;
-
-        // TODO(AJM): PRX should probably do some kind of nonce validation. For now,
-        // just update the tracking variables
-        self.last_rx_tick = fleet_nonce.tick;
-        self.last_rx_count = fleet_nonce.msg_count;
+        match error {
+            Some(e) => Err(e),
+            None => Ok(expired),
+        }
+    }
+}
 
-        let ga_nonce = GenericArray::from_slice(nonce_bytes);
-        let mut buf = LilBuf {
-            used: payload.len() as u8,
-            buf: payload,
-        };
+#[test]
+fn prx_nonces_never_repeat() {
+    struct FixedRng(u32);
 
-        self.crypt.decrypt_in_place(&ga_nonce, b"", &mut buf)?;
+    impl Entropy for FixedRng {
+        fn random_u32(&mut self) -> u32 {
+            self.0
+        }
+    }
 
-        Ok(frame)
+    const PER_PIPE: usize = 4;
+    const PER_BOOT: usize = NUM_PIPES * PER_PIPE;
+
+    // Two boots of the same modem, answering devices that haven't sent
+    // anything yet, so every frame echoes the same tick
+    let mut nonces = [FleetNonce::new(0, 0); 2 * PER_BOOT];
+    for (boot, &seed) in [0xFFFF_FFFE, 0x1234_5678].iter().enumerate() {
+        let mut prx = Prx::new(1000, &mut FixedRng(seed));
+        for pipe in 0..NUM_PIPES {
+            for i in 0..PER_PIPE {
+                let idx = boot * PER_BOOT + pipe * PER_PIPE + i;
+                nonces[idx] = prx.tx_nonce(pipe as u8).unwrap();
+            }
+        }
     }
 
-    pub fn receive_with(&mut self) -> Result<GrantWrap<IncomingLen>, Error> {
-        let frame = self.just_gimme_frame()?;
-        Ok(GrantWrap { fgr: frame })
+    let suite = crate::CipherSuite::ChaCha8Poly1305;
+    for (i, a) in nonces.iter().enumerate() {
+        assert_eq!(a.sender(), Sender::Prx);
+
+        // The devices' own nonces never match ours either
+        let ptx = FleetNonce::new(a.tick(), a.msg_count());
+        assert_ne!(a.to_bytes(suite), ptx.to_bytes(suite));
+
+        for b in nonces[i + 1..].iter() {
+            assert_ne!(a.to_bytes(suite), b.to_bytes(suite));
+        }
     }
 }
@@ -1,291 +1,199 @@
-pub use esb::payload::PayloadR;
-use esb::{ArrayLength, EsbApp, EsbHeader};
-
-use serde::{de::DeserializeOwned, Serialize};
-
-use chacha20poly1305::aead::{generic_array::GenericArray, Aead, Buffer, NewAead};
-use chacha20poly1305::ChaCha8Poly1305; // Or `XChaCha20Poly1305`
-
-use crate::hal::Rng;
-
-use postcard::{from_bytes, to_slice};
+use chacha20poly1305::ChaCha8Poly1305;
 
 use crate::{
-    nonce::FleetNonce, BorrowRxMessage, Error, LilBuf, MessageMetadata, RollingTimer, RxMessage,
-    MIN_CRYPT_SIZE, NONCE_SIZE,
+    fragment::{FragmentHeader, Reassembler},
+    nonce::{FleetNonce, NonceWindow, Sender},
+    poll::PollScheduler,
+    radio::{FleetRadio, Role},
+    session::Session,
+    transport::Transport,
+    Entropy, Error, FleetCipher, RollingTimer,
 };
 
-use serde::de::Deserialize;
+pub use crate::GrantWrap;
 
-pub struct FleetRadioPtx<OutgoingLen, IncomingLen, Tick>
-where
-    OutgoingLen: ArrayLength<u8>,
-    IncomingLen: ArrayLength<u8>,
-    Tick: RollingTimer,
-{
-    app: EsbApp<OutgoingLen, IncomingLen>,
-    crypt: ChaCha8Poly1305,
+/// A PTX radio, which initiates every exchange with the PRX
+pub type FleetRadioPtx<Radio, Tick, Cipher = ChaCha8Poly1305> =
+    FleetRadio<Radio, Ptx<Tick>, Cipher>;
+
+/// The PTX role. Nonces are built from our own (offset) clock, and the PRX
+/// echoes our ticks back, so we can check them against our own clock.
+pub struct Ptx<Tick: RollingTimer> {
     tick: Tick,
 
-    tick_window: u32,
     tick_offset: u32,
     last_tx_tick: u32,
     last_rx_tick: u32,
 
     msg_count: u32,
-    last_rx_count: u32,
-}
+    rx_window: NonceWindow,
 
-impl<OutgoingLen, IncomingLen, Tick> FleetRadioPtx<OutgoingLen, IncomingLen, Tick>
-where
-    OutgoingLen: ArrayLength<u8>,
-    IncomingLen: ArrayLength<u8>,
-    Tick: RollingTimer,
-{
-    pub fn new(
-        app: EsbApp<OutgoingLen, IncomingLen>,
-        key: &[u8; 32],
-        tick: Tick,
-        tick_window: u32,
-        rng: &mut Rng,
-    ) -> Self {
-        let ga_key = GenericArray::clone_from_slice(key);
-        let crypt = ChaCha8Poly1305::new(ga_key);
-
-        let msg_count = rng.random_u32();
-        let tick_offset = rng.random_u32();
-
-        Self {
-            app,
-            tick_window,
-            tick,
-            crypt,
+    reassembly: Reassembler,
 
-            tick_offset,
-            msg_count,
+    poll: PollScheduler,
+}
 
-            last_rx_count: msg_count,
-            last_rx_tick: tick_offset,
-            last_tx_tick: tick_offset,
-        }
+impl<Tick: RollingTimer> Ptx<Tick> {
+    fn current_tick(&self) -> u32 {
+        self.tick.get_current_tick().wrapping_add(self.tick_offset)
     }
+}
 
-    pub fn send<T: Serialize>(&mut self, msg: &T, pipe: u8) -> Result<(), Error> {
-        let header = EsbHeader::build()
-            .max_payload(self.app.maximum_payload_size() as u8)
-            .pid(0) // todo
-            .pipe(pipe)
-            .no_ack(false)
-            .check()?;
-
-        let mut grant = self.app.grant_packet(header)?;
+impl<Tick: RollingTimer> Role for Ptx<Tick> {
+    const START_TX: bool = true;
+    const FOLLOW_EPOCH: bool = true;
 
-        // serialize directly to buffer
-        let used = to_slice(msg, &mut grant)?.len();
+    fn session_slot(_pipe: u8) -> usize {
+        0
+    }
 
-        // Update nonce vars
+    fn tx_nonce(&mut self, _pipe: u8) -> Result<FleetNonce, Error> {
         self.msg_count = self.msg_count.wrapping_add(1);
         let tick = self.current_tick();
-
-        let nonce_bytes = FleetNonce {
-            tick,
-            msg_count: self.msg_count,
-        }
-        .to_bytes();
-
-        // Create nonce
-        let ga_nonce = GenericArray::from_slice(&nonce_bytes);
-
-        let mut buf = LilBuf {
-            buf: &mut grant,
-            used: used as u8,
-        };
-
-        // Encrypt
-        self.crypt.encrypt_in_place(&ga_nonce, b"", &mut buf)?;
-
-        // Add nonce to payload
-        buf.extend_from_slice(&ga_nonce)?;
-
-        // Extract the bytes used of the LilBuf
-        let used = buf.used.into();
-
-        // Commit payload
-        grant.commit(used);
-
-        // Update TX
         self.last_tx_tick = tick;
 
-        // Kick the radio
-        self.app.start_tx();
-
-        Ok(())
+        Ok(FleetNonce::new(tick, self.msg_count))
     }
 
-    pub fn current_tick(&self) -> u32 {
-        self.tick.get_current_tick().wrapping_add(self.tick_offset)
-    }
+    fn check_rx(&self, _pipe: u8, nonce: &FleetNonce) -> Result<(), Error> {
+        // Our own frames, reflected back at us
+        if nonce.sender != Sender::Prx {
+            return Err(Error::InvalidNonce);
+        }
 
-    pub fn ticks_since_last_tx(&self) -> u32 {
-        self.current_tick().wrapping_sub(self.last_tx_tick)
+        self.rx_window.check(nonce, Some(self.current_tick()))
     }
 
-    pub fn ticks_since_last_rx(&self) -> u32 {
-        self.current_tick().wrapping_sub(self.last_rx_tick)
+    fn accept_rx(&mut self, _pipe: u8, nonce: &FleetNonce) {
+        self.rx_window.accept(nonce);
+        self.last_rx_tick = nonce.tick;
     }
 
-    pub fn receive<T: 'static + DeserializeOwned>(
-        &mut self,
-    ) -> Result<Option<RxMessage<T>>, Error> {
-        let mut with_rgr = match self.receive_with() {
-            Ok(rgr) => rgr,
-            Err(Error::NoData) => return Ok(None),
-            Err(e) => return Err(e),
-        };
-
-        let view = with_rgr.view_with(|msg: BorrowRxMessage<T>| RxMessage {
-            msg: msg.msg,
-            meta: msg.meta,
-        });
-
-        // TODO - Auto Release?
-        with_rgr.fgr.release();
-
-        match view {
-            Ok(msg) => Ok(Some(msg)),
-            Err(e) => Err(e),
-        }
+    fn reassembler(&mut self, _pipe: u8) -> Option<&mut Reassembler> {
+        Some(&mut self.reassembly)
     }
 
-    pub fn just_gimme_frame(&mut self) -> Result<PayloadR<IncomingLen>, Error> {
-        let mut frame = loop {
-            let pkt = self.app.read_packet().map(|mut frame| {
-                frame.auto_release(true);
-                frame
-            });
-
-            match pkt {
-                // No packet ready
-                None => return Err(Error::NoData),
-
-                // Empty ACK, release and get the next packet
-                Some(pkt) if pkt.payload_len() == 0 => {
-                    continue;
-                }
-
-                // We got a potentially good one!
-                Some(pkt) => {
-                    break pkt;
-                }
-            };
-        };
+    fn rx_tick(&self, _nonce: &FleetNonce) -> u32 {
+        self.current_tick()
+    }
 
-        if frame.payload_len() <= MIN_CRYPT_SIZE {
-            return Err(Error::PacketTooSmol);
+    fn received(&mut self, _pipe: u8, header: &FragmentHeader, more_pending: bool) {
+        if *header != FragmentHeader::Poll {
+            self.poll.received(more_pending);
         }
+    }
 
-        let len = frame.payload_len();
-        let (payload, nonce_bytes) = frame.split_at_mut(len - NONCE_SIZE);
-        let fleet_nonce = FleetNonce::try_from_bytes(nonce_bytes)?;
+    fn sent_message(&mut self, _pipe: u8) {
+        self.poll.sent();
+    }
+}
 
-        // Nonce check!
-        self.check_nonce_and_update(&fleet_nonce)?;
+impl<Radio, Tick, Cipher> FleetRadio<Radio, Ptx<Tick>, Cipher>
+where
+    Radio: Transport,
+    Tick: RollingTimer,
+    Cipher: FleetCipher,
+{
+    /// Create a new PTX radio.
+    ///
+    /// `network_id` must match the PRX, and is authenticated as part of every frame.
+    /// Without a `key`, all traffic is refused until `set_master_key()` is called.
+    ///
+    /// `poll()` starts out polling every `tick_window / 20` ticks, backing off
+    /// to every `tick_window / 2` ticks. See `set_poll_intervals()`.
+    pub fn new<R: Entropy>(
+        app: Radio,
+        key: Option<&[u8; 32]>,
+        network_id: u32,
+        tick: Tick,
+        tick_window: u32,
+        rng: &mut R,
+    ) -> Self {
+        let msg_count = rng.random_u32();
+        let tick_offset = rng.random_u32();
 
-        let ga_nonce = GenericArray::from_slice(nonce_bytes);
-        let mut buf = LilBuf {
-            used: payload.len() as u8,
-            buf: payload,
+        let role = Ptx {
+            tick,
+            tick_offset,
+            last_tx_tick: tick_offset,
+            last_rx_tick: tick_offset,
+            msg_count,
+            rx_window: NonceWindow::new(tick_window),
+            reassembly: Reassembler::new(tick_window),
+            poll: PollScheduler::new(tick_window / 20, tick_window / 2),
         };
 
-        self.crypt.decrypt_in_place(ga_nonce, b"", &mut buf)?;
+        Self::from_role(app, key, network_id, role)
+    }
 
-        Ok(frame)
+    /// Switch to a newly established session key. See the `session` module
+    /// for how to establish a session.
+    pub fn start_session(&mut self, session: Session) {
+        self.set_session(0, Some(session));
     }
 
-    pub fn receive_with(&mut self) -> Result<GrantWrap<IncomingLen>, Error> {
-        let frame = self.just_gimme_frame()?;
-        Ok(GrantWrap { fgr: frame })
+    /// Return to the master key. While the modem holds a session for us, it
+    /// only accepts handshakes sealed with the master key, so a new session
+    /// is established with `send_handshake()` without ending this one.
+    pub fn end_session(&mut self) {
+        self.set_session(0, None);
     }
 
-    fn check_nonce_and_update(&mut self, nonce: &FleetNonce) -> Result<(), Error> {
-        let cur_tick = self.current_tick();
-        let min_tick = cur_tick.wrapping_sub(self.tick_window);
-
-        // Is the tick >= the last received tick?
-        let last_tick_good = if self.last_rx_tick > cur_tick {
-            // We have rolled. Make sure the tick is between
-            // last and u32::max, OR between zero and current
-            (nonce.tick >= self.last_rx_tick) || (nonce.tick <= cur_tick)
-        } else {
-            (nonce.tick >= self.last_rx_tick) && (nonce.tick <= cur_tick)
-        };
+    /// The current session, if any
+    pub fn session(&self) -> Option<&Session> {
+        self.get_session(0)
+    }
 
-        // Is the tick >= our minimum acceptable staleness?
-        let min_tick_good = if min_tick > cur_tick {
-            // We have rolled. Make sure the tick is between
-            // min and u32::max, OR between zero and current
-            (nonce.tick >= min_tick) || (nonce.tick <= cur_tick)
-        } else {
-            (nonce.tick >= min_tick) && (nonce.tick <= cur_tick)
-        };
+    pub fn current_tick(&self) -> u32 {
+        self.role.current_tick()
+    }
 
-        // Is the count >= the last received count?
-        let count_good = if self.last_rx_count > cur_tick {
-            // We have rolled. Blah Blah
-            (nonce.msg_count >= self.last_rx_count) || (nonce.msg_count <= self.msg_count)
-        } else {
-            (nonce.msg_count >= self.last_rx_count) && (nonce.msg_count <= self.msg_count)
-        };
+    pub fn ticks_since_last_tx(&self) -> u32 {
+        self.current_tick().wrapping_sub(self.role.last_tx_tick)
+    }
 
-        if last_tick_good && min_tick_good && count_good {
-            // Success, update tracking variables
-            self.last_rx_count = nonce.msg_count;
-            self.last_rx_tick = nonce.tick;
-            Ok(())
-        } else {
-            Err(Error::InvalidNonce)
-        }
+    pub fn ticks_since_last_rx(&self) -> u32 {
+        self.current_tick().wrapping_sub(self.role.last_rx_tick)
     }
-}
 
-pub struct GrantWrap<N>
-where
-    N: ArrayLength<u8>,
-{
-    fgr: PayloadR<N>,
-}
+    /// Poll the PRX every `fast` ticks while messages are flowing, backing
+    /// off to every `slow` ticks when the link is idle.
+    ///
+    /// The PRX answers with the tick of our previous frame, so `slow` must be
+    /// well below the tick window given to `new()`, or the answers will be
+    /// rejected as stale.
+    pub fn set_poll_intervals(&mut self, fast: u32, slow: u32) {
+        self.role.poll = PollScheduler::new(fast, slow);
+    }
 
-impl<N> GrantWrap<N>
-where
-    N: ArrayLength<u8>,
-{
-    pub fn view_with<'a: 'de, 'de, T, F, R>(&'a mut self, fun: F) -> Result<R, Error>
-    where
-        T: 'de + Deserialize<'de>,
-        F: FnOnce(BorrowRxMessage<'de, T>) -> R,
-        R: 'static,
-    {
-        match process_rx_frame::<T, N>(&mut self.fgr) {
-            Ok(msg) => Ok(fun(msg)),
-            Err(e) => Err(e),
+    /// Poll the PRX on the given pipe, if nothing has been sent for the
+    /// current poll interval. Returns `true` if a poll was sent.
+    ///
+    /// Batched messages are sent straight away instead, which gives the PRX
+    /// the same chance to answer. Any answer is picked up by the next `receive()`.
+    pub fn poll(&mut self, pipe: u8) -> Result<bool, Error> {
+        if self.batched() != 0 {
+            self.flush()?;
+            return Ok(true);
         }
+
+        if !self.role.poll.is_due(self.ticks_since_last_tx()) {
+            return Ok(false);
+        }
+
+        self.send_poll(pipe)?;
+        self.role.poll.polled();
+        Ok(true)
     }
-}
 
-fn process_rx_frame<'de, T: 'de + Deserialize<'de>, N>(
-    frame: &'de mut PayloadR<N>,
-) -> Result<BorrowRxMessage<'de, T>, Error>
-where
-    N: ArrayLength<u8>,
-{
-    let len = frame.payload_len();
-    let payload_len = len - NONCE_SIZE;
-
-    from_bytes(&frame[..payload_len])
-        .map(|pkt| BorrowRxMessage {
-            msg: pkt,
-            meta: MessageMetadata { pipe: frame.pipe() },
-            marker: core::marker::PhantomData,
-        })
-        .map_err(|e| e.into())
+    /// The number of ticks until the next poll is due. Unless there is
+    /// something to send, the device can sleep until then.
+    pub fn ticks_until_poll(&self) -> u32 {
+        self.role.poll.ticks_until_due(self.ticks_since_last_tx())
+    }
+
+    /// The current poll interval, in ticks
+    pub fn poll_interval(&self) -> u32 {
+        self.role.poll.interval()
+    }
 }
@@ -0,0 +1,335 @@
+//! Outgoing message queue for the PRX
+//!
+//! A PRX can only send to a device as part of the ACK to a frame from that
+//! device. Frames handed to the radio are sent in order, however long the
+//! device takes to poll again, so a command queued while a device is offline
+//! would be delivered long after it was useful.
+//!
+//! Instead, messages are held back in an `OutgoingQueue`, and only handed to
+//! the radio one at a time per pipe. The most urgent message is always sent
+//! first, and messages that outlive their time to live are dropped.
+
+use crate::{Error, NUM_PIPES};
+
+/// The number of messages that can be queued, shared by all pipes
+pub const QUEUE_SLOTS: usize = 8;
+
+/// The largest serialized message that can be queued
+pub const MAX_QUEUED_SIZE: usize = 256;
+
+/// The urgency of a queued message. More urgent messages are sent first,
+/// and may push out less urgent ones when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Regular traffic, such as status requests
+    Routine,
+
+    /// Commands that should be handled promptly
+    Urgent,
+
+    /// Safety messages, such as switching all relays off
+    Safety,
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    pipe: u8,
+    priority: Priority,
+    queued_at: u32,
+    ttl: Option<u32>,
+
+    // Orders messages of the same priority, oldest first
+    seq: u32,
+
+    len: usize,
+    buf: [u8; MAX_QUEUED_SIZE],
+}
+
+impl Slot {
+    fn is_expired(&self, now: u32) -> bool {
+        match self.ttl {
+            Some(ttl) => now.wrapping_sub(self.queued_at) > ttl,
+            None => false,
+        }
+    }
+
+    /// Should this message be sent before the other one?
+    fn is_before(&self, other: &Slot, seq: u32) -> bool {
+        if self.priority != other.priority {
+            return self.priority > other.priority;
+        }
+
+        // Compare ages relative to the next sequence number, so the order
+        // is kept when the counter rolls over
+        seq.wrapping_sub(self.seq) > seq.wrapping_sub(other.seq)
+    }
+}
+
+/// Messages waiting to be sent, for all pipes
+pub struct OutgoingQueue {
+    slots: [Option<Slot>; QUEUE_SLOTS],
+    seq: u32,
+}
+
+impl Default for OutgoingQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OutgoingQueue {
+    pub const fn new() -> Self {
+        Self {
+            slots: [None; QUEUE_SLOTS],
+            seq: 0,
+        }
+    }
+
+    /// The number of messages queued for the given pipe
+    pub fn len(&self, pipe: u8) -> usize {
+        self.slots
+            .iter()
+            .filter_map(|s| s.as_ref())
+            .filter(|s| s.pipe == pipe)
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.iter().all(|s| s.is_none())
+    }
+
+    /// Queue a serialized message for the given pipe. `ttl` is the number of
+    /// ticks after `now` that the message may still be sent, or `None` if
+    /// it never expires.
+    ///
+    /// If the queue is full, the newest of the least urgent messages is
+    /// dropped to make room, as long as it is less urgent than this one.
+    /// Returns `true` if a message was dropped.
+    pub fn push(
+        &mut self,
+        pipe: u8,
+        msg: &[u8],
+        priority: Priority,
+        ttl: Option<u32>,
+        now: u32,
+    ) -> Result<bool, Error> {
+        if usize::from(pipe) >= NUM_PIPES {
+            return Err(Error::InvalidPipe);
+        }
+        if msg.len() > MAX_QUEUED_SIZE {
+            return Err(Error::MessageTooLarge);
+        }
+
+        let mut evicted = false;
+        let idx = match self.slots.iter().position(|s| s.is_none()) {
+            Some(idx) => idx,
+            None => {
+                let idx = self.last_of_lowest().ok_or(Error::QueueFull)?;
+                match self.slots[idx] {
+                    Some(ref s) if s.priority < priority => {}
+                    _ => return Err(Error::QueueFull),
+                }
+                evicted = true;
+                idx
+            }
+        };
+
+        let mut slot = Slot {
+            pipe,
+            priority,
+            queued_at: now,
+            ttl,
+            seq: self.seq,
+            len: msg.len(),
+            buf: [0u8; MAX_QUEUED_SIZE],
+        };
+        slot.buf[..msg.len()].copy_from_slice(msg);
+
+        self.seq = self.seq.wrapping_add(1);
+        self.slots[idx] = Some(slot);
+
+        Ok(evicted)
+    }
+
+    /// Drop all messages whose time to live has run out, calling `expired`
+    /// with the pipe and priority of each
+    pub fn expire<F: FnMut(u8, Priority)>(&mut self, now: u32, mut expired: F) {
+        for slot in self.slots.iter_mut() {
+            let drop = match slot {
+                Some(ref s) if s.is_expired(now) => {
+                    expired(s.pipe, s.priority);
+                    true
+                }
+                _ => false,
+            };
+
+            if drop {
+                *slot = None;
+            }
+        }
+    }
+
+    /// The next message to send on the given pipe, and a handle to
+    /// `remove()` it once sent
+    pub fn peek(&self, pipe: u8) -> Option<(usize, &[u8])> {
+        self.peek_excluding(pipe, &[])
+    }
+
+    /// The next message to send on the given pipe, after the messages with
+    /// the given handles, e.g. to send several messages together
+    pub fn peek_excluding(&self, pipe: u8, exclude: &[usize]) -> Option<(usize, &[u8])> {
+        let mut best: Option<(usize, &Slot)> = None;
+
+        for (idx, slot) in self.slots.iter().enumerate() {
+            let slot = match slot {
+                Some(ref s) if s.pipe == pipe && !exclude.contains(&idx) => s,
+                _ => continue,
+            };
+
+            best = match best {
+                Some((_, b)) if !slot.is_before(b, self.seq) => best,
+                _ => Some((idx, slot)),
+            };
+        }
+
+        best.map(|(idx, s)| (idx, &s.buf[..s.len]))
+    }
+
+    pub fn remove(&mut self, idx: usize) {
+        if let Some(slot) = self.slots.get_mut(idx) {
+            *slot = None;
+        }
+    }
+
+    /// Drop all messages queued for the given pipe
+    pub fn clear(&mut self, pipe: u8) {
+        for slot in self.slots.iter_mut() {
+            if slot.as_ref().map(|s| s.pipe == pipe).unwrap_or(false) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// The slot that would be sent last of all pipes
+    fn last_of_lowest(&self) -> Option<usize> {
+        let mut worst: Option<(usize, &Slot)> = None;
+
+        for (idx, slot) in self.slots.iter().enumerate() {
+            let slot = match slot {
+                Some(ref s) => s,
+                None => continue,
+            };
+
+            worst = match worst {
+                Some((_, w)) if !w.is_before(slot, self.seq) => worst,
+                _ => Some((idx, slot)),
+            };
+        }
+
+        worst.map(|(idx, _)| idx)
+    }
+}
+
+#[test]
+fn queue_priority_order() {
+    let mut queue = OutgoingQueue::new();
+
+    queue.push(0, b"first", Priority::Routine, None, 0).unwrap();
+    queue
+        .push(0, b"second", Priority::Routine, None, 0)
+        .unwrap();
+    queue
+        .push(1, b"other pipe", Priority::Safety, None, 0)
+        .unwrap();
+    queue
+        .push(0, b"all off", Priority::Safety, None, 0)
+        .unwrap();
+    queue.push(0, b"urgent", Priority::Urgent, None, 0).unwrap();
+    assert_eq!(queue.len(0), 4);
+
+    let mut order = [[0u8; 8]; 4];
+    for out in order.iter_mut() {
+        let (idx, msg) = queue.peek(0).unwrap();
+        out[..msg.len()].copy_from_slice(msg);
+        queue.remove(idx);
+    }
+
+    assert_eq!(&order[0][..7], b"all off");
+    assert_eq!(&order[1][..6], b"urgent");
+    assert_eq!(&order[2][..5], b"first");
+    assert_eq!(&order[3][..6], b"second");
+    assert!(queue.peek(0).is_none());
+    assert_eq!(queue.len(1), 1);
+}
+
+#[test]
+fn queue_fifo_across_rollover() {
+    let mut queue = OutgoingQueue::new();
+    queue.seq = u32::MAX;
+
+    queue.push(0, b"a", Priority::Routine, None, 0).unwrap();
+    queue.push(0, b"b", Priority::Routine, None, 0).unwrap();
+
+    let (idx, msg) = queue.peek(0).unwrap();
+    assert_eq!(msg, b"a");
+    queue.remove(idx);
+    assert_eq!(queue.peek(0).unwrap().1, b"b");
+}
+
+#[test]
+fn queue_ttl_expiry() {
+    let mut queue = OutgoingQueue::new();
+    let start = u32::MAX - 5;
+
+    queue
+        .push(0, b"relay on", Priority::Routine, Some(10), start)
+        .unwrap();
+    queue
+        .push(2, b"forever", Priority::Routine, None, start)
+        .unwrap();
+
+    let mut expired = 0;
+    queue.expire(start.wrapping_add(10), |_, _| expired += 1);
+    assert_eq!(expired, 0);
+    assert_eq!(queue.len(0), 1);
+
+    queue.expire(start.wrapping_add(11), |pipe, prio| {
+        assert_eq!((pipe, prio), (0, Priority::Routine));
+        expired += 1;
+    });
+    assert_eq!(expired, 1);
+    assert!(queue.peek(0).is_none());
+    assert_eq!(queue.len(2), 1);
+}
+
+#[test]
+fn queue_full_evicts_less_urgent() {
+    let mut queue = OutgoingQueue::new();
+
+    for _ in 0..QUEUE_SLOTS {
+        assert!(!queue
+            .push(0, b"routine", Priority::Routine, None, 0)
+            .unwrap());
+    }
+
+    // Equally urgent messages don't push anything out
+    assert!(queue
+        .push(0, b"routine", Priority::Routine, None, 0)
+        .is_err());
+
+    assert!(queue
+        .push(0, b"all off", Priority::Safety, None, 0)
+        .unwrap());
+    assert_eq!(queue.len(0), QUEUE_SLOTS);
+    assert_eq!(queue.peek(0).unwrap().1, b"all off");
+
+    let big = [0u8; MAX_QUEUED_SIZE + 1];
+    assert!(queue.push(0, &big, Priority::Safety, None, 0).is_err());
+    assert!(matches!(
+        queue.push(NUM_PIPES as u8, b"x", Priority::Safety, None, 0),
+        Err(Error::InvalidPipe)
+    ));
+
+    queue.clear(0);
+    assert!(queue.is_empty());
+}
@@ -0,0 +1,811 @@
+//! The radio core shared by the PTX and PRX
+//!
+//! `FleetRadio` handles framing, fragmentation, encryption, ESB packet IDs
+//! and statistics for both sides of the link. The differences between the
+//! two sides, mostly how nonces are chosen and checked, are provided by a
+//! `Role`: see `ptx::Ptx` and `prx::Prx`.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use chacha20poly1305::ChaCha8Poly1305;
+
+use crate::{
+    batch::{Batch, RxBatch},
+    cipher::FleetCipher,
+    fragment::{
+        fragments, max_plaintext, serialize, FragmentHeader, Message, Reassembler,
+        FRAGMENT_HEADER_SIZE, MAX_MESSAGE_SIZE, MORE_PENDING, WHOLE_HEADER_SIZE,
+    },
+    frame::{open_in_place, seal_in_place, split_frame, MAX_FRAME_SIZE},
+    keyring::{KeyRing, NUM_EPOCHS},
+    nonce::FleetNonce,
+    session::Session,
+    transport::{PidState, RxFrame, Transport, TxGrant},
+    BorrowRxMessage, Error, GrantWrap, RadioStats, RxMessage, NUM_PIPES,
+};
+
+/// The policies that differ between the two ends of the link
+pub trait Role {
+    /// Should the radio be started after queueing frames? A PRX instead
+    /// sends its frames with the next ACK.
+    const START_TX: bool;
+
+    /// Should we switch to the key epoch of authentic frames we receive?
+    /// Devices follow the epoch chosen by the modem.
+    const FOLLOW_EPOCH: bool;
+
+    /// The session used for the given pipe. A PTX uses the same session
+    /// for all pipes, a PRX has one per pipe.
+    fn session_slot(pipe: u8) -> usize;
+
+    /// The nonce for a new frame sent on the given pipe
+    fn tx_nonce(&mut self, pipe: u8) -> Result<FleetNonce, Error>;
+
+    /// Check the nonce of a frame received on the given pipe, before it
+    /// is decrypted
+    fn check_rx(&self, pipe: u8, nonce: &FleetNonce) -> Result<(), Error>;
+
+    /// Should frames that fail `check_rx` still be decrypted, in case they
+    /// are a handshake that `resync_rx` accepts?
+    const RESYNC: bool = false;
+
+    /// Called for an authentic handshake frame that failed `check_rx`,
+    /// because the other side has restarted with new nonces. Returns true
+    /// if the nonce state of the pipe was reset, so the frame is accepted.
+    fn resync_rx(&mut self, _pipe: u8, _nonce: &FleetNonce) -> bool {
+        false
+    }
+
+    /// Record the nonce of an authentic frame
+    fn accept_rx(&mut self, pipe: u8, nonce: &FleetNonce);
+
+    /// The reassembly buffer for fragmented messages received on the given pipe
+    fn reassembler(&mut self, pipe: u8) -> Option<&mut Reassembler>;
+
+    /// The tick used to time out fragmented messages, for an authentic frame
+    fn rx_tick(&self, nonce: &FleetNonce) -> u32;
+
+    /// Called for every frame read from the given pipe, before any checks
+    fn frame_read(&mut self, _pipe: u8) {}
+
+    /// Called for every authentic frame received on the given pipe, with
+    /// its header, and whether the sender has more frames waiting
+    fn received(&mut self, _pipe: u8, _header: &FragmentHeader, _more_pending: bool) {}
+
+    /// Called after a message has been sent on the given pipe
+    fn sent_message(&mut self, _pipe: u8) {}
+
+    /// Are there more messages waiting to be sent on the given pipe, after
+    /// the one being sent? If so, the other side is told to poll again soon.
+    fn has_queued(&self, _pipe: u8) -> bool {
+        false
+    }
+}
+
+/// An established session, and the matching cipher
+struct SessionSlot<Cipher: FleetCipher> {
+    crypt: Cipher,
+    session: Session,
+}
+
+/// Per pipe state, common to both roles
+#[derive(Debug, Clone, Copy)]
+struct PipeState {
+    pids: PidState,
+
+    // The ID of the last fragmented message sent on this pipe
+    frag_id: u8,
+
+    // The key epoch of the last authentic frame received on this pipe
+    rx_epoch: u8,
+
+    rejected: u32,
+    retransmits: u32,
+}
+
+impl PipeState {
+    const fn new() -> Self {
+        Self {
+            pids: PidState::new(),
+            frag_id: 0,
+            rx_epoch: 0,
+            rejected: 0,
+            retransmits: 0,
+        }
+    }
+}
+
+pub struct FleetRadio<Radio, R, Cipher = ChaCha8Poly1305>
+where
+    Radio: Transport,
+    R: Role,
+    Cipher: FleetCipher,
+{
+    app: Radio,
+
+    // Without a master key, all radio traffic is refused
+    master_keys: KeyRing<Cipher>,
+    network_id: u32,
+
+    // Keys of individual devices, used instead of the master key
+    device_keys: [KeyRing<Cipher>; NUM_PIPES],
+
+    // The key epoch used for sending
+    epoch: u8,
+
+    pipes: [PipeState; NUM_PIPES],
+    sessions: [Option<SessionSlot<Cipher>>; NUM_PIPES],
+    pub(crate) stats: RadioStats,
+
+    // Small messages waiting to be sent together, and the rest of the last
+    // batch received
+    tx_batch: Batch,
+    rx_batch: RxBatch,
+
+    pub(crate) role: R,
+}
+
+impl<Radio, R, Cipher> FleetRadio<Radio, R, Cipher>
+where
+    Radio: Transport,
+    R: Role,
+    Cipher: FleetCipher,
+{
+    pub(crate) fn from_role(app: Radio, key: Option<&[u8; 32]>, network_id: u32, role: R) -> Self {
+        let mut radio = Self {
+            app,
+            master_keys: KeyRing::new(),
+            network_id,
+
+            device_keys: [
+                KeyRing::new(),
+                KeyRing::new(),
+                KeyRing::new(),
+                KeyRing::new(),
+                KeyRing::new(),
+                KeyRing::new(),
+                KeyRing::new(),
+                KeyRing::new(),
+            ],
+            epoch: 0,
+
+            pipes: [PipeState::new(); NUM_PIPES],
+            sessions: [None, None, None, None, None, None, None, None],
+            stats: RadioStats::default(),
+
+            tx_batch: Batch::new(),
+            rx_batch: RxBatch::new(),
+
+            role,
+        };
+        radio.set_master_key(0, key);
+        radio
+    }
+
+    /// The underlying radio transport
+    pub fn transport(&self) -> &Radio {
+        &self.app
+    }
+
+    /// The master key of the current epoch, used to derive session keys
+    pub fn master_key(&self) -> Option<&[u8; 32]> {
+        self.master_keys.key(self.epoch)
+    }
+
+    /// The key of the current epoch used on the given pipe outside of a
+    /// session: the device's own key if it has one, otherwise the master
+    /// key. Sessions on the pipe are derived from this key.
+    pub fn pipe_key(&self, pipe: u8) -> Option<&[u8; 32]> {
+        match self.device_keys.get(usize::from(pipe)) {
+            Some(ring) if !ring.is_empty() => ring.key(self.epoch),
+            Some(_) => self.master_key(),
+            None => None,
+        }
+    }
+
+    /// The key epoch used for sending. See the `keyring` module.
+    pub fn key_epoch(&self) -> u8 {
+        self.epoch
+    }
+
+    /// Replace all master keys with the key of the given epoch, e.g. once a
+    /// key has been provisioned. Any sessions are ended, as they were derived
+    /// from the old key. With no key, all radio traffic is refused with
+    /// `Error::NoKey`.
+    pub fn set_master_key(&mut self, epoch: u8, key: Option<&[u8; 32]>) {
+        self.epoch = epoch % NUM_EPOCHS;
+        self.master_keys.clear();
+        if let Some(key) = key {
+            self.master_keys.insert(self.epoch, key, self.epoch);
+        }
+        for slot in self.sessions.iter_mut() {
+            *slot = None;
+        }
+    }
+
+    /// Add the master key of the next epoch. Frames in that epoch are
+    /// accepted, but we keep sending in the current epoch until
+    /// `switch_epoch()`.
+    pub fn add_master_key(&mut self, epoch: u8, key: &[u8; 32]) {
+        self.master_keys.insert(epoch, key, self.epoch);
+    }
+
+    /// Start sending in the given epoch, whose keys must have been added.
+    /// Frames in the old epoch are still accepted until `retire_keys()`.
+    pub fn switch_epoch(&mut self, epoch: u8) -> Result<(), Error> {
+        let epoch = epoch % NUM_EPOCHS;
+        if !self.holds_epoch(epoch) {
+            return Err(Error::UnknownEpoch(epoch));
+        }
+        self.epoch = epoch;
+        Ok(())
+    }
+
+    /// Drop the keys of every epoch but the current one, ending the
+    /// rollover window
+    pub fn retire_keys(&mut self) {
+        let epoch = self.epoch;
+        self.master_keys.retain(epoch);
+        for ring in self.device_keys.iter_mut() {
+            ring.retain(epoch);
+        }
+    }
+
+    /// Use the given key for one epoch on one pipe, instead of the master
+    /// key. `None` removes the key of that epoch. Any session on the pipe
+    /// is ended.
+    pub(crate) fn set_pipe_key(&mut self, pipe: u8, epoch: u8, key: Option<&[u8; 32]>) {
+        let current = self.epoch;
+        if let Some(ring) = self.device_keys.get_mut(usize::from(pipe)) {
+            match key {
+                Some(key) => ring.insert(epoch, key, current),
+                None => ring.remove(epoch % NUM_EPOCHS),
+            }
+            self.set_session(pipe, None);
+        }
+    }
+
+    /// The key epoch to send in on the given pipe. A device whose keys don't
+    /// cover the current epoch, such as one still pairing, is answered in the
+    /// epoch it last sent in.
+    fn tx_epoch(&self, pipe: u8) -> u8 {
+        if key_for(&self.device_keys, &self.master_keys, pipe, self.epoch).is_some() {
+            return self.epoch;
+        }
+        self.pipes
+            .get(usize::from(pipe))
+            .map(|p| p.rx_epoch)
+            .unwrap_or(self.epoch)
+    }
+
+    /// Do we have the keys of the given epoch, for any pipe?
+    fn holds_epoch(&self, epoch: u8) -> bool {
+        self.master_keys.contains(epoch) || self.device_keys.iter().any(|r| r.contains(epoch))
+    }
+
+    /// Link quality statistics for all pipes, since the radio was created
+    pub fn stats(&self) -> RadioStats {
+        self.stats.snapshot()
+    }
+
+    /// The number of frames received on the given pipe that were
+    /// rejected as replayed or stale
+    pub fn nonce_rejects(&self, pipe: u8) -> u32 {
+        self.pipes
+            .get(usize::from(pipe))
+            .map(|p| p.rejected)
+            .unwrap_or(0)
+    }
+
+    /// The number of frames received on the given pipe that were dropped
+    /// as ESB retransmissions, because the other side missed our ACK
+    pub fn retransmits(&self, pipe: u8) -> u32 {
+        self.pipes
+            .get(usize::from(pipe))
+            .map(|p| p.retransmits)
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn set_session(&mut self, pipe: u8, session: Option<Session>) {
+        if let Some(slot) = self.sessions.get_mut(R::session_slot(pipe)) {
+            *slot = session.map(|session| SessionSlot {
+                crypt: Cipher::from_key(session.key()),
+                session,
+            });
+        }
+    }
+
+    pub(crate) fn get_session(&self, pipe: u8) -> Option<&Session> {
+        self.sessions
+            .get(R::session_slot(pipe))
+            .and_then(|s| s.as_ref())
+            .map(|s| &s.session)
+    }
+
+    /// Forget the packet ID state of the given pipe, and drop any partially
+    /// received message
+    pub(crate) fn reset_pipe_state(&mut self, pipe: u8) {
+        if let Some(p) = self.pipes.get_mut(usize::from(pipe)) {
+            p.pids.reset();
+        }
+        if let Some(r) = self.role.reassembler(pipe) {
+            r.reset();
+        }
+    }
+
+    /// Send a message. Messages too large for a single frame are split into
+    /// several fragments, which are reassembled on the other side.
+    ///
+    /// If the outgoing queue fills up part way through a fragmented message,
+    /// the fragments already queued are still sent, and the other side will
+    /// drop the partial message.
+    ///
+    /// Any batched messages are sent first.
+    pub fn send<T: Serialize>(&mut self, msg: &T, pipe: u8) -> Result<(), Error> {
+        let mut scratch = [0u8; MAX_MESSAGE_SIZE];
+        let msg = serialize(msg, &mut scratch)?;
+        self.flush()?;
+
+        let more_pending = self.role.has_queued(pipe);
+        self.send_bytes(msg, pipe, more_pending)?;
+        self.role.sent_message(pipe);
+
+        if R::START_TX {
+            // Kick the radio
+            self.app.start_tx();
+        }
+
+        Ok(())
+    }
+
+    /// Add a small message to a batch, to be sent in a single frame with
+    /// other messages for the same pipe. This saves airtime, and the cost
+    /// of encrypting each message separately.
+    ///
+    /// The batch is sent once the next message doesn't fit, or is for
+    /// another pipe, or by `flush()` or `send()`. Messages too large to be
+    /// batched are sent straight away, after the batch.
+    pub fn batch<T: Serialize>(&mut self, msg: &T, pipe: u8) -> Result<(), Error> {
+        let mut scratch = [0u8; MAX_MESSAGE_SIZE];
+        let msg = serialize(msg, &mut scratch)?;
+        let frame_size = self.app.maximum_payload_size();
+
+        if self.tx_batch.push(msg, pipe, frame_size) {
+            return Ok(());
+        }
+
+        self.flush()?;
+        if self.tx_batch.push(msg, pipe, frame_size) {
+            return Ok(());
+        }
+
+        let more_pending = self.role.has_queued(pipe);
+        self.send_bytes(msg, pipe, more_pending)?;
+        self.role.sent_message(pipe);
+
+        if R::START_TX {
+            self.app.start_tx();
+        }
+
+        Ok(())
+    }
+
+    /// The number of messages waiting in the batch
+    pub fn batched(&self) -> usize {
+        self.tx_batch.count()
+    }
+
+    /// Send any batched messages
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if self.tx_batch.is_empty() {
+            return Ok(());
+        }
+
+        let pipe = self.tx_batch.pipe();
+        let more_pending = self.role.has_queued(pipe);
+
+        // A batch of one is cheaper to send as a whole message
+        let single = self.tx_batch.single().is_some();
+        let body = self
+            .tx_batch
+            .single()
+            .unwrap_or_else(|| self.tx_batch.body());
+        let mut scratch = [0u8; MAX_FRAME_SIZE];
+        let body = {
+            scratch[..body.len()].copy_from_slice(body);
+            &scratch[..body.len()]
+        };
+
+        let result = if single {
+            self.send_bytes(body, pipe, more_pending).map(drop)
+        } else {
+            self.send_batch(pipe, body, more_pending)
+        };
+
+        // The batch is dropped even if sending failed, like any other message
+        self.tx_batch.clear();
+        result?;
+        self.role.sent_message(pipe);
+
+        if R::START_TX {
+            self.app.start_tx();
+        }
+
+        Ok(())
+    }
+
+    /// Send the body of a batch in a single frame
+    pub(crate) fn send_batch(
+        &mut self,
+        pipe: u8,
+        body: &[u8],
+        more_pending: bool,
+    ) -> Result<(), Error> {
+        self.send_frame(pipe, FragmentHeader::Batch, body, more_pending)
+    }
+
+    /// Send a frame without a message, only so the PRX can answer with its ACK
+    pub(crate) fn send_poll(&mut self, pipe: u8) -> Result<(), Error> {
+        self.send_frame(pipe, FragmentHeader::Poll, &[], false)?;
+
+        if R::START_TX {
+            self.app.start_tx();
+        }
+
+        Ok(())
+    }
+
+    /// Send a session handshake message, see the `session` module. The
+    /// message must fit into a single frame.
+    ///
+    /// Handshakes are never sealed with a session key, only with the key of
+    /// the pipe, so they can be read by a peer that has lost its session.
+    /// A PRX accepts a handshake even if its nonce is stale, as the device
+    /// may have restarted with new nonces, and starts the nonce checks of
+    /// the pipe over from it. An old handshake may be replayed this way, so
+    /// every handshake must be answered with a new session.
+    pub fn send_handshake<T: Serialize>(&mut self, msg: &T, pipe: u8) -> Result<(), Error> {
+        let mut scratch = [0u8; MAX_MESSAGE_SIZE];
+        let msg = serialize(msg, &mut scratch)?;
+        if msg.len() + WHOLE_HEADER_SIZE > max_plaintext(self.app.maximum_payload_size()) {
+            return Err(Error::MessageTooLarge);
+        }
+        self.flush()?;
+
+        let more_pending = self.role.has_queued(pipe);
+        self.send_frame(pipe, FragmentHeader::Handshake, msg, more_pending)?;
+        self.role.sent_message(pipe);
+
+        if R::START_TX {
+            self.app.start_tx();
+        }
+
+        Ok(())
+    }
+
+    /// Send an already serialized message, returning the number of frames used.
+    /// If `more_pending` is set, the other side is told more messages will follow.
+    pub(crate) fn send_bytes(
+        &mut self,
+        msg: &[u8],
+        pipe: u8,
+        more_pending: bool,
+    ) -> Result<usize, Error> {
+        let state = self
+            .pipes
+            .get_mut(usize::from(pipe))
+            .ok_or(Error::InvalidPipe)?;
+        state.frag_id = state.frag_id.wrapping_add(1);
+        let frag_id = state.frag_id;
+
+        let max = max_plaintext(self.app.maximum_payload_size());
+
+        let mut frames = 0;
+        let mut frags = fragments(msg, frag_id, max)?.peekable();
+        while let Some((header, chunk)) = frags.next() {
+            let pending = more_pending || frags.peek().is_some();
+            self.send_frame(pipe, header, chunk, pending)?;
+            frames += 1;
+        }
+
+        Ok(frames)
+    }
+
+    fn send_frame(
+        &mut self,
+        pipe: u8,
+        header: FragmentHeader,
+        chunk: &[u8],
+        more_pending: bool,
+    ) -> Result<(), Error> {
+        let epoch = self.tx_epoch(pipe);
+        if key_for(&self.device_keys, &self.master_keys, pipe, epoch).is_none() {
+            return Err(Error::NoKey);
+        }
+
+        let pid = self
+            .pipes
+            .get_mut(usize::from(pipe))
+            .ok_or(Error::InvalidPipe)?
+            .pids
+            .next_tx();
+        let mut grant = self.app.grant(pipe, pid)?;
+
+        let start = header.write(&mut grant)?;
+        if more_pending {
+            grant[0] |= MORE_PENDING;
+        }
+        let used = start + chunk.len();
+        grant
+            .get_mut(start..used)
+            .ok_or(Error::MessageTooLarge)?
+            .copy_from_slice(chunk);
+
+        let mut nonce = self.role.tx_nonce(pipe)?;
+        nonce.epoch = epoch;
+
+        // Encrypt, with the session key if we have one
+        let session = match header {
+            FragmentHeader::Handshake => None,
+            _ => self.sessions[R::session_slot(pipe)].as_mut(),
+        };
+        let used = match session {
+            Some(slot) => {
+                let used =
+                    seal_in_place(&slot.crypt, self.network_id, pipe, &nonce, &mut grant, used)?;
+                slot.session.count_message();
+                used
+            }
+            None => {
+                let crypt = key_for(&self.device_keys, &self.master_keys, pipe, epoch)
+                    .ok_or(Error::NoKey)?;
+                seal_in_place(crypt, self.network_id, pipe, &nonce, &mut grant, used)?
+            }
+        };
+
+        // Commit payload
+        grant.commit(used);
+        self.stats.frames_sent = self.stats.frames_sent.wrapping_add(1);
+
+        Ok(())
+    }
+
+    /// Receive the next complete message, and deserialize it
+    pub fn receive<T: 'static + DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<RxMessage<T>>, Error> {
+        let grant = match self.receive_with() {
+            Ok(rgr) => rgr,
+            Err(Error::NoData) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        // The grant is released when dropped
+        grant
+            .view_with(|msg: BorrowRxMessage<T>| RxMessage {
+                msg: msg.msg,
+                meta: msg.meta,
+            })
+            .map(Some)
+    }
+
+    /// Receive the next complete message, reassembling fragmented messages.
+    ///
+    /// The message is released when the returned grant is dropped.
+    pub fn receive_with(&mut self) -> Result<GrantWrap<Radio::Frame>, Error> {
+        loop {
+            // Hand out the rest of the last batch first
+            if let Some(next) = self.rx_batch.next_message() {
+                let (pipe, msg) = next?;
+                return Ok(GrantWrap::reassembled(Message::from_slice(msg), pipe));
+            }
+
+            let (frame, nonce, len) = self.next_frame()?;
+            let pipe = frame.pipe();
+
+            let (header, _) = FragmentHeader::parse(&frame[..len])?;
+            let more_pending = (frame[0] & MORE_PENDING) != 0;
+            self.role.received(pipe, &header, more_pending);
+
+            let frag = match header {
+                // Nothing for the application, release and get the next packet
+                FragmentHeader::Poll => continue,
+                FragmentHeader::Whole | FragmentHeader::Handshake => {
+                    return Ok(GrantWrap::frame(frame, WHOLE_HEADER_SIZE, len));
+                }
+                FragmentHeader::Batch => {
+                    self.rx_batch.load(pipe, &frame[WHOLE_HEADER_SIZE..len]);
+                    continue;
+                }
+                FragmentHeader::Part(frag) => frag,
+            };
+
+            let tick = self.role.rx_tick(&nonce);
+            let reassembly = self.role.reassembler(pipe).ok_or(Error::InvalidPipe)?;
+            reassembly.expire(tick);
+            let done = reassembly.push(frag, &frame[FRAGMENT_HEADER_SIZE..len], tick)?;
+            drop(frame);
+
+            if let Some(msg) = done {
+                return Ok(GrantWrap::reassembled(msg, pipe));
+            }
+        }
+    }
+
+    /// Receive the next authentic frame, returning the frame, its nonce,
+    /// and the length of its plaintext
+    fn next_frame(&mut self) -> Result<(Radio::Frame, FleetNonce, usize), Error> {
+        loop {
+            let mut frame = match self.app.read() {
+                // No packet ready
+                None => return Err(Error::NoData),
+                Some(pkt) => pkt,
+            };
+
+            if let Some(rssi) = frame.rssi() {
+                self.stats.last_rssi = Some(rssi);
+            }
+            self.role.frame_read(frame.pipe());
+
+            // Empty ACK, release and get the next packet
+            if frame.is_empty() {
+                continue;
+            }
+
+            let pipe = frame.pipe();
+
+            // Drop everything until we have a key
+            let no_keys = match self.device_keys.get(usize::from(pipe)) {
+                Some(ring) => ring.is_empty() && self.master_keys.is_empty(),
+                None => true,
+            };
+            if no_keys {
+                return Err(Error::NoKey);
+            }
+
+            let pid = frame.pid();
+            let (payload, fleet_nonce) = match split_frame::<Cipher>(&mut frame) {
+                Ok(split) => split,
+                Err(e) => {
+                    if let Error::PacketTooSmol = e {
+                        self.stats.too_small = self.stats.too_small.wrapping_add(1);
+                    }
+                    return Err(e);
+                }
+            };
+
+            let state = self
+                .pipes
+                .get_mut(usize::from(pipe))
+                .ok_or(Error::InvalidPipe)?;
+
+            // The other side missed our ACK, and sent the same frame again
+            if state.pids.is_retransmit(pid, &fleet_nonce) {
+                state.retransmits = state.retransmits.wrapping_add(1);
+                self.stats.retransmits = self.stats.retransmits.wrapping_add(1);
+                continue;
+            }
+
+            // Reject replayed or stale frames before spending time on
+            // decryption, unless they may be a handshake we can resync to
+            let stale = match self.role.check_rx(pipe, &fleet_nonce) {
+                Ok(()) => None,
+                Err(e) if R::RESYNC => Some(e),
+                Err(e) => {
+                    state.rejected = state.rejected.wrapping_add(1);
+                    self.stats.nonce_rejects = self.stats.nonce_rejects.wrapping_add(1);
+                    return Err(e);
+                }
+            };
+
+            let network_id = self.network_id;
+            let slot = R::session_slot(pipe);
+            let epoch = fleet_nonce.epoch;
+            let master = key_for(&self.device_keys, &self.master_keys, pipe, epoch);
+
+            // A handshake is never sealed with the session key, and a stale
+            // frame may be a replay, so it must not end the session either
+            let session = match stale {
+                Some(_) => None,
+                None => self.sessions[slot].as_mut(),
+            };
+
+            let opened = match session {
+                Some(sess) => {
+                    // Keep a copy of the ciphertext, in case we need to retry
+                    // with the master key
+                    let used = payload.len();
+                    let mut backup = [0u8; MAX_FRAME_SIZE];
+                    backup[..used].copy_from_slice(payload);
+
+                    match open_in_place(&sess.crypt, network_id, pipe, &fleet_nonce, payload) {
+                        Ok(len) => {
+                            sess.session.count_message();
+                            Ok(len)
+                        }
+                        Err(_) => {
+                            // Once a session is up, the master key is only accepted for
+                            // handshakes, e.g. from a peer that restarted and lost the
+                            // session. The session is kept until the app replaces it.
+                            payload.copy_from_slice(&backup[..used]);
+                            match master {
+                                Some(master) => {
+                                    open_in_place(master, network_id, pipe, &fleet_nonce, payload)
+                                        .and_then(|len| {
+                                            if is_handshake(&payload[..len]) {
+                                                Ok(len)
+                                            } else {
+                                                Err(Error::OutsideSession)
+                                            }
+                                        })
+                                }
+                                None => Err(Error::UnknownEpoch(epoch)),
+                            }
+                        }
+                    }
+                }
+                None => match master {
+                    Some(master) => open_in_place(master, network_id, pipe, &fleet_nonce, payload),
+                    None => Err(Error::UnknownEpoch(epoch)),
+                },
+            };
+
+            let len = match (opened, stale) {
+                (Ok(len), None) => len,
+                (Err(e), None) => {
+                    self.stats.decrypt_failures = self.stats.decrypt_failures.wrapping_add(1);
+                    return Err(e);
+                }
+                (opened, Some(e)) => {
+                    // Only an authentic handshake may restart the nonce checks
+                    let handshake = opened.ok().filter(|&len| is_handshake(&frame[..len]));
+                    match handshake {
+                        Some(len) if self.role.resync_rx(pipe, &fleet_nonce) => len,
+                        _ => {
+                            state.rejected = state.rejected.wrapping_add(1);
+                            self.stats.nonce_rejects = self.stats.nonce_rejects.wrapping_add(1);
+                            return Err(e);
+                        }
+                    }
+                }
+            };
+
+            // Only update the tracking variables once we know the frame is
+            // authentic, otherwise a forged nonce could lock out the other side
+            self.role.accept_rx(pipe, &fleet_nonce);
+            state.pids.accept(pid, &fleet_nonce);
+            state.rx_epoch = epoch;
+            self.stats.frames_received = self.stats.frames_received.wrapping_add(1);
+
+            // An authentic frame in the next epoch tells us to switch to it
+            if R::FOLLOW_EPOCH && epoch != self.epoch && master.is_some() {
+                self.epoch = epoch;
+            }
+
+            return Ok((frame, fleet_nonce, len));
+        }
+    }
+}
+
+/// Is this decrypted frame a session handshake?
+fn is_handshake(plaintext: &[u8]) -> bool {
+    matches!(
+        FragmentHeader::parse(plaintext),
+        Ok((FragmentHeader::Handshake, _))
+    )
+}
+
+/// The key used on the given pipe in the given epoch, outside of a session
+fn key_for<'a, Cipher: FleetCipher>(
+    device_keys: &'a [KeyRing<Cipher>; NUM_PIPES],
+    master_keys: &'a KeyRing<Cipher>,
+    pipe: u8,
+    epoch: u8,
+) -> Option<&'a Cipher> {
+    match device_keys.get(usize::from(pipe)) {
+        // Devices with their own keys never use the master key
+        Some(ring) if !ring.is_empty() => ring.get(epoch),
+        Some(_) => master_keys.get(epoch),
+        None => None,
+    }
+}
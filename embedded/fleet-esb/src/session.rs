@@ -0,0 +1,183 @@
+//! Per-session key establishment
+//!
+//! Rather than encrypting all traffic directly with the long-term fleet
+//! key, a device and the host agree on a session key when the device
+//! starts up, and periodically afterwards:
+//!
+//! 1. The device picks a random nonce, and sends it to the host in an
+//!    `InitializeSession` message, protected by the current key.
+//! 2. The host picks its own random nonce, and sends it back in a
+//!    `SessionAccepted` message, protected by the current key.
+//! 3. Both sides derive the session key with HKDF-SHA256 over the master
+//!    key and both nonces, and switch to it for all further frames.
+//!
+//! Sessions are rotated when the device reboots, or after a fixed number
+//! of messages, which limits how much traffic any one key protects.
+//!
+//! Both handshake messages are sent with `send_handshake()`, which always
+//! uses the key of the pipe. Once a session is up, no other frames are
+//! accepted under that key, and the old session stays in use until the
+//! handshake replacing it completes.
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+pub const SESSION_NONCE_SIZE: usize = 16;
+pub const SESSION_KEY_SIZE: usize = 32;
+
+/// The default number of messages protected by a session key before
+/// a new session should be established
+pub const DEFAULT_SESSION_MESSAGES: u32 = 1 << 20;
+
+const SESSION_INFO: &[u8] = b"fleet-esb session key v1";
+
+/// Derive a session key from the fleet master key and both session nonces
+pub fn derive_session_key(
+    master: &[u8; 32],
+    device_nonce: &[u8; SESSION_NONCE_SIZE],
+    host_nonce: &[u8; SESSION_NONCE_SIZE],
+) -> [u8; SESSION_KEY_SIZE] {
+    let mut salt = [0u8; 2 * SESSION_NONCE_SIZE];
+    salt[..SESSION_NONCE_SIZE].copy_from_slice(device_nonce);
+    salt[SESSION_NONCE_SIZE..].copy_from_slice(host_nonce);
+
+    let hk = Hkdf::<Sha256>::new(Some(&salt), master);
+    let mut key = [0u8; SESSION_KEY_SIZE];
+
+    // 32 bytes is always a valid output length for SHA256
+    hk.expand(SESSION_INFO, &mut key).ok();
+
+    key
+}
+
+/// A session key, along with how many messages it has protected
+pub struct Session {
+    key: [u8; SESSION_KEY_SIZE],
+    messages: u32,
+    limit: u32,
+}
+
+impl Session {
+    pub fn new(key: [u8; SESSION_KEY_SIZE], limit: u32) -> Self {
+        Self {
+            key,
+            messages: 0,
+            limit,
+        }
+    }
+
+    pub fn key(&self) -> &[u8; SESSION_KEY_SIZE] {
+        &self.key
+    }
+
+    /// Record that a message was protected with this session key
+    pub fn count_message(&mut self) {
+        self.messages = self.messages.saturating_add(1);
+    }
+
+    pub fn messages(&self) -> u32 {
+        self.messages
+    }
+
+    /// Has this session protected enough messages that it should be replaced?
+    pub fn needs_rotation(&self) -> bool {
+        self.messages >= self.limit
+    }
+}
+
+/// The device (PTX) side of the session handshake
+pub struct DeviceHandshake {
+    device_nonce: [u8; SESSION_NONCE_SIZE],
+}
+
+impl DeviceHandshake {
+    /// Start a new handshake. `device_nonce` MUST be freshly generated
+    /// from a hardware RNG.
+    pub fn new(device_nonce: [u8; SESSION_NONCE_SIZE]) -> Self {
+        Self { device_nonce }
+    }
+
+    /// The nonce to send to the host in `InitializeSession`
+    pub fn device_nonce(&self) -> &[u8; SESSION_NONCE_SIZE] {
+        &self.device_nonce
+    }
+
+    /// Complete the handshake with the nonce received in `SessionAccepted`
+    pub fn complete(
+        self,
+        master: &[u8; 32],
+        host_nonce: &[u8; SESSION_NONCE_SIZE],
+        limit: u32,
+    ) -> Session {
+        Session::new(
+            derive_session_key(master, &self.device_nonce, host_nonce),
+            limit,
+        )
+    }
+}
+
+/// The host (PRX) side of the session handshake
+///
+/// The host side completes in a single step, as soon as it has seen the
+/// device's nonce. `host_nonce` MUST be freshly generated from a hardware
+/// RNG, and should be sent back to the device in `SessionAccepted`.
+pub fn accept_session(
+    master: &[u8; 32],
+    device_nonce: &[u8; SESSION_NONCE_SIZE],
+    host_nonce: &[u8; SESSION_NONCE_SIZE],
+    limit: u32,
+) -> Session {
+    Session::new(derive_session_key(master, device_nonce, host_nonce), limit)
+}
+
+#[test]
+fn handshake_in_memory() {
+    let master = [0x11; 32];
+
+    // Device starts the handshake
+    let device = DeviceHandshake::new([0x22; SESSION_NONCE_SIZE]);
+    let init_nonce = *device.device_nonce();
+
+    // Host responds
+    let host_nonce = [0x33; SESSION_NONCE_SIZE];
+    let host_session = accept_session(&master, &init_nonce, &host_nonce, 3);
+
+    // Device completes
+    let device_session = device.complete(&master, &host_nonce, 3);
+
+    assert_eq!(host_session.key(), device_session.key());
+    assert_ne!(host_session.key(), &master);
+}
+
+#[test]
+fn sessions_differ() {
+    let master = [0x11; 32];
+    let dev_a = [0x01; SESSION_NONCE_SIZE];
+    let dev_b = [0x02; SESSION_NONCE_SIZE];
+    let host_a = [0x03; SESSION_NONCE_SIZE];
+    let host_b = [0x04; SESSION_NONCE_SIZE];
+
+    let base = derive_session_key(&master, &dev_a, &host_a);
+
+    // Changing either nonce, or the master key, changes the session key
+    assert_ne!(base, derive_session_key(&master, &dev_b, &host_a));
+    assert_ne!(base, derive_session_key(&master, &dev_a, &host_b));
+    assert_ne!(base, derive_session_key(&[0x12; 32], &dev_a, &host_a));
+
+    // Swapping the roles of the nonces also changes the key
+    assert_ne!(base, derive_session_key(&master, &host_a, &dev_a));
+
+    // But the derivation is deterministic
+    assert_eq!(base, derive_session_key(&master, &dev_a, &host_a));
+}
+
+#[test]
+fn session_rotation() {
+    let mut session = Session::new([0u8; SESSION_KEY_SIZE], 2);
+    assert!(!session.needs_rotation());
+    session.count_message();
+    assert!(!session.needs_rotation());
+    session.count_message();
+    assert!(session.needs_rotation());
+    assert_eq!(session.messages(), 2);
+}
@@ -0,0 +1,64 @@
+//! Link quality statistics
+//!
+//! Each radio keeps a `RadioStats`, available with `stats()`. The counters
+//! wrap on overflow, so consumers should look at the difference between two
+//! snapshots, rather than the absolute values.
+//!
+//! `MaximumAttempts` errors are reported by the ESB interrupt, rather than
+//! the radio, so the interrupt handler should call `record_max_attempts()`.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+static MAX_ATTEMPTS: AtomicU32 = AtomicU32::new(0);
+
+/// Record a `MaximumAttempts` error from the ESB interrupt
+pub fn record_max_attempts() {
+    // Only the radio interrupt writes this counter, so a load and store is
+    // enough. This also works on the nRF51, which has no atomic RMW operations.
+    let count = MAX_ATTEMPTS.load(Ordering::Relaxed);
+    MAX_ATTEMPTS.store(count.wrapping_add(1), Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RadioStats {
+    /// Frames committed to the radio, including each fragment of a message
+    pub frames_sent: u32,
+
+    /// Authentic frames received
+    pub frames_received: u32,
+
+    /// Frames that failed to decrypt, e.g. due to a wrong key or tampering
+    pub decrypt_failures: u32,
+
+    /// Frames rejected as replayed or stale
+    pub nonce_rejects: u32,
+
+    /// Frames dropped as ESB retransmissions of an already received frame
+    pub retransmits: u32,
+
+    /// Frames too small (or large) to contain a payload, tag and nonce
+    pub too_small: u32,
+
+    /// Frames the radio gave up sending, after not receiving an ACK
+    pub max_attempts: u32,
+
+    /// Queued messages dropped because their time to live ran out
+    pub expired: u32,
+
+    /// Queued messages dropped to make room for a more urgent message
+    pub evicted: u32,
+
+    /// The RSSI of the last frame received, if reported by the radio
+    pub last_rssi: Option<u8>,
+}
+
+impl RadioStats {
+    pub(crate) fn snapshot(&self) -> Self {
+        Self {
+            max_attempts: MAX_ATTEMPTS.load(Ordering::Relaxed),
+            ..*self
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! The radio transport used by the fleet radios
+//!
+//! `FleetRadioPtx` and `FleetRadioPrx` only need to be able to grant, commit,
+//! read and release frames. On hardware, this is implemented by `esb::EsbApp`.
+//! On a host, the `loopback` module (with the `std` feature) can be used instead.
+
+use core::ops::DerefMut;
+
+use crate::{nonce::FleetNonce, Error};
+
+/// A radio that can send and receive frames of up to 255 bytes
+pub trait Transport {
+    /// A writable frame, obtained with `grant()`
+    type Grant: TxGrant;
+
+    /// A received frame, obtained with `read()`
+    type Frame: RxFrame;
+
+    /// The largest frame, including the tag and nonce, that can be sent
+    fn maximum_payload_size(&self) -> usize;
+
+    /// Obtain a frame to be sent on the given pipe, with the given 2-bit
+    /// packet ID. The grant is `maximum_payload_size()` bytes long.
+    fn grant(&mut self, pipe: u8, pid: u8) -> Result<Self::Grant, Error>;
+
+    /// Obtain the next received frame, if any. Frames should be released
+    /// when dropped.
+    fn read(&mut self) -> Option<Self::Frame>;
+
+    /// Start sending any committed frames. This may be a no-op, e.g. for a
+    /// PRX, where frames are sent as part of the ACK.
+    fn start_tx(&mut self);
+}
+
+/// A writable frame
+pub trait TxGrant: DerefMut<Target = [u8]> {
+    /// Send the first `used` bytes of the grant
+    fn commit(self, used: usize);
+}
+
+/// A received frame
+pub trait RxFrame: DerefMut<Target = [u8]> {
+    /// The pipe this frame was received on
+    fn pipe(&self) -> u8;
+
+    /// The 2-bit packet ID of this frame
+    fn pid(&self) -> u8;
+
+    /// The received signal strength of this frame, if known
+    fn rssi(&self) -> Option<u8> {
+        None
+    }
+
+    /// Release the frame, making room for the next one
+    fn release(self);
+}
+
+/// Tracks the ESB packet IDs of a single pipe
+///
+/// The PID is incremented for every new frame sent, and kept the same when
+/// the radio retransmits a frame. If our ACK is lost, the sender will
+/// retransmit a frame we have already received. Such a frame has the same PID
+/// and nonce as the last frame, and is dropped before the nonce checks, so it
+/// isn't mistaken for a replay.
+#[derive(Debug, Clone, Copy)]
+pub struct PidState {
+    tx: u8,
+    last_rx: Option<(u8, FleetNonce)>,
+}
+
+impl PidState {
+    pub const fn new() -> Self {
+        Self {
+            tx: 0,
+            last_rx: None,
+        }
+    }
+
+    /// The PID to use for the next new frame
+    pub fn next_tx(&mut self) -> u8 {
+        let pid = self.tx;
+        self.tx = (self.tx + 1) & 0b11;
+        pid
+    }
+
+    /// Is this frame a retransmission of the last accepted frame?
+    pub fn is_retransmit(&self, pid: u8, nonce: &FleetNonce) -> bool {
+        self.last_rx == Some((pid, *nonce))
+    }
+
+    /// Record an authentic frame
+    pub fn accept(&mut self, pid: u8, nonce: &FleetNonce) {
+        self.last_rx = Some((pid, *nonce));
+    }
+
+    pub fn reset(&mut self) {
+        self.last_rx = None;
+    }
+}
+
+impl Default for PidState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "radio")]
+mod esb_app {
+    use super::{RxFrame, Transport, TxGrant};
+    use crate::Error;
+    use esb::{
+        payload::{PayloadR, PayloadW},
+        ArrayLength, EsbApp, EsbHeader,
+    };
+
+    impl<OutgoingLen, IncomingLen> Transport for EsbApp<OutgoingLen, IncomingLen>
+    where
+        OutgoingLen: 'static + ArrayLength<u8>,
+        IncomingLen: 'static + ArrayLength<u8>,
+    {
+        type Grant = PayloadW<OutgoingLen>;
+        type Frame = PayloadR<IncomingLen>;
+
+        fn maximum_payload_size(&self) -> usize {
+            EsbApp::maximum_payload_size(self)
+        }
+
+        fn grant(&mut self, pipe: u8, pid: u8) -> Result<Self::Grant, Error> {
+            let header = EsbHeader::build()
+                .max_payload(EsbApp::maximum_payload_size(self) as u8)
+                .pid(pid)
+                .pipe(pipe)
+                .no_ack(false)
+                .check()?;
+
+            Ok(self.grant_packet(header)?)
+        }
+
+        fn read(&mut self) -> Option<Self::Frame> {
+            self.read_packet().map(|mut frame| {
+                frame.auto_release(true);
+                frame
+            })
+        }
+
+        fn start_tx(&mut self) {
+            EsbApp::start_tx(self)
+        }
+    }
+
+    impl<N> TxGrant for PayloadW<N>
+    where
+        N: 'static + ArrayLength<u8>,
+    {
+        fn commit(self, used: usize) {
+            PayloadW::commit(self, used)
+        }
+    }
+
+    impl<N> RxFrame for PayloadR<N>
+    where
+        N: 'static + ArrayLength<u8>,
+    {
+        fn pipe(&self) -> u8 {
+            PayloadR::pipe(self)
+        }
+
+        fn pid(&self) -> u8 {
+            PayloadR::pid(self)
+        }
+
+        fn rssi(&self) -> Option<u8> {
+            Some(PayloadR::rssi(self))
+        }
+
+        fn release(self) {
+            PayloadR::release(self)
+        }
+    }
+}
+
+#[test]
+fn pid_rotation_and_retransmits() {
+    let mut pids = PidState::new();
+    let sent = [
+        pids.next_tx(),
+        pids.next_tx(),
+        pids.next_tx(),
+        pids.next_tx(),
+        pids.next_tx(),
+    ];
+    assert_eq!(sent, [0, 1, 2, 3, 0]);
+
+    let nonce = FleetNonce::new(10, 20);
+    assert!(!pids.is_retransmit(1, &nonce));
+    pids.accept(1, &nonce);
+    assert!(pids.is_retransmit(1, &nonce));
+
+    // A new PID, or a new nonce, is not a retransmission
+    assert!(!pids.is_retransmit(2, &nonce));
+    assert!(!pids.is_retransmit(1, &FleetNonce::new(10, 21)));
+
+    pids.reset();
+    assert!(!pids.is_retransmit(1, &nonce));
+}
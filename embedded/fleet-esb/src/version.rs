@@ -0,0 +1,81 @@
+//! The fleet protocol version
+//!
+//! Every nonce carries the protocol version of its sender, in the low bits
+//! of the magic word. Peers with the same major version can talk to each
+//! other, whatever their minor and trivial versions, so a fleet can be
+//! upgraded one node at a time. A new major version is only needed when old
+//! and new nodes can no longer understand each other.
+
+use crate::{
+    CipherSuite, MAGIC_MASK, MAGIC_WORD, MAJOR_MASK, MAJOR_SHIFT, MINOR_MASK, MINOR_SHIFT,
+    SUITE_MASK, SUITE_SHIFT, TRIVIAL_MASK,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolVersion {
+    /// At most 3, as the rest of the major nibble carries the cipher suite
+    pub major: u8,
+
+    /// At most 15
+    pub minor: u8,
+
+    /// At most 15
+    pub trivial: u8,
+}
+
+/// The protocol version spoken by this crate
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::from_magic(MAGIC_WORD);
+
+impl ProtocolVersion {
+    /// Extract the version from a magic word. The magic bits themselves
+    /// are not checked.
+    pub const fn from_magic(magic: u32) -> Self {
+        Self {
+            major: ((magic & MAJOR_MASK) >> MAJOR_SHIFT) as u8,
+            minor: ((magic & MINOR_MASK) >> MINOR_SHIFT) as u8,
+            trivial: (magic & TRIVIAL_MASK) as u8,
+        }
+    }
+
+    /// The magic word sent by a peer with this version, using the given
+    /// cipher suite
+    pub fn magic_word(&self, suite: CipherSuite) -> u32 {
+        (MAGIC_WORD & MAGIC_MASK)
+            | ((u32::from(self.major) << MAJOR_SHIFT) & MAJOR_MASK)
+            | ((u32::from(suite.bits()) << SUITE_SHIFT) & SUITE_MASK)
+            | ((u32::from(self.minor) << MINOR_SHIFT) & MINOR_MASK)
+            | (u32::from(self.trivial) & TRIVIAL_MASK)
+    }
+
+    /// Can a peer with this version talk to one with the other version?
+    pub fn is_compatible(&self, other: &ProtocolVersion) -> bool {
+        self.major == other.major
+    }
+}
+
+#[test]
+fn version_magic_roundtrip() {
+    use crate::magic_word;
+
+    assert_eq!(
+        PROTOCOL_VERSION.magic_word(CipherSuite::ChaCha8Poly1305),
+        magic_word(CipherSuite::ChaCha8Poly1305)
+    );
+
+    let version = ProtocolVersion {
+        major: 3,
+        minor: 9,
+        trivial: 15,
+    };
+    let magic = version.magic_word(CipherSuite::Aes128GcmSiv);
+    assert_eq!(magic & MAGIC_MASK, MAGIC_WORD & MAGIC_MASK);
+    assert_eq!(ProtocolVersion::from_magic(magic), version);
+    assert_eq!((magic & SUITE_MASK) >> SUITE_SHIFT, 2);
+
+    let newer_minor = ProtocolVersion {
+        minor: PROTOCOL_VERSION.minor + 1,
+        ..PROTOCOL_VERSION
+    };
+    assert!(PROTOCOL_VERSION.is_compatible(&newer_minor));
+    assert!(!PROTOCOL_VERSION.is_compatible(&version));
+}
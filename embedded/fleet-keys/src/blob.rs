@@ -0,0 +1,202 @@
+//! Keys provisioned at runtime, rather than compiled in
+//!
+//! A key blob is written to a dedicated flash region of each node, normally
+//! the customer area of the UICR, so that one firmware image can be flashed
+//! to every node. Blobs are generated on the host by `fleet-cli key-blob`.
+//!
+//! A second blob may follow the first, holding the key of the next epoch,
+//! so keys can be rolled over without reflashing every node at once. The
+//! low four bits of the key ID are its epoch.
+//!
+//! The blob is laid out as follows, with all integers little endian:
+//!
+//! | offset | size | contents                                   |
+//! | ------ | ---- | ------------------------------------------ |
+//! | 0      | 4    | `BLOB_MAGIC`                               |
+//! | 4      | 1    | `BLOB_VERSION`                             |
+//! | 5      | 3    | reserved, zero                             |
+//! | 8      | 4    | key ID                                     |
+//! | 12     | 32   | key                                        |
+//! | 44     | 4    | CRC-32 (IEEE) of all of the bytes above    |
+
+use crate::FleetKey;
+
+/// The size of a serialized key blob
+pub const BLOB_SIZE: usize = 48;
+
+/// The first bytes of every key blob
+pub const BLOB_MAGIC: [u8; 4] = *b"FKEY";
+
+/// The current blob layout
+pub const BLOB_VERSION: u8 = 1;
+
+/// The start of the UICR customer area on the nRF52 series, which has room
+/// for 128 bytes
+pub const UICR_CUSTOMER: usize = 0x1000_1080;
+
+/// Where the blob with the key of the next epoch is stored
+pub const UICR_NEXT: usize = UICR_CUSTOMER + BLOB_SIZE;
+
+// Both blobs must fit in the customer area
+const _: () = assert!(UICR_NEXT + BLOB_SIZE <= UICR_CUSTOMER + 128);
+
+const ID_OFFSET: usize = 8;
+const KEY_OFFSET: usize = 12;
+const CRC_OFFSET: usize = 44;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlobError {
+    /// The region is erased, so no key was ever provisioned
+    Missing,
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadCrc,
+}
+
+/// A key, and the ID used to tell provisioned keys apart
+pub struct KeyBlob {
+    key_id: u32,
+    key: FleetKey,
+}
+
+impl KeyBlob {
+    pub fn new(key_id: u32, key: [u8; 32]) -> Self {
+        Self {
+            key_id,
+            key: FleetKey { key },
+        }
+    }
+
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    pub fn key(&self) -> &FleetKey {
+        &self.key
+    }
+
+    /// The key epoch, carried in every radio frame
+    pub fn epoch(&self) -> u8 {
+        (self.key_id & 0xF) as u8
+    }
+
+    pub fn to_bytes(&self) -> [u8; BLOB_SIZE] {
+        let mut buf = [0u8; BLOB_SIZE];
+        buf[..4].copy_from_slice(&BLOB_MAGIC);
+        buf[4] = BLOB_VERSION;
+        buf[ID_OFFSET..KEY_OFFSET].copy_from_slice(&self.key_id.to_le_bytes());
+        buf[KEY_OFFSET..CRC_OFFSET].copy_from_slice(&self.key.key);
+
+        let crc = crc32(&buf[..CRC_OFFSET]);
+        buf[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, BlobError> {
+        if buf.len() < BLOB_SIZE || buf[..4].iter().all(|b| *b == 0xFF) {
+            return Err(BlobError::Missing);
+        }
+        if buf[..4] != BLOB_MAGIC {
+            return Err(BlobError::BadMagic);
+        }
+        if buf[4] != BLOB_VERSION {
+            return Err(BlobError::UnsupportedVersion(buf[4]));
+        }
+
+        let mut crc_buf = [0u8; 4];
+        crc_buf.copy_from_slice(&buf[CRC_OFFSET..BLOB_SIZE]);
+        if crc32(&buf[..CRC_OFFSET]) != u32::from_le_bytes(crc_buf) {
+            return Err(BlobError::BadCrc);
+        }
+
+        let mut id_buf = [0u8; 4];
+        id_buf.copy_from_slice(&buf[ID_OFFSET..KEY_OFFSET]);
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&buf[KEY_OFFSET..CRC_OFFSET]);
+
+        Ok(Self::new(u32::from_le_bytes(id_buf), key))
+    }
+
+    /// Load the blob stored at the given address
+    ///
+    /// # Safety
+    ///
+    /// `addr` must point to `BLOB_SIZE` readable bytes, such as flash or the
+    /// UICR.
+    pub unsafe fn load(addr: usize) -> Result<Self, BlobError> {
+        let mut buf = [0u8; BLOB_SIZE];
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = core::ptr::read_volatile((addr + i) as *const u8);
+        }
+        Self::from_bytes(&buf)
+    }
+
+    /// Load the blob provisioned in the UICR customer area
+    #[cfg(feature = "uicr")]
+    pub fn from_uicr() -> Result<Self, BlobError> {
+        // The UICR is always mapped, and always readable
+        unsafe { Self::load(UICR_CUSTOMER) }
+    }
+
+    /// Load the blob with the key of the next epoch, if one was provisioned
+    #[cfg(feature = "uicr")]
+    pub fn next_from_uicr() -> Result<Self, BlobError> {
+        unsafe { Self::load(UICR_NEXT) }
+    }
+}
+
+/// CRC-32, as used by Ethernet and zlib
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[test]
+fn blob_roundtrip() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+    let blob = KeyBlob::new(0x0102_0304, [0x42; 32]);
+    let bytes = blob.to_bytes();
+    assert_eq!(&bytes[..5], b"FKEY\x01");
+
+    let parsed = KeyBlob::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed.key_id(), 0x0102_0304);
+    assert_eq!(parsed.key().key(), &[0x42; 32]);
+    assert_eq!(parsed.epoch(), 4);
+}
+
+#[test]
+fn blob_rejects_bad_regions() {
+    let bytes = KeyBlob::new(7, [0x42; 32]).to_bytes();
+
+    assert_eq!(
+        KeyBlob::from_bytes(&[0xFF; BLOB_SIZE]).err(),
+        Some(BlobError::Missing)
+    );
+    assert_eq!(
+        KeyBlob::from_bytes(&bytes[..BLOB_SIZE - 1]).err(),
+        Some(BlobError::Missing)
+    );
+
+    let mut bad = bytes;
+    bad[0] = b'X';
+    assert_eq!(KeyBlob::from_bytes(&bad).err(), Some(BlobError::BadMagic));
+
+    let mut bad = bytes;
+    bad[4] = 2;
+    assert_eq!(
+        KeyBlob::from_bytes(&bad).err(),
+        Some(BlobError::UnsupportedVersion(2))
+    );
+
+    let mut bad = bytes;
+    bad[KEY_OFFSET] ^= 1;
+    assert_eq!(KeyBlob::from_bytes(&bad).err(), Some(BlobError::BadCrc));
+}
@@ -0,0 +1,75 @@
+//! Per-device keys
+//!
+//! Rather than every node sharing the fleet master key, each node is given
+//! a key derived from the master key and its hardware ID, the FICR DEVICEID
+//! on the nRF52. Only the PRX holds the master key, and derives the key of
+//! each device it talks to, so compromising one node doesn't expose the
+//! traffic of the others.
+//!
+//! The device key is HKDF-SHA256 over the master key, salted with the
+//! device ID in little endian byte order.
+
+use crate::FleetKey;
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+const DEVICE_INFO: &[u8] = b"fleet-keys device key v1";
+
+/// The FICR DEVICEID registers of the nRF52 series, low word first
+pub const FICR_DEVICEID: usize = 0x1000_0060;
+
+/// Derive the key of the device with the given hardware ID
+pub fn derive_device_key(master: &FleetKey, device_id: u64) -> FleetKey {
+    let hk = Hkdf::<Sha256>::new(Some(&device_id.to_le_bytes()), master.key());
+    let mut key = [0u8; 32];
+
+    // 32 bytes is always a valid output length for SHA256
+    hk.expand(DEVICE_INFO, &mut key).ok();
+
+    FleetKey { key }
+}
+
+/// Combine the two DEVICEID words of the FICR into a device ID
+pub fn device_id(deviceid: [u32; 2]) -> u64 {
+    u64::from(deviceid[0]) | (u64::from(deviceid[1]) << 32)
+}
+
+#[test]
+fn derive_device_key_vectors() {
+    let master = FleetKey { key: [0x42; 32] };
+
+    // These must never change, or provisioned devices lose contact. They
+    // match RFC 5869 HKDF-SHA256, e.g. as computed by Python's `hmac`.
+    let vectors: [(u64, [u8; 32]); 3] = [
+        (
+            0,
+            [
+                0xAE, 0xA7, 0x02, 0x66, 0xA9, 0x15, 0x9F, 0x08, 0x97, 0x08, 0xB3, 0xDF, 0x8D, 0xD3,
+                0xDA, 0xAC, 0xE9, 0x39, 0xB3, 0xE1, 0xEF, 0x67, 0x09, 0x17, 0x68, 0x53, 0x04, 0x8C,
+                0x13, 0xB9, 0xAE, 0x0E,
+            ],
+        ),
+        (
+            0x0123_4567_89AB_CDEF,
+            [
+                0x50, 0x6D, 0x38, 0xFE, 0xB5, 0x1B, 0x26, 0x26, 0xDE, 0x63, 0x2A, 0x8B, 0xA5, 0x24,
+                0x56, 0x50, 0x90, 0x98, 0x8A, 0x01, 0x25, 0xD3, 0xC8, 0x59, 0x06, 0x72, 0xE9, 0x62,
+                0xE0, 0x23, 0xC0, 0xAB,
+            ],
+        ),
+        (
+            u64::MAX,
+            [
+                0x01, 0x64, 0x57, 0x41, 0x0A, 0x27, 0x54, 0x49, 0xB4, 0xFE, 0x62, 0x42, 0x39, 0x53,
+                0x09, 0xF2, 0xAF, 0xC1, 0xBC, 0x49, 0x39, 0x88, 0xFB, 0x4D, 0xB5, 0x47, 0xB2, 0x79,
+                0xCB, 0x13, 0xA0, 0x69,
+            ],
+        ),
+    ];
+
+    for (id, expected) in vectors.iter() {
+        assert_eq!(derive_device_key(&master, *id).key(), expected);
+    }
+
+    assert_eq!(device_id([0x89AB_CDEF, 0x0123_4567]), 0x0123_4567_89AB_CDEF);
+}
@@ -1,6 +1,9 @@
 #![no_std]
 
+pub mod blob;
+pub mod derive;
 pub mod keys;
+pub mod pairing;
 
 pub struct FleetKey {
     pub(crate) key: [u8; 32],
@@ -0,0 +1,302 @@
+//! The results of pairing, stored in flash
+//!
+//! When a device is paired with the modem, see `fleet_esb::pairing`, both
+//! sides store a record of the pipe, address and key the modem assigned.
+//! Records live in a flash page reserved in `memory.x`, with room for one
+//! record per ESB pipe. A device only uses the first slot, the modem uses
+//! the slot of each paired pipe.
+//!
+//! Each record is laid out as follows, with all integers little endian:
+//!
+//! | offset | size | contents                                   |
+//! | ------ | ---- | ------------------------------------------ |
+//! | 0      | 4    | `RECORD_MAGIC`                             |
+//! | 4      | 1    | `RECORD_VERSION`                           |
+//! | 5      | 1    | pipe                                       |
+//! | 6      | 1    | address prefix of the pipe                 |
+//! | 7      | 1    | reserved, zero                             |
+//! | 8      | 4    | base address of the pipe                   |
+//! | 12     | 8    | device ID                                  |
+//! | 20     | 4    | key ID                                     |
+//! | 24     | 32   | key                                        |
+//! | 56     | 4    | CRC-32 (IEEE) of all of the bytes above    |
+
+use crate::blob::{crc32, BlobError, KeyBlob};
+
+/// The size of a serialized pairing record
+pub const RECORD_SIZE: usize = 60;
+
+/// The first bytes of every pairing record
+pub const RECORD_MAGIC: [u8; 4] = *b"FPAR";
+
+/// The current record layout
+pub const RECORD_VERSION: u8 = 1;
+
+/// The flash page reserved for pairing records, the last page of a 512KiB
+/// part
+pub const PAIRING_PAGE: usize = 0x0007_F000;
+
+/// The number of record slots in the page, one per ESB pipe
+pub const MAX_RECORDS: usize = 8;
+
+// Slots are word aligned, for the flash controller
+const SLOT_SIZE: usize = 64;
+const _: () = assert!(RECORD_SIZE <= SLOT_SIZE && SLOT_SIZE.is_multiple_of(4));
+
+const BASE_OFFSET: usize = 8;
+const DEVICE_OFFSET: usize = 12;
+const ID_OFFSET: usize = 20;
+const KEY_OFFSET: usize = 24;
+const CRC_OFFSET: usize = 56;
+
+/// The pipe, address and key assigned to a paired device
+pub struct PairingRecord {
+    pipe: u8,
+    base: [u8; 4],
+    prefix: u8,
+    device_id: u64,
+    key: KeyBlob,
+}
+
+impl PairingRecord {
+    pub fn new(pipe: u8, base: [u8; 4], prefix: u8, device_id: u64, key: KeyBlob) -> Self {
+        Self {
+            pipe,
+            base,
+            prefix,
+            device_id,
+            key,
+        }
+    }
+
+    pub fn pipe(&self) -> u8 {
+        self.pipe
+    }
+
+    pub fn base(&self) -> &[u8; 4] {
+        &self.base
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn device_id(&self) -> u64 {
+        self.device_id
+    }
+
+    /// The device's key, and its epoch
+    pub fn key(&self) -> &KeyBlob {
+        &self.key
+    }
+
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let mut buf = [0u8; RECORD_SIZE];
+        buf[..4].copy_from_slice(&RECORD_MAGIC);
+        buf[4] = RECORD_VERSION;
+        buf[5] = self.pipe;
+        buf[6] = self.prefix;
+        buf[BASE_OFFSET..DEVICE_OFFSET].copy_from_slice(&self.base);
+        buf[DEVICE_OFFSET..ID_OFFSET].copy_from_slice(&self.device_id.to_le_bytes());
+        buf[ID_OFFSET..KEY_OFFSET].copy_from_slice(&self.key.key_id().to_le_bytes());
+        buf[KEY_OFFSET..CRC_OFFSET].copy_from_slice(self.key.key().key());
+
+        let crc = crc32(&buf[..CRC_OFFSET]);
+        buf[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, BlobError> {
+        if buf.len() < RECORD_SIZE || buf[..4].iter().all(|b| *b == 0xFF) {
+            return Err(BlobError::Missing);
+        }
+        if buf[..4] != RECORD_MAGIC {
+            return Err(BlobError::BadMagic);
+        }
+        if buf[4] != RECORD_VERSION {
+            return Err(BlobError::UnsupportedVersion(buf[4]));
+        }
+
+        let mut crc_buf = [0u8; 4];
+        crc_buf.copy_from_slice(&buf[CRC_OFFSET..RECORD_SIZE]);
+        if crc32(&buf[..CRC_OFFSET]) != u32::from_le_bytes(crc_buf) {
+            return Err(BlobError::BadCrc);
+        }
+
+        let mut base = [0u8; 4];
+        base.copy_from_slice(&buf[BASE_OFFSET..DEVICE_OFFSET]);
+        let mut device_buf = [0u8; 8];
+        device_buf.copy_from_slice(&buf[DEVICE_OFFSET..ID_OFFSET]);
+        let mut id_buf = [0u8; 4];
+        id_buf.copy_from_slice(&buf[ID_OFFSET..KEY_OFFSET]);
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&buf[KEY_OFFSET..CRC_OFFSET]);
+
+        Ok(Self::new(
+            buf[5],
+            base,
+            buf[6],
+            u64::from_le_bytes(device_buf),
+            KeyBlob::new(u32::from_le_bytes(id_buf), key),
+        ))
+    }
+
+    /// Load the record stored at the given address
+    ///
+    /// # Safety
+    ///
+    /// `addr` must point to `RECORD_SIZE` readable bytes, such as flash.
+    pub unsafe fn load(addr: usize) -> Result<Self, BlobError> {
+        let mut buf = [0u8; RECORD_SIZE];
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = core::ptr::read_volatile((addr + i) as *const u8);
+        }
+        Self::from_bytes(&buf)
+    }
+
+    /// Load the record in the given slot of the pairing page
+    #[cfg(feature = "flash")]
+    pub fn from_flash(slot: usize) -> Result<Self, BlobError> {
+        if slot >= MAX_RECORDS {
+            return Err(BlobError::Missing);
+        }
+
+        // The pairing page is reserved in `memory.x`, and always readable
+        unsafe { Self::load(slot_addr(slot)) }
+    }
+}
+
+/// The address of the given record slot
+pub fn slot_addr(slot: usize) -> usize {
+    PAIRING_PAGE + slot * SLOT_SIZE
+}
+
+/// Store a record in the given slot of the pairing page, or clear the slot.
+/// The records in the other slots are kept.
+///
+/// The whole page is erased and written again, which stalls the CPU for
+/// up to 100ms.
+///
+/// # Safety
+///
+/// Nothing else may be using the flash controller (NVMC).
+#[cfg(feature = "flash")]
+pub unsafe fn store(slot: usize, record: Option<&PairingRecord>) {
+    if slot >= MAX_RECORDS {
+        return;
+    }
+
+    let mut page = [[0xFFu8; RECORD_SIZE]; MAX_RECORDS];
+    for (i, bytes) in page.iter_mut().enumerate() {
+        if let Ok(rec) = PairingRecord::from_flash(i) {
+            *bytes = rec.to_bytes();
+        }
+    }
+    page[slot] = match record {
+        Some(rec) => rec.to_bytes(),
+        None => [0xFF; RECORD_SIZE],
+    };
+
+    nvmc::erase_page(PAIRING_PAGE);
+    for (i, bytes) in page.iter().enumerate() {
+        if bytes[..4] != [0xFF; 4] {
+            nvmc::write(slot_addr(i), bytes);
+        }
+    }
+}
+
+/// Just enough of the nRF52 flash controller to write records, without
+/// depending on a particular HAL
+#[cfg(feature = "flash")]
+mod nvmc {
+    use core::ptr::{read_volatile, write_volatile};
+
+    const NVMC: usize = 0x4001_E000;
+    const READY: *const u32 = (NVMC + 0x400) as *const u32;
+    const CONFIG: *mut u32 = (NVMC + 0x504) as *mut u32;
+    const ERASEPAGE: *mut u32 = (NVMC + 0x508) as *mut u32;
+
+    const CONFIG_REN: u32 = 0;
+    const CONFIG_WEN: u32 = 1;
+    const CONFIG_EEN: u32 = 2;
+
+    unsafe fn wait() {
+        while read_volatile(READY) & 1 == 0 {}
+    }
+
+    unsafe fn config(mode: u32) {
+        write_volatile(CONFIG, mode);
+        wait();
+    }
+
+    pub(super) unsafe fn erase_page(addr: usize) {
+        config(CONFIG_EEN);
+        write_volatile(ERASEPAGE, addr as u32);
+        wait();
+        config(CONFIG_REN);
+    }
+
+    /// Write to erased flash, one word at a time. A partial last word is
+    /// padded with 0xFF, which leaves those bytes erased.
+    pub(super) unsafe fn write(addr: usize, data: &[u8]) {
+        config(CONFIG_WEN);
+        for (i, chunk) in data.chunks(4).enumerate() {
+            let mut word = [0xFFu8; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            write_volatile((addr + i * 4) as *mut u32, u32::from_le_bytes(word));
+            wait();
+        }
+        config(CONFIG_REN);
+    }
+}
+
+#[test]
+fn record_roundtrip() {
+    let record = PairingRecord::new(
+        3,
+        [0xC2; 4],
+        0xC4,
+        0x0123_4567_89AB_CDEF,
+        KeyBlob::new(0x12, [0x42; 32]),
+    );
+    let bytes = record.to_bytes();
+    assert_eq!(&bytes[..5], b"FPAR\x01");
+
+    let parsed = PairingRecord::from_bytes(&bytes).unwrap();
+    assert_eq!(parsed.pipe(), 3);
+    assert_eq!(parsed.base(), &[0xC2; 4]);
+    assert_eq!(parsed.prefix(), 0xC4);
+    assert_eq!(parsed.device_id(), 0x0123_4567_89AB_CDEF);
+    assert_eq!(parsed.key().key_id(), 0x12);
+    assert_eq!(parsed.key().epoch(), 2);
+    assert_eq!(parsed.key().key().key(), &[0x42; 32]);
+
+    assert!(slot_addr(MAX_RECORDS) <= PAIRING_PAGE + 4096);
+}
+
+#[test]
+fn record_rejects_bad_regions() {
+    use crate::blob::BLOB_SIZE;
+
+    let bytes = PairingRecord::new(1, [0; 4], 0, 7, KeyBlob::new(1, [0x42; 32])).to_bytes();
+
+    assert_eq!(
+        PairingRecord::from_bytes(&[0xFF; RECORD_SIZE]).err(),
+        Some(BlobError::Missing)
+    );
+
+    // Key blobs aren't pairing records
+    let mut blob = [0u8; RECORD_SIZE];
+    blob[..BLOB_SIZE].copy_from_slice(&KeyBlob::new(1, [0x42; 32]).to_bytes());
+    assert_eq!(
+        PairingRecord::from_bytes(&blob).err(),
+        Some(BlobError::BadMagic)
+    );
+
+    let mut bad = bytes;
+    bad[KEY_OFFSET] ^= 1;
+    assert_eq!(
+        PairingRecord::from_bytes(&bad).err(),
+        Some(BlobError::BadCrc)
+    );
+}
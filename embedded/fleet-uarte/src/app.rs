@@ -1,6 +1,13 @@
-use crate::hal::pac::{Interrupt, NVIC};
+use crate::config::Reconfig;
+use crate::errors::{LineErrorCounts, LineErrors};
+use crate::framed::{self, FrameDecoder, FrameError, Step};
+use crate::hal::{
+    pac::{Interrupt, NVIC},
+    uarte::Baudrate,
+};
 use bbqueue::{ArrayLength, Consumer, Error, GrantR, GrantW, Producer};
 use core::ops::{Deref, DerefMut};
+use serde::{de::DeserializeOwned, Serialize};
 
 pub struct UarteApp<OutgoingLen, IncomingLen>
 where
@@ -9,6 +16,12 @@ where
 {
     pub(crate) outgoing_prod: Producer<'static, OutgoingLen>,
     pub incoming_cons: Consumer<'static, IncomingLen>,
+
+    // Pended to let the UARTE handle new data or space
+    pub(crate) interrupt: Interrupt,
+
+    pub(crate) line_errors: &'static LineErrors,
+    pub(crate) reconfig: &'static Reconfig,
 }
 
 impl<OutgoingLen, IncomingLen> UarteApp<OutgoingLen, IncomingLen>
@@ -17,18 +30,100 @@ where
     IncomingLen: ArrayLength<u8>,
 {
     pub fn read(&mut self) -> Result<UarteGrantR<'static, IncomingLen>, Error> {
-        self.incoming_cons
-            .read()
-            .map(|gr| UarteGrantR { grant_r: gr })
+        self.incoming_cons.read().map(|gr| UarteGrantR {
+            grant_r: gr,
+            interrupt: self.interrupt,
+        })
     }
 
     pub fn write_grant(
         &mut self,
         bytes: usize,
     ) -> Result<UarteGrantW<'static, OutgoingLen>, Error> {
-        self.outgoing_prod
-            .grant_exact(bytes)
-            .map(|gr| UarteGrantW { grant_w: gr })
+        self.outgoing_prod.grant_exact(bytes).map(|gr| UarteGrantW {
+            grant_w: gr,
+            interrupt: self.interrupt,
+        })
+    }
+
+    /// The number of line errors of each kind since startup, see `errors`
+    pub fn line_errors(&self) -> LineErrorCounts {
+        self.line_errors.snapshot()
+    }
+
+    /// Change the baud rate and idle timeout, see `config`. Bytes already
+    /// queued are sent at the old baud rate, so stop writing until
+    /// `reconfigure_pending()` returns false.
+    pub fn reconfigure(&mut self, baudrate: Baudrate, idle_us: u32) {
+        self.reconfig.request(baudrate as u32, idle_us);
+        NVIC::pend(self.interrupt);
+    }
+
+    pub fn reconfigure_pending(&self) -> bool {
+        self.reconfig.pending()
+    }
+
+    /// Send a message, see `framed`
+    pub fn send_msg<T: Serialize + ?Sized>(&mut self, msg: &T) -> Result<(), FrameError> {
+        let max_len = framed::max_encoded_len(msg)?;
+        if max_len > OutgoingLen::to_usize() {
+            return Err(FrameError::TooLarge);
+        }
+
+        let mut wgr = self
+            .write_grant(max_len)
+            .map_err(|_| FrameError::QueueFull)?;
+        let used = postcard::to_slice_cobs(msg, &mut wgr)
+            .map_err(|_| FrameError::Serialize)?
+            .len();
+        wgr.commit(used);
+        Ok(())
+    }
+
+    /// Receive the next message, if one has arrived, see `framed`. A frame
+    /// that can't be decoded is dropped, and its error returned.
+    pub fn recv_msg<T, N>(&mut self, decoder: &mut FrameDecoder<N>) -> Result<Option<T>, FrameError>
+    where
+        T: DeserializeOwned,
+        N: generic_array::ArrayLength<u8>,
+    {
+        match self.recv_with(decoder, |_, msg| postcard::from_bytes(msg)) {
+            Ok(Some(Ok(msg))) => Ok(Some(msg)),
+            Ok(Some(Err(_))) => Err(FrameError::Deserialize),
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Like `recv_msg`, but hands the serialized message to `fun`, e.g. to
+    /// try more than one message type. `fun` can send replies with the app
+    /// it is given.
+    pub fn recv_with<N, F, R>(
+        &mut self,
+        decoder: &mut FrameDecoder<N>,
+        mut fun: F,
+    ) -> Result<Option<R>, FrameError>
+    where
+        N: generic_array::ArrayLength<u8>,
+        F: FnMut(&mut Self, &[u8]) -> R,
+    {
+        // A frame may span more than one read grant
+        while let Ok(mut rgr) = self.read() {
+            let (used, result) = match decoder.feed(&mut rgr) {
+                (used, Step::Pending) => (used, None),
+                (used, Step::OverFull) => (used, Some(Err(FrameError::TooLarge))),
+                (used, Step::Frame(frame)) => {
+                    (used, Some(framed::decode(frame).map(|msg| fun(self, msg))))
+                }
+            };
+            rgr.release(used);
+
+            if let Some(result) = result {
+                return result.map(Some);
+            }
+        }
+
+        Ok(None)
     }
 }
 
@@ -42,6 +137,7 @@ where
     N: ArrayLength<u8>,
 {
     grant_w: GrantW<'a, N>,
+    interrupt: Interrupt,
 }
 
 /// A read grant for a single Uarte
@@ -54,6 +150,7 @@ where
     N: ArrayLength<u8>,
 {
     grant_r: GrantR<'a, N>,
+    interrupt: Interrupt,
 }
 
 impl<'a, N> Deref for UarteGrantW<'a, N>
@@ -107,7 +204,7 @@ where
     pub fn commit(self, used: usize) {
         // Commit the header + Uarte
         self.grant_w.commit(used);
-        NVIC::pend(Interrupt::UARTE0_UART0);
+        NVIC::pend(self.interrupt);
     }
 }
 
@@ -120,6 +217,6 @@ where
     /// Note: The full Uarte is always released
     pub fn release(self, used: usize) {
         self.grant_r.release(used);
-        NVIC::pend(Interrupt::UARTE0_UART0);
+        NVIC::pend(self.interrupt);
     }
 }
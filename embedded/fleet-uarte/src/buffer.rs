@@ -1,13 +1,17 @@
 use crate::Error;
 use bbqueue::{ArrayLength, BBBuffer};
 
-use crate::hal::pac::UARTE0;
 use crate::hal::ppi::{ConfigurablePpi, Ppi};
+use crate::hal::target_constants::EASY_DMA_SIZE;
 use crate::hal::timer::Instance as TimerInstance;
 use crate::hal::uarte::{Baudrate, Parity, Pins};
 use crate::{
     app::UarteApp,
+    config::Reconfig,
+    errors::LineErrors,
     irq::{UarteIrq, UarteTimer},
+    state::UarteState,
+    Instance as UarteInstance,
 };
 use core::sync::atomic::AtomicBool;
 
@@ -23,18 +27,21 @@ where
     pub txd_buf: BBBuffer<OutgoingLen>,
     pub rxd_buf: BBBuffer<IncomingLen>,
     pub timeout_flag: AtomicBool,
+    pub line_errors: LineErrors,
+    pub reconfig: Reconfig,
 }
 
-pub struct UarteParts<OutgoingLen, IncomingLen, Timer, Channel>
+pub struct UarteParts<OutgoingLen, IncomingLen, Timer, Channel, Uarte>
 where
     OutgoingLen: ArrayLength<u8>,
     IncomingLen: ArrayLength<u8>,
     Timer: TimerInstance,
     Channel: Ppi + ConfigurablePpi,
+    Uarte: UarteInstance,
 {
     pub app: UarteApp<OutgoingLen, IncomingLen>,
     pub timer: UarteTimer<Timer>,
-    pub irq: UarteIrq<OutgoingLen, IncomingLen, Channel>,
+    pub irq: UarteIrq<OutgoingLen, IncomingLen, Channel, Uarte>,
 }
 
 impl<OutgoingLen, IncomingLen> UarteBuffer<OutgoingLen, IncomingLen>
@@ -42,17 +49,27 @@ where
     OutgoingLen: ArrayLength<u8>,
     IncomingLen: ArrayLength<u8>,
 {
-    pub fn try_split<Timer: TimerInstance, Channel: Ppi + ConfigurablePpi>(
+    pub fn try_split<Timer: TimerInstance, Channel: Ppi + ConfigurablePpi, Uarte: UarteInstance>(
         &'static self,
         pins: Pins,
         parity: Parity,
         baudrate: Baudrate,
         timer: Timer,
         mut ppi_ch: Channel,
-        uarte: UARTE0,
+        uarte: Uarte,
         rx_block_size: usize,
         idle_us: u32,
-    ) -> Result<UarteParts<OutgoingLen, IncomingLen, Timer, Channel>, Error> {
+    ) -> Result<UarteParts<OutgoingLen, IncomingLen, Timer, Channel, Uarte>, Error> {
+        // Each block is received with a single DMA transfer
+        if rx_block_size == 0 || rx_block_size > EASY_DMA_SIZE {
+            return Err(Error::BadBlockSize);
+        }
+
+        // Hardware flow control is used when both RTS and CTS are given
+        if pins.rts.is_some() != pins.cts.is_some() {
+            return Err(Error::BadPins);
+        }
+
         let (txd_prod, txd_cons) = self.txd_buf.try_split().map_err(|_| Error::Todo)?;
         let (rxd_prod, rxd_cons) = self.rxd_buf.try_split().map_err(|_| Error::Todo)?;
 
@@ -74,19 +91,23 @@ where
         let mut utim = UarteTimer {
             timer,
             timeout_flag: &self.timeout_flag,
+            interrupt: Uarte::INTERRUPT,
+            reconfig: &self.reconfig,
         };
 
         ppi_ch.set_task_endpoint(unsafe { &(&*hw_timer).tasks_clear });
         ppi_ch.set_event_endpoint(&uarte.events_rxdrdy);
 
         let mut uirq = UarteIrq {
-            incoming_prod: rxd_prod,
-            outgoing_cons: txd_cons,
-            timeout_flag: &self.timeout_flag,
-            rx_grant: None,
-            tx_grant: None,
+            state: UarteState::new(
+                txd_cons,
+                rxd_prod,
+                &self.timeout_flag,
+                rx_block_size,
+                &self.line_errors,
+                &self.reconfig,
+            ),
             uarte,
-            block_size: rx_block_size,
             ppi_ch,
         };
 
@@ -98,6 +119,9 @@ where
             app: UarteApp {
                 outgoing_prod: txd_prod,
                 incoming_cons: rxd_cons,
+                interrupt: Uarte::INTERRUPT,
+                line_errors: &self.line_errors,
+                reconfig: &self.reconfig,
             },
             irq: uirq,
             timer: utim,
@@ -0,0 +1,59 @@
+//! Changing the UARTE settings at runtime
+//!
+//! `UarteApp::reconfigure` only records the new settings. The timer picks up
+//! a new idle timeout on its next tick, and `UarteIrq` changes the baud rate
+//! once everything queued for sending has been sent, between two receptions.
+
+use core::sync::atomic::{AtomicU32, Ordering::SeqCst};
+
+// Neither a valid BAUDRATE value, nor a valid timeout
+const NONE: u32 = 0;
+
+/// Settings requested by the app, not yet applied
+pub struct Reconfig {
+    baudrate: AtomicU32,
+    idle_us: AtomicU32,
+}
+
+impl Reconfig {
+    pub const fn new() -> Self {
+        Self {
+            baudrate: AtomicU32::new(NONE),
+            idle_us: AtomicU32::new(NONE),
+        }
+    }
+
+    /// `baudrate` is the raw value of the BAUDRATE register
+    pub(crate) fn request(&self, baudrate: u32, idle_us: u32) {
+        self.baudrate.store(baudrate, SeqCst);
+        self.idle_us.store(idle_us.max(1), SeqCst);
+    }
+
+    pub(crate) fn baudrate_pending(&self) -> bool {
+        self.baudrate.load(SeqCst) != NONE
+    }
+
+    pub(crate) fn take_baudrate(&self) -> Option<u32> {
+        match self.baudrate.swap(NONE, SeqCst) {
+            NONE => None,
+            baudrate => Some(baudrate),
+        }
+    }
+
+    pub(crate) fn take_idle_us(&self) -> Option<u32> {
+        match self.idle_us.swap(NONE, SeqCst) {
+            NONE => None,
+            idle_us => Some(idle_us),
+        }
+    }
+
+    pub(crate) fn pending(&self) -> bool {
+        self.baudrate_pending() || self.idle_us.load(SeqCst) != NONE
+    }
+}
+
+impl Default for Reconfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
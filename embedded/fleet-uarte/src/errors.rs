@@ -0,0 +1,94 @@
+//! UART line errors
+//!
+//! The UARTE reports overrun, parity, framing and break conditions in
+//! ERRORSRC. `UarteIrq` counts them in the `LineErrors` of its
+//! `UarteBuffer`, available with `UarteApp::line_errors()`. The counters
+//! wrap on overflow, so consumers should look at the difference between two
+//! snapshots, rather than the absolute values.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// ERRORSRC bits
+pub(crate) const OVERRUN: u32 = 1 << 0;
+const PARITY: u32 = 1 << 1;
+const FRAMING: u32 = 1 << 2;
+const BREAK: u32 = 1 << 3;
+
+/// Line error counters, written by the UARTE interrupt
+pub struct LineErrors {
+    overrun: AtomicU32,
+    parity: AtomicU32,
+    framing: AtomicU32,
+    break_condition: AtomicU32,
+}
+
+impl LineErrors {
+    pub const fn new() -> Self {
+        Self {
+            overrun: AtomicU32::new(0),
+            parity: AtomicU32::new(0),
+            framing: AtomicU32::new(0),
+            break_condition: AtomicU32::new(0),
+        }
+    }
+
+    /// Count the errors flagged in an ERRORSRC value
+    pub(crate) fn record(&self, errorsrc: u32) {
+        let counters = [
+            (OVERRUN, &self.overrun),
+            (PARITY, &self.parity),
+            (FRAMING, &self.framing),
+            (BREAK, &self.break_condition),
+        ];
+
+        for (bit, counter) in counters.iter() {
+            if errorsrc & bit != 0 {
+                // Only the UARTE interrupt writes the counters, so a load
+                // and store is enough
+                let count = counter.load(Ordering::Relaxed);
+                counter.store(count.wrapping_add(1), Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> LineErrorCounts {
+        LineErrorCounts {
+            overrun: self.overrun.load(Ordering::Relaxed),
+            parity: self.parity.load(Ordering::Relaxed),
+            framing: self.framing.load(Ordering::Relaxed),
+            break_condition: self.break_condition.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for LineErrors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineErrorCounts {
+    /// Bytes lost because the RX FIFO was full, e.g. while no RX buffer
+    /// was available
+    pub overrun: u32,
+
+    /// Bytes received with the wrong parity
+    pub parity: u32,
+
+    /// Bytes without a valid stop bit, usually a baud rate mismatch
+    pub framing: u32,
+
+    /// The line was held low for longer than a byte
+    pub break_condition: u32,
+}
+
+impl LineErrorCounts {
+    /// The number of errors of any kind
+    pub fn total(&self) -> u32 {
+        self.overrun
+            .wrapping_add(self.parity)
+            .wrapping_add(self.framing)
+            .wrapping_add(self.break_condition)
+    }
+}
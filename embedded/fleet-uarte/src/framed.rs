@@ -0,0 +1,231 @@
+//! COBS framed postcard messages, see `UarteApp::send_msg` and
+//! `UarteApp::recv_msg`
+//!
+//! Each message is serialized with postcard, COBS encoded, and terminated
+//! with a zero byte. Messages are encoded straight into a write grant of the
+//! outgoing queue. Received frames are decoded in place in the incoming
+//! queue, unless they span the end of the queue, or more than one read
+//! grant, in which case they are collected in a `FrameDecoder` first.
+
+use generic_array::{ArrayLength, GenericArray};
+use postcard::flavors::SerFlavor;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// There isn't room for the message in the outgoing queue right now
+    QueueFull,
+
+    /// The message is larger than the whole outgoing queue, or a received
+    /// frame was larger than the `FrameDecoder`, and was dropped
+    TooLarge,
+
+    /// A received frame isn't valid COBS, e.g. due to lost bytes
+    Cobs,
+
+    /// The message could not be serialized, or a received frame could not
+    /// be deserialized as the expected type
+    Serialize,
+    Deserialize,
+}
+
+/// Collects received frames that can't be decoded in place
+pub struct FrameDecoder<N: ArrayLength<u8>> {
+    buf: GenericArray<u8, N>,
+    idx: usize,
+
+    // Drop everything up to the end of the frame
+    overfull: bool,
+}
+
+pub(crate) enum Step<'a> {
+    /// The input ended before the end of the frame
+    Pending,
+
+    /// A complete frame, still COBS encoded, without its terminator
+    Frame(&'a mut [u8]),
+
+    /// The end of a frame that didn't fit
+    OverFull,
+}
+
+impl<N: ArrayLength<u8>> FrameDecoder<N> {
+    pub fn new() -> Self {
+        Self {
+            buf: GenericArray::default(),
+            idx: 0,
+            overfull: false,
+        }
+    }
+
+    /// Look for the end of a frame in `input`. Returns the number of bytes
+    /// of the input used, which includes the terminator of the frame.
+    pub(crate) fn feed<'a>(&'a mut self, input: &'a mut [u8]) -> (usize, Step<'a>) {
+        let end = match input.iter().position(|b| *b == 0) {
+            Some(end) => end,
+            None => {
+                if !self.overfull && self.idx + input.len() <= N::to_usize() {
+                    self.buf[self.idx..self.idx + input.len()].copy_from_slice(input);
+                    self.idx += input.len();
+                } else {
+                    self.overfull = true;
+                    self.idx = 0;
+                }
+                return (input.len(), Step::Pending);
+            }
+        };
+
+        let start = self.idx;
+        self.idx = 0;
+
+        if core::mem::replace(&mut self.overfull, false) {
+            (end + 1, Step::OverFull)
+        } else if start == 0 && end == 0 {
+            // Nothing between two terminators
+            (1, Step::Pending)
+        } else if start == 0 {
+            // The whole frame is in the input, no need to copy it
+            (end + 1, Step::Frame(&mut input[..end]))
+        } else if start + end <= N::to_usize() {
+            self.buf[start..start + end].copy_from_slice(&input[..end]);
+            (end + 1, Step::Frame(&mut self.buf[..start + end]))
+        } else {
+            (end + 1, Step::OverFull)
+        }
+    }
+}
+
+impl<N: ArrayLength<u8>> Default for FrameDecoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Counts the bytes of a serialized message, without storing them
+struct Size(usize);
+
+impl SerFlavor for Size {
+    type Output = usize;
+
+    fn try_extend(&mut self, data: &[u8]) -> Result<(), ()> {
+        self.0 += data.len();
+        Ok(())
+    }
+
+    fn try_push(&mut self, _data: u8) -> Result<(), ()> {
+        self.0 += 1;
+        Ok(())
+    }
+
+    fn release(self) -> Result<usize, ()> {
+        Ok(self.0)
+    }
+}
+
+/// The number of bytes needed to send `msg`, including the COBS overhead
+/// and the terminator
+pub fn max_encoded_len<T: Serialize + ?Sized>(msg: &T) -> Result<usize, FrameError> {
+    let len = postcard::serialize_with_flavor(msg, Size(0)).map_err(|_| FrameError::Serialize)?;
+    Ok(postcard_cobs::max_encoding_length(len) + 1)
+}
+
+/// Decode a frame from `feed` in place, returning the serialized message
+pub(crate) fn decode(frame: &mut [u8]) -> Result<&[u8], FrameError> {
+    let len = postcard_cobs::decode_in_place(frame).map_err(|_| FrameError::Cobs)?;
+    Ok(&frame[..len])
+}
+
+#[cfg(test)]
+use generic_array::typenum::consts::{U16, U64};
+
+#[cfg(test)]
+type Decoded = Option<Result<(u32, u8), FrameError>>;
+
+#[cfg(test)]
+fn decode_all<N: ArrayLength<u8>>(
+    decoder: &mut FrameDecoder<N>,
+    mut input: &mut [u8],
+) -> ([Decoded; 4], usize) {
+    let mut results = [None; 4];
+    let mut count = 0;
+    while !input.is_empty() {
+        let (used, step) = decoder.feed(input);
+        let result = match step {
+            Step::Pending => None,
+            Step::OverFull => Some(Err(FrameError::TooLarge)),
+            Step::Frame(frame) => Some(
+                decode(frame)
+                    .and_then(|msg| postcard::from_bytes(msg).map_err(|_| FrameError::Deserialize)),
+            ),
+        };
+        if let Some(result) = result {
+            results[count] = Some(result);
+            count += 1;
+        }
+        input = &mut input[used..];
+    }
+    (results, count)
+}
+
+#[test]
+fn frames_split_across_inputs() {
+    let mut buf = [0u8; 32];
+    let len = postcard::to_slice_cobs(&(1_000_000u32, 7u8), &mut buf)
+        .unwrap()
+        .len();
+    assert!(len <= max_encoded_len(&(1_000_000u32, 7u8)).unwrap());
+
+    let mut decoder: FrameDecoder<U16> = FrameDecoder::new();
+
+    // In one piece, and split at every possible point
+    for split in 0..len {
+        let mut frame = buf;
+        let (first, second) = frame[..len].split_at_mut(split);
+        assert_eq!(decode_all(&mut decoder, first).1, 0);
+
+        let (results, count) = decode_all(&mut decoder, second);
+        assert_eq!(count, 1);
+        assert_eq!(results[0], Some(Ok((1_000_000, 7))));
+    }
+}
+
+#[test]
+fn frames_report_errors() {
+    let mut decoder: FrameDecoder<U16> = FrameDecoder::new();
+
+    // Too large for the decoder when split, but each part is still framed
+    let mut input = [0u8; 64];
+    let mut len = 0;
+    for msg in &[(1u32, 2u8), (3, 4)] {
+        len += postcard::to_slice_cobs(msg, &mut input[len..])
+            .unwrap()
+            .len();
+    }
+    let mut long = [0x01u8; 40];
+    long[39] = 0;
+
+    assert_eq!(decode_all(&mut decoder, &mut long[..20]).1, 0);
+    let (results, count) = decode_all(&mut decoder, &mut long[20..]);
+    assert_eq!(count, 1);
+    assert_eq!(results[0], Some(Err(FrameError::TooLarge)));
+
+    // The decoder recovers, and empty frames are skipped
+    let mut with_empty = [0u8; 65];
+    with_empty[1..len + 1].copy_from_slice(&input[..len]);
+    let (results, count) = decode_all(&mut decoder, &mut with_empty[..len + 1]);
+    assert_eq!(count, 2);
+    assert_eq!(results[0], Some(Ok((1, 2))));
+    assert_eq!(results[1], Some(Ok((3, 4))));
+
+    // A frame of the wrong type
+    let mut short = [0u8; 8];
+    let len = postcard::to_slice_cobs(&1u8, &mut short).unwrap().len();
+    let (results, _) = decode_all(&mut decoder, &mut short[..len]);
+    assert_eq!(results[0], Some(Err(FrameError::Deserialize)));
+
+    // Lost bytes make the COBS invalid
+    let mut bad = [0x05u8, 0x01, 0x00];
+    let mut large: FrameDecoder<U64> = FrameDecoder::new();
+    let (results, _) = decode_all(&mut large, &mut bad);
+    assert_eq!(results[0], Some(Err(FrameError::Cobs)));
+}
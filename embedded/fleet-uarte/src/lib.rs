@@ -1,4 +1,6 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
+// Parts of `config` and `framed` are only used by the hardware modules
+#![cfg_attr(not(feature = "uarte"), allow(dead_code))]
 
 #[cfg(feature = "52810")]
 use nrf52810_hal as hal;
@@ -7,11 +9,50 @@ use nrf52832_hal as hal;
 #[cfg(feature = "52840")]
 use nrf52840_hal as hal;
 
+#[cfg(feature = "uarte")]
+use hal::pac::Interrupt;
+
+#[cfg(feature = "uarte")]
 pub mod app;
+#[cfg(feature = "uarte")]
 pub mod buffer;
+pub mod config;
+pub mod errors;
+pub mod framed;
+#[cfg(feature = "uarte")]
 pub mod irq;
+pub mod regs;
+pub mod state;
+
+#[cfg(feature = "std")]
+pub mod sim;
+
+/// A UARTE peripheral, and the interrupt it raises
+#[cfg(feature = "uarte")]
+pub trait Instance: hal::uarte::Instance {
+    const INTERRUPT: Interrupt;
+}
+
+#[cfg(feature = "uarte")]
+impl Instance for hal::pac::UARTE0 {
+    const INTERRUPT: Interrupt = Interrupt::UARTE0_UART0;
+}
+
+#[cfg(feature = "52840")]
+impl Instance for hal::pac::UARTE1 {
+    const INTERRUPT: Interrupt = Interrupt::UARTE1;
+}
 
 #[derive(Debug)]
 pub enum Error {
     Todo,
+
+    /// The RX block size is zero, or larger than the chip's `EASY_DMA_SIZE`
+    BadBlockSize,
+
+    /// Only one of RTS and CTS was given. Flow control needs both.
+    BadPins,
+
+    /// A DMA buffer is larger than the chip's `EASY_DMA_SIZE`
+    BufferTooLarge,
 }
@@ -0,0 +1,221 @@
+//! Access to the UARTE registers used by `UarteState`
+//!
+//! On hardware, `UarteRegs` is implemented for every UARTE peripheral. On a
+//! host, the `sim` module (with the `std` feature) provides a simulated UARTE
+//! instead, so the state machine can be tested without a chip.
+
+use crate::Error;
+
+/// The UARTE events handled by the state machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    EndRx,
+    EndTx,
+    RxDrdy,
+    Error,
+    TxStopped,
+    RxStarted,
+}
+
+pub trait UarteRegs {
+    /// The largest buffer that can be used for a single DMA transfer
+    const EASY_DMA_SIZE: usize;
+
+    fn event(&self, event: Event) -> bool;
+
+    fn clear_event(&self, event: Event);
+
+    /// The sources of any line errors, in ERRORSRC format
+    fn errorsrc(&self) -> u32;
+
+    fn clear_errorsrc(&self, errorsrc: u32);
+
+    /// The number of bytes received by the last read transaction
+    fn rx_amount(&self) -> usize;
+
+    /// Start a read transaction into `rx_buffer`. The buffer is written by
+    /// DMA until ENDRX, so it must stay valid until then.
+    fn start_read(&self, rx_buffer: &mut [u8]) -> Result<(), Error>;
+
+    /// Queue the buffer of the next read transaction, which starts as soon
+    /// as the current one ends. Only valid once the current one has started.
+    fn queue_read(&self, rx_buffer: &mut [u8]) -> Result<(), Error>;
+
+    /// Don't start another read transaction when the current one ends
+    fn stop_queue(&self);
+
+    /// Stop an unfinished read transaction and flush the FIFO to its buffer.
+    /// Any queued transaction is not started. ENDRX is set on return.
+    fn cancel_read(&self);
+
+    /// Start sending `tx_buffer`, which must stay valid until ENDTX
+    fn start_write(&self, tx_buffer: &[u8]) -> Result<(), Error>;
+
+    /// `baudrate` is the raw value of the BAUDRATE register
+    fn set_baudrate(&self, baudrate: u32);
+}
+
+#[cfg(feature = "uarte")]
+mod uarte {
+    use super::{Event, UarteRegs};
+    use crate::hal::target_constants::EASY_DMA_SIZE;
+    use crate::{Error, Instance as UarteInstance};
+    use core::sync::atomic::{compiler_fence, Ordering::SeqCst};
+
+    impl<T: UarteInstance> UarteRegs for T {
+        const EASY_DMA_SIZE: usize = EASY_DMA_SIZE;
+
+        fn event(&self, event: Event) -> bool {
+            let bits = match event {
+                Event::EndRx => self.events_endrx.read().bits(),
+                Event::EndTx => self.events_endtx.read().bits(),
+                Event::RxDrdy => self.events_rxdrdy.read().bits(),
+                Event::Error => self.events_error.read().bits(),
+                Event::TxStopped => self.events_txstopped.read().bits(),
+                Event::RxStarted => self.events_rxstarted.read().bits(),
+            };
+            bits != 0
+        }
+
+        fn clear_event(&self, event: Event) {
+            match event {
+                Event::EndRx => self.events_endrx.write(|w| w),
+                Event::EndTx => self.events_endtx.write(|w| w),
+                Event::RxDrdy => self.events_rxdrdy.write(|w| w),
+                Event::Error => self.events_error.write(|w| w),
+                Event::TxStopped => self.events_txstopped.write(|w| w),
+                Event::RxStarted => self.events_rxstarted.write(|w| w),
+            }
+        }
+
+        fn errorsrc(&self) -> u32 {
+            self.errorsrc.read().bits()
+        }
+
+        fn clear_errorsrc(&self, errorsrc: u32) {
+            // The bits are cleared by writing 1 to them
+            self.errorsrc.write(|w| unsafe { w.bits(errorsrc) });
+        }
+
+        fn rx_amount(&self) -> usize {
+            self.rxd.amount.read().bits() as usize
+        }
+
+        fn start_read(&self, rx_buffer: &mut [u8]) -> Result<(), Error> {
+            set_rx_buffer(self, rx_buffer)?;
+
+            // Start UARTE Receive transaction
+            self.tasks_startrx.write(|w|
+                // `1` is a valid value to write to task registers.
+                unsafe { w.bits(1) });
+
+            Ok(())
+        }
+
+        fn queue_read(&self, rx_buffer: &mut [u8]) -> Result<(), Error> {
+            set_rx_buffer(self, rx_buffer)?;
+            self.shorts.write(|w| w.endrx_startrx().enabled());
+            Ok(())
+        }
+
+        fn stop_queue(&self) {
+            self.shorts.write(|w| w.endrx_startrx().disabled());
+        }
+
+        fn cancel_read(&self) {
+            self.stop_queue();
+            self.events_rxto.write(|w| w);
+
+            // Stop reception
+            self.tasks_stoprx.write(|w| unsafe { w.bits(1) });
+
+            // Wait for the reception to have stopped
+            while self.events_rxto.read().bits() == 0 {}
+
+            // Reset the event flag
+            self.events_rxto.write(|w| w);
+
+            // Ask UART to flush FIFO to DMA buffer
+            self.tasks_flushrx.write(|w| unsafe { w.bits(1) });
+
+            // Wait for the flush to complete.
+            while self.events_endrx.read().bits() == 0 {}
+
+            // The event flag itself is later reset by the state machine
+        }
+
+        fn start_write(&self, tx_buffer: &[u8]) -> Result<(), Error> {
+            if tx_buffer.len() > EASY_DMA_SIZE {
+                return Err(Error::BufferTooLarge);
+            }
+
+            // Conservative compiler fence to prevent optimizations that do not
+            // take in to account actions by DMA. The fence has been placed here,
+            // before any DMA action has started
+            compiler_fence(SeqCst);
+
+            // Reset the events.
+            self.events_endtx.reset();
+            self.events_txstopped.reset();
+
+            // Set up the DMA write
+            self.txd.ptr.write(|w|
+                // We're giving the register a pointer to the stack. Since we're
+                // waiting for the UARTE transaction to end before this stack pointer
+                // becomes invalid, there's nothing wrong here.
+                //
+                // The PTR field is a full 32 bits wide and accepts the full range
+                // of values.
+                unsafe { w.ptr().bits(tx_buffer.as_ptr() as u32) });
+            self.txd.maxcnt.write(|w|
+                // We're giving it the length of the buffer, so no danger of
+                // accessing invalid memory. We have verified that the length of the
+                // buffer fits in `EASY_DMA_SIZE`, the width of the MAXCNT field on
+                // this chip, so the cast is also fine.
+                unsafe { w.maxcnt().bits(tx_buffer.len() as _) });
+
+            // Start UARTE Transmit transaction
+            self.tasks_starttx.write(|w|
+                // `1` is a valid value to write to task registers.
+                unsafe { w.bits(1) });
+
+            Ok(())
+        }
+
+        fn set_baudrate(&self, baudrate: u32) {
+            self.baudrate.write(|w| unsafe { w.bits(baudrate) });
+        }
+    }
+
+    fn set_rx_buffer<T: UarteInstance>(uarte: &T, rx_buffer: &mut [u8]) -> Result<(), Error> {
+        if rx_buffer.len() > EASY_DMA_SIZE {
+            return Err(Error::BufferTooLarge);
+        }
+
+        // NOTE: RAM slice check is not necessary, as a mutable slice can only be
+        // built from data located in RAM
+
+        // Conservative compiler fence to prevent optimizations that do not
+        // take in to account actions by DMA. The fence has been placed here,
+        // before any DMA action has started
+        compiler_fence(SeqCst);
+
+        // Set up the DMA read
+        uarte.rxd.ptr.write(|w|
+            // We're giving the register a pointer to the stack. Since we're
+            // waiting for the UARTE transaction to end before this stack pointer
+            // becomes invalid, there's nothing wrong here.
+            //
+            // The PTR field is a full 32 bits wide and accepts the full range
+            // of values.
+            unsafe { w.ptr().bits(rx_buffer.as_ptr() as u32) });
+        uarte.rxd.maxcnt.write(|w|
+            // We're giving it the length of the buffer, so no danger of
+            // accessing invalid memory. We have verified that the length of the
+            // buffer fits in `EASY_DMA_SIZE`, the width of the MAXCNT field on
+            // this chip, so the cast is also fine.
+            unsafe { w.maxcnt().bits(rx_buffer.len() as _) });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,514 @@
+//! A simulated UARTE, for testing without hardware
+//!
+//! `SimUarte` implements `UarteRegs` the way the peripheral behaves: read and
+//! write transactions DMA to and from the buffers they were given, and set
+//! the same events as they progress. The test decides when bytes arrive,
+//! when a transmission completes and when line errors happen, and calls
+//! `UarteState::interrupt` whenever the interrupt would run.
+//!
+//! Unlike the peripheral, there is no RX FIFO. Bytes that arrive while no
+//! read transaction is running are lost immediately, and counted as an
+//! overrun.
+
+use std::{cell::Cell, cell::RefCell, vec::Vec};
+
+use crate::{
+    regs::{Event, UarteRegs},
+    Error,
+};
+
+// ERRORSRC bit
+const OVERRUN: u32 = 1 << 0;
+
+const NUM_EVENTS: usize = 6;
+
+#[derive(Clone, Copy)]
+struct Transfer {
+    ptr: *mut u8,
+    len: usize,
+    count: usize,
+}
+
+#[derive(Default)]
+pub struct SimUarte {
+    events: [Cell<bool>; NUM_EVENTS],
+    errorsrc: Cell<u32>,
+    amount: Cell<usize>,
+
+    // RXD.PTR and RXD.MAXCNT, used by the next read transaction
+    rxd: Cell<Option<(*mut u8, usize)>>,
+
+    // The ENDRX_STARTRX short
+    queued: Cell<bool>,
+    rx: Cell<Option<Transfer>>,
+    tx: Cell<Option<(*const u8, usize)>>,
+    sent: RefCell<Vec<u8>>,
+    in_flight: RefCell<Vec<u8>>,
+    baudrate: Cell<Option<u32>>,
+}
+
+impl SimUarte {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes arrive on the RX line
+    pub fn receive(&self, bytes: &[u8]) {
+        for byte in bytes {
+            let mut transfer = match self.rx.get() {
+                Some(transfer) => transfer,
+                None => {
+                    self.line_error(OVERRUN);
+                    continue;
+                }
+            };
+
+            // Like the DMA, this doesn't care who else is using the buffer
+            unsafe { transfer.ptr.add(transfer.count).write(*byte) };
+            transfer.count += 1;
+            self.set(Event::RxDrdy);
+
+            if transfer.count == transfer.len {
+                self.end_rx(transfer.count);
+                if self.queued.get() {
+                    // The peripheral would happily receive into the same
+                    // buffer again
+                    let next = self.rxd.get().map(|(ptr, _)| ptr);
+                    assert_ne!(next, Some(transfer.ptr), "no block queued in time");
+                    self.start_rx();
+                }
+            } else {
+                self.rx.set(Some(transfer));
+            }
+        }
+    }
+
+    /// Bytes that arrive after the interrupt has read the events, but before
+    /// the next read is cancelled
+    pub fn in_flight(&self, bytes: &[u8]) {
+        self.in_flight.borrow_mut().extend_from_slice(bytes);
+    }
+
+    /// End the current read transaction, as a STOPRX from elsewhere would
+    pub fn stop_rx(&self) {
+        let count = self.rx.get().map_or(0, |transfer| transfer.count);
+        self.end_rx(count);
+    }
+
+    /// Overwrite RXD.AMOUNT, e.g. with a value left over from an earlier
+    /// transaction
+    pub fn set_amount(&self, amount: usize) {
+        self.amount.set(amount);
+    }
+
+    /// Finish sending the current write transaction, if any
+    pub fn transmit(&self) -> bool {
+        match self.tx.take() {
+            Some((ptr, len)) => {
+                let bytes = unsafe { core::slice::from_raw_parts(ptr, len) };
+                self.sent.borrow_mut().extend_from_slice(bytes);
+                self.set(Event::EndTx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Everything sent so far, since the last call
+    pub fn take_sent(&self) -> Vec<u8> {
+        self.sent.replace(Vec::new())
+    }
+
+    /// A line error, in ERRORSRC format
+    pub fn line_error(&self, errorsrc: u32) {
+        self.errorsrc.set(self.errorsrc.get() | errorsrc);
+        self.set(Event::Error);
+    }
+
+    pub fn receiving(&self) -> bool {
+        self.rx.get().is_some()
+    }
+
+    pub fn sending(&self) -> bool {
+        self.tx.get().is_some()
+    }
+
+    /// The last value written to BAUDRATE
+    pub fn baudrate(&self) -> Option<u32> {
+        self.baudrate.get()
+    }
+
+    fn set(&self, event: Event) {
+        self.events[event as usize].set(true);
+    }
+
+    fn start_rx(&self) {
+        let (ptr, len) = self.rxd.get().expect("STARTRX without RXD.PTR");
+        self.rx.set(Some(Transfer { ptr, len, count: 0 }));
+        self.set(Event::RxStarted);
+    }
+
+    fn end_rx(&self, count: usize) {
+        self.rx.set(None);
+        self.amount.set(count);
+        self.set(Event::EndRx);
+    }
+
+    fn set_rx_buffer(&self, rx_buffer: &mut [u8]) -> Result<(), Error> {
+        if rx_buffer.len() > Self::EASY_DMA_SIZE {
+            return Err(Error::BufferTooLarge);
+        }
+        self.rxd
+            .set(Some((rx_buffer.as_mut_ptr(), rx_buffer.len())));
+        Ok(())
+    }
+}
+
+impl UarteRegs for SimUarte {
+    // As on the nRF52832
+    const EASY_DMA_SIZE: usize = 255;
+
+    fn event(&self, event: Event) -> bool {
+        self.events[event as usize].get()
+    }
+
+    fn clear_event(&self, event: Event) {
+        self.events[event as usize].set(false);
+    }
+
+    fn errorsrc(&self) -> u32 {
+        self.errorsrc.get()
+    }
+
+    fn clear_errorsrc(&self, errorsrc: u32) {
+        self.errorsrc.set(self.errorsrc.get() & !errorsrc);
+    }
+
+    fn rx_amount(&self) -> usize {
+        self.amount.get()
+    }
+
+    fn start_read(&self, rx_buffer: &mut [u8]) -> Result<(), Error> {
+        assert!(!self.receiving(), "STARTRX while receiving");
+        self.set_rx_buffer(rx_buffer)?;
+        self.start_rx();
+        Ok(())
+    }
+
+    fn queue_read(&self, rx_buffer: &mut [u8]) -> Result<(), Error> {
+        self.set_rx_buffer(rx_buffer)?;
+        self.queued.set(true);
+        Ok(())
+    }
+
+    fn stop_queue(&self) {
+        self.queued.set(false);
+    }
+
+    fn cancel_read(&self) {
+        let in_flight = self.in_flight.replace(Vec::new());
+        self.receive(&in_flight);
+
+        self.stop_queue();
+        self.stop_rx();
+    }
+
+    fn start_write(&self, tx_buffer: &[u8]) -> Result<(), Error> {
+        assert!(!self.sending(), "STARTTX while sending");
+        if tx_buffer.len() > Self::EASY_DMA_SIZE {
+            return Err(Error::BufferTooLarge);
+        }
+
+        self.clear_event(Event::EndTx);
+        self.clear_event(Event::TxStopped);
+        self.tx.set(Some((tx_buffer.as_ptr(), tx_buffer.len())));
+        Ok(())
+    }
+
+    fn set_baudrate(&self, baudrate: u32) {
+        self.baudrate.set(Some(baudrate));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        config::Reconfig,
+        errors::{LineErrorCounts, LineErrors},
+        state::UarteState,
+    };
+    use bbqueue::{
+        consts::{U16, U20, U64},
+        ArrayLength, BBBuffer, Consumer, Producer,
+    };
+    use core::sync::atomic::{AtomicBool, Ordering::SeqCst};
+    use std::boxed::Box;
+
+    const BLOCK: usize = 4;
+
+    // Bit 1 and 2 of ERRORSRC
+    const PARITY: u32 = 1 << 1;
+    const FRAMING: u32 = 1 << 2;
+
+    fn leak<T>(value: T) -> &'static T {
+        Box::leak(Box::new(value))
+    }
+
+    struct Harness<IncomingLen: ArrayLength<u8>> {
+        state: UarteState<U64, IncomingLen>,
+        uarte: SimUarte,
+        outgoing: Producer<'static, U64>,
+        incoming: Consumer<'static, IncomingLen>,
+        timeout: &'static AtomicBool,
+        line_errors: &'static LineErrors,
+        reconfig: &'static Reconfig,
+    }
+
+    impl Harness<U16> {
+        fn new() -> Self {
+            Self::with_queue()
+        }
+    }
+
+    impl<IncomingLen: ArrayLength<u8>> Harness<IncomingLen> {
+        fn with_queue() -> Self {
+            let (outgoing, outgoing_cons) = leak(BBBuffer::new()).try_split().unwrap();
+            let (incoming_prod, incoming) = leak(BBBuffer::new()).try_split().unwrap();
+            let timeout = leak(AtomicBool::new(false));
+            let line_errors = leak(LineErrors::new());
+            let reconfig = leak(Reconfig::new());
+
+            let mut state = UarteState::new(
+                outgoing_cons,
+                incoming_prod,
+                timeout,
+                BLOCK,
+                line_errors,
+                reconfig,
+            );
+            let uarte = SimUarte::new();
+            state.start(&uarte);
+
+            Self {
+                state,
+                uarte,
+                outgoing,
+                incoming,
+                timeout,
+                line_errors,
+                reconfig,
+            }
+        }
+
+        fn interrupt(&mut self) {
+            self.state.interrupt(&self.uarte);
+        }
+
+        fn idle(&mut self) {
+            self.timeout.store(true, SeqCst);
+            self.interrupt();
+        }
+
+        fn send(&mut self, bytes: &[u8]) {
+            let mut wgr = self.outgoing.grant_exact(bytes.len()).unwrap();
+            wgr.copy_from_slice(bytes);
+            wgr.commit(bytes.len());
+        }
+
+        fn received(&mut self) -> Vec<u8> {
+            let mut bytes = Vec::new();
+            while let Ok(rgr) = self.incoming.read() {
+                bytes.extend_from_slice(&rgr);
+                let len = rgr.len();
+                rgr.release(len);
+            }
+            bytes
+        }
+    }
+
+    #[test]
+    fn full_blocks_are_received_back_to_back() {
+        let mut h = Harness::new();
+        let input: Vec<u8> = (0..40).collect();
+
+        // The interrupt runs after every byte, or once per block
+        let mut output = Vec::new();
+        for chunk in input[..20].chunks(1).chain(input[20..].chunks(BLOCK)) {
+            h.uarte.receive(chunk);
+            h.interrupt();
+            assert!(h.uarte.receiving());
+            output.extend(h.received());
+        }
+
+        h.idle();
+        output.extend(h.received());
+
+        assert_eq!(output, input);
+        assert_eq!(h.line_errors.snapshot(), LineErrorCounts::default());
+    }
+
+    #[test]
+    fn handover_at_end_of_queue() {
+        // Not a multiple of two blocks, so the grant after a handover
+        // doesn't fit at the end of the queue
+        let mut h: Harness<U20> = Harness::with_queue();
+        let input: Vec<u8> = (0..40).collect();
+
+        let mut output = Vec::new();
+        for chunk in input.chunks(1) {
+            h.uarte.receive(chunk);
+            h.interrupt();
+            assert!(h.uarte.receiving());
+            output.extend(h.received());
+        }
+
+        h.idle();
+        output.extend(h.received());
+
+        assert_eq!(output, input);
+        assert_eq!(h.line_errors.snapshot(), LineErrorCounts::default());
+    }
+
+    #[test]
+    fn idle_line_flushes_partial_block() {
+        let mut h = Harness::new();
+        h.uarte.receive(&[1, 2, 3]);
+        h.interrupt();
+        assert!(h.received().is_empty());
+
+        h.idle();
+        assert_eq!(h.received(), [1, 2, 3]);
+        assert!(h.uarte.receiving());
+
+        // Nothing received since, so there's nothing to flush
+        h.idle();
+        assert!(h.received().is_empty());
+
+        // The full block is handed over first, the rest on the next timeout
+        h.uarte.receive(&[4, 5, 6, 7, 8]);
+        h.idle();
+        assert_eq!(h.received(), [4, 5, 6, 7]);
+        h.idle();
+        assert_eq!(h.received(), [8]);
+    }
+
+    #[test]
+    fn block_filled_while_cancelling() {
+        let mut h = Harness::new();
+        h.uarte.receive(&[1, 2]);
+        h.interrupt();
+        h.uarte.receive(&[3]);
+
+        // The first block fills up, and the queued one starts, before the
+        // reception is stopped
+        h.uarte.in_flight(&[4, 5]);
+        h.idle();
+
+        assert_eq!(h.received(), [1, 2, 3, 4, 5]);
+        assert!(h.uarte.receiving());
+
+        h.uarte.receive(&[6, 7, 8, 9]);
+        h.interrupt();
+        assert_eq!(h.received(), [6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn stale_amount_is_ignored() {
+        let mut h = Harness::new();
+        h.uarte.receive(&[1, 2, 3, 4]);
+        h.interrupt();
+        assert_eq!(h.received(), [1, 2, 3, 4]);
+
+        // The reception ends without a byte, and AMOUNT is left over from
+        // the last one
+        h.uarte.stop_rx();
+        h.uarte.set_amount(BLOCK);
+        h.interrupt();
+
+        assert!(h.received().is_empty());
+        assert!(h.uarte.receiving());
+    }
+
+    #[test]
+    fn full_queue_drops_bytes_until_read() {
+        let mut h = Harness::new();
+        let input: Vec<u8> = (0..24).collect();
+
+        // Nobody reads the incoming queue, so it fills up
+        for chunk in input.chunks(BLOCK) {
+            h.uarte.receive(chunk);
+            h.interrupt();
+        }
+        assert!(!h.uarte.receiving());
+        assert!(h.line_errors.snapshot().overrun > 0);
+
+        let output = h.received();
+        assert!(!output.is_empty());
+        assert_eq!(output[..], input[..output.len()]);
+
+        // Reception resumes on the next timeout
+        h.idle();
+        assert!(h.uarte.receiving());
+        h.uarte.receive(&[100, 101]);
+        h.idle();
+        assert_eq!(h.received(), [100, 101]);
+    }
+
+    #[test]
+    fn outgoing_bytes_are_sent_in_order() {
+        let mut h = Harness::new();
+        h.send(&[1, 2, 3]);
+        h.interrupt();
+        assert!(h.uarte.sending());
+
+        // Queued while the first write is still in progress
+        h.send(&[4, 5]);
+        h.interrupt();
+        assert!(h.uarte.transmit());
+        h.interrupt();
+        assert!(h.uarte.transmit());
+        h.interrupt();
+
+        assert!(!h.uarte.sending());
+        assert_eq!(h.uarte.take_sent(), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn baudrate_changes_once_sent() {
+        let mut h = Harness::new();
+        h.uarte.receive(&[1, 2]);
+        h.send(&[3, 4]);
+        h.interrupt();
+
+        h.reconfig.request(0x0275_0000, 100);
+        h.interrupt();
+        assert_eq!(h.uarte.baudrate(), None);
+
+        // The write ends, but the new baud rate is only used on the next
+        // interrupt, after the bytes received so far have been flushed
+        assert!(h.uarte.transmit());
+        h.interrupt();
+        h.interrupt();
+
+        assert_eq!(h.uarte.baudrate(), Some(0x0275_0000));
+        assert!(!h.reconfig.baudrate_pending());
+        assert_eq!(h.received(), [1, 2]);
+        assert!(h.uarte.receiving());
+    }
+
+    #[test]
+    fn line_errors_are_counted() {
+        let mut h = Harness::new();
+        h.uarte.line_error(PARITY | FRAMING);
+        h.interrupt();
+        h.uarte.line_error(FRAMING);
+        h.interrupt();
+
+        let errors = h.line_errors.snapshot();
+        assert_eq!(errors.parity, 1);
+        assert_eq!(errors.framing, 2);
+        assert_eq!(h.uarte.errorsrc(), 0);
+        assert!(!h.uarte.event(Event::Error));
+    }
+}
@@ -0,0 +1,270 @@
+//! The UARTE interrupt state machine
+//!
+//! `UarteState` moves bytes between the bbqueues and the UARTE, through the
+//! `UarteRegs` trait. It doesn't touch any hardware itself, so it can run
+//! against the simulated UARTE in `sim`.
+
+use crate::{
+    config::Reconfig,
+    errors::{LineErrorCounts, LineErrors, OVERRUN},
+    regs::{Event, UarteRegs},
+};
+use bbqueue::{ArrayLength, Consumer, GrantR, GrantW, Producer};
+use core::sync::atomic::{compiler_fence, AtomicBool, Ordering::SeqCst};
+
+pub struct UarteState<OutgoingLen, IncomingLen>
+where
+    OutgoingLen: ArrayLength<u8>,
+    IncomingLen: ArrayLength<u8>,
+{
+    pub(crate) outgoing_cons: Consumer<'static, OutgoingLen>,
+    pub(crate) incoming_prod: Producer<'static, IncomingLen>,
+    pub(crate) timeout_flag: &'static AtomicBool,
+    pub(crate) rx_grant: Option<GrantW<'static, IncomingLen>>,
+
+    // Whether the second block of `rx_grant` has been queued, to be filled
+    // as soon as the first is full
+    pub(crate) rx_queued: bool,
+    pub(crate) tx_grant: Option<GrantR<'static, OutgoingLen>>,
+    pub(crate) block_size: usize,
+    pub(crate) line_errors: &'static LineErrors,
+    pub(crate) error_callback: Option<fn(LineErrorCounts)>,
+    pub(crate) reconfig: &'static Reconfig,
+}
+
+impl<OutgoingLen, IncomingLen> UarteState<OutgoingLen, IncomingLen>
+where
+    OutgoingLen: ArrayLength<u8>,
+    IncomingLen: ArrayLength<u8>,
+{
+    /// `timeout_flag` is set when the line has been idle for a while, see
+    /// `UarteTimer`
+    pub fn new(
+        outgoing_cons: Consumer<'static, OutgoingLen>,
+        incoming_prod: Producer<'static, IncomingLen>,
+        timeout_flag: &'static AtomicBool,
+        block_size: usize,
+        line_errors: &'static LineErrors,
+        reconfig: &'static Reconfig,
+    ) -> Self {
+        Self {
+            outgoing_cons,
+            incoming_prod,
+            timeout_flag,
+            rx_grant: None,
+            rx_queued: false,
+            tx_grant: None,
+            block_size,
+            line_errors,
+            error_callback: None,
+            reconfig,
+        }
+    }
+
+    /// Start the first reception
+    pub fn start<Regs: UarteRegs>(&mut self, regs: &Regs) {
+        if let Some(mut gr) = self.grant_rx() {
+            regs.start_read(&mut gr[..self.block_size]).unwrap();
+            self.rx_grant = Some(gr);
+        }
+    }
+
+    /// Call `callback` from the interrupt whenever a line error occurs,
+    /// with the updated counts
+    pub fn set_error_callback(&mut self, callback: Option<fn(LineErrorCounts)>) {
+        self.error_callback = callback;
+    }
+
+    /// Get a grant for the next reception. Two blocks are requested, so the
+    /// second can be queued while the first is being filled, falling back to
+    /// one if there isn't room.
+    fn grant_rx(&mut self) -> Option<GrantW<'static, IncomingLen>> {
+        self.incoming_prod
+            .grant_exact(2 * self.block_size)
+            .or_else(|_| self.incoming_prod.grant_exact(self.block_size))
+            .ok()
+    }
+
+    /// Get a grant starting at `at`, where the hardware is already receiving
+    /// the queued block of the last grant. Two blocks if there is room after
+    /// it, otherwise only that block.
+    ///
+    /// A grant that wraps around to the start of the queue must never be
+    /// taken and dropped here: dropping it would invert the queue, and the
+    /// next grant would no longer start at `at`. `grant_max_remaining` only
+    /// wraps if there is no room at the end at all, and the block being
+    /// received is always there.
+    fn grant_rx_at(&mut self, at: *const u8) -> Option<GrantW<'static, IncomingLen>> {
+        let gr = self
+            .incoming_prod
+            .grant_max_remaining(2 * self.block_size)
+            .ok()?;
+
+        if gr.as_ptr() == at && gr.len() >= self.block_size {
+            Some(gr)
+        } else {
+            None
+        }
+    }
+
+    pub fn interrupt<Regs: UarteRegs>(&mut self, regs: &Regs) {
+        let endrx = regs.event(Event::EndRx);
+        let endtx = regs.event(Event::EndTx);
+        let rxdrdy = regs.event(Event::RxDrdy);
+        let error = regs.event(Event::Error);
+        let txstopped = regs.event(Event::TxStopped);
+        let mut rxstarted = regs.event(Event::RxStarted);
+
+        let timeout = self.timeout_flag.swap(false, SeqCst);
+        let errsrc = regs.errorsrc();
+
+        // Only change the baud rate between receptions, once everything
+        // queued for sending has been sent
+        let reconfigure = !endrx
+            && self.reconfig.baudrate_pending()
+            && self.tx_grant.is_none()
+            && self.outgoing_cons.read().is_err();
+
+        // RX section
+        let mut restart = self.rx_grant.is_none();
+
+        if self.rx_grant.is_some() && (reconfigure || (!endrx && timeout && rxdrdy)) {
+            // We only flush the connection if:
+            //
+            // * We didn't get a "natural" end of reception (full buffer), AND
+            // * The timer expired, AND
+            // * We have received one or more bytes to the receive buffer
+            //
+            // Or if we're about to change the baud rate.
+            regs.cancel_read();
+
+            // If the first block filled up just before we stopped, the
+            // reception we cancelled was in the queued block. Both blocks are
+            // part of the same grant, so all of the bytes can be committed.
+            let handed_over = self.rx_queued && regs.event(Event::RxStarted);
+            let filled = if handed_over { self.block_size } else { 0 };
+            let amt = regs.rx_amount();
+            regs.clear_event(Event::EndRx);
+
+            compiler_fence(SeqCst);
+
+            if let Some(gr) = self.rx_grant.take() {
+                gr.commit(filled + amt);
+            }
+            self.rx_queued = false;
+            restart = true;
+        } else if endrx && self.rx_queued {
+            // The first block is full, and the hardware has moved on to the
+            // queued one, which is now the start of the free space
+            compiler_fence(SeqCst);
+
+            let amt = regs.rx_amount();
+            let next = self.rx_grant.take().map(|gr| {
+                let next = gr[self.block_size..].as_ptr();
+                gr.commit(amt);
+                next
+            });
+            self.rx_queued = false;
+            self.rx_grant = next.and_then(|next| self.grant_rx_at(next));
+
+            if self.rx_grant.is_none() {
+                // The block being received isn't ours anymore, so its bytes
+                // can't be kept. Stop, and count them as lost.
+                regs.cancel_read();
+                regs.clear_event(Event::EndRx);
+                self.line_errors.record(OVERRUN);
+                restart = true;
+            }
+        } else if endrx {
+            compiler_fence(SeqCst);
+
+            // Get the bytes received. If the rxdrdy flag wasn't set, then we haven't
+            // actually received any bytes, and we can't trust the `amount` field
+            // (it may have a stale value from the last reception)
+            let amt = if rxdrdy { regs.rx_amount() } else { 0 };
+
+            if let Some(gr) = self.rx_grant.take() {
+                gr.commit(amt);
+            }
+            restart = true;
+        }
+
+        if reconfigure {
+            if let Some(baudrate) = self.reconfig.take_baudrate() {
+                regs.set_baudrate(baudrate);
+            }
+        }
+
+        // Attempt to get the next grant. If we don't get one now, no worries,
+        // we'll try again on the next timeout
+        if restart && self.rx_grant.is_none() {
+            // Any start or byte we haven't handled yet was for the old grant.
+            // Otherwise RXDRDY stays set until the bytes are flushed, even if
+            // other events are handled first.
+            regs.clear_event(Event::RxStarted);
+            regs.clear_event(Event::RxDrdy);
+            rxstarted = false;
+
+            if let Some(mut gr) = self.grant_rx() {
+                regs.start_read(&mut gr[..self.block_size]).unwrap();
+                self.rx_grant = Some(gr);
+            }
+        }
+
+        // Once a block has started, queue the block after it, so no bytes are
+        // lost between ENDRX and the next STARTRX
+        if rxstarted && !self.rx_queued {
+            regs.clear_event(Event::RxStarted);
+
+            match self.rx_grant {
+                Some(ref mut gr) if gr.len() >= 2 * self.block_size => {
+                    let block = &mut gr[self.block_size..2 * self.block_size];
+                    regs.queue_read(block).unwrap();
+                    self.rx_queued = true;
+                }
+                _ => regs.stop_queue(),
+            }
+        }
+
+        // TX Section
+        if endtx || self.tx_grant.is_none() {
+            if endtx {
+                if let Some(gr) = self.tx_grant.take() {
+                    let len = gr.len();
+                    gr.release(len.min(Regs::EASY_DMA_SIZE));
+                }
+            }
+
+            if let Ok(gr) = self.outgoing_cons.read() {
+                let len = gr.len();
+                regs.start_write(&gr[..len.min(Regs::EASY_DMA_SIZE)])
+                    .unwrap();
+                self.tx_grant = Some(gr);
+            }
+        }
+
+        // Clear events we processed
+        if endrx {
+            regs.clear_event(Event::EndRx);
+        }
+        if endtx {
+            regs.clear_event(Event::EndTx);
+        }
+        if error {
+            regs.clear_event(Event::Error);
+        }
+        if txstopped {
+            regs.clear_event(Event::TxStopped);
+        }
+
+        // Count and clear any errors
+        if errsrc != 0 {
+            regs.clear_errorsrc(errsrc);
+            self.line_errors.record(errsrc);
+
+            if let Some(callback) = self.error_callback {
+                callback(self.line_errors.snapshot());
+            }
+        }
+    }
+}
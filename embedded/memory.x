@@ -1,7 +1,9 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN  = 0x00000000, LENGTH = 512K
+  FLASH : ORIGIN  = 0x00000000, LENGTH = 508K
+  /* Pairing records, see `fleet_keys::pairing` */
+  PAIRING : ORIGIN = 0x0007F000, LENGTH = 4K
   RAM : ORIGIN    = 0x20000000, LENGTH = 63K
   PANDUMP: ORIGIN = 0x2000FC00, LENGTH = 1K
 }
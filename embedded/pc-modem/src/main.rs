@@ -15,26 +15,46 @@ use {
     hal::{
         clocks::LfOscConfiguration,
         gpio::{Level, Output, Pin, PushPull},
-        pac::{RTC0, TIMER0, TIMER2},
+        pac::{RTC0, TIMER0, TIMER2, UARTE0},
         ppi::{Parts, Ppi0},
+        rng::Rng,
         rtc::{Rtc, RtcInterrupt, Started},
+        target_constants::EASY_DMA_SIZE,
         wdt::{count, handles::HdlN, Parts as WatchdogParts, Watchdog, WatchdogHandle},
     },
     rtt_target::{rprintln, rtt_init_print},
 };
 
-use anachro_icd::{arbitrator::Arbitrator, component::Component, Uuid};
+use anachro_icd::{
+    arbitrator::{Arbitrator, PubSubResponse, SubMsg},
+    component::Component,
+    PubSubPath, Uuid,
+};
 use anachro_server::{Request, Response};
-use fleet_esb::{prx::FleetRadioPrx, BorrowRxMessage, RxMessage};
-use fleet_icd::{Buffer as CobsBuffer, WithResult};
-use fleet_keys::keys::KEY;
+use fleet_esb::{
+    pairing::PAIRING_PIPE, prx::FleetRadioPrx, queue::Priority, transport::Transport,
+    BorrowRxMessage, RollingTimer, RxMessage, NUM_PIPES,
+};
+use fleet_icd::{
+    modem::{ModemToPc, PairingCommand, PairingEvent, PcToModem, UartErrors},
+    radio::{
+        PairingDeviceMessage, RelayState, ADDRESS_PREFIXES, BASE_ADDRESSES, FLEET_NETWORK_ID,
+        RF_CHANNEL,
+    },
+    radio2::{matches, RelayCommand},
+};
+use fleet_keys::{blob::KeyBlob, derive::derive_device_key, pairing::PairingRecord};
 
-use fleet_uarte;
+use fleet_uarte::{self, framed::FrameDecoder};
 
-use postcard::{from_bytes, to_slice, to_slice_cobs};
+use postcard::from_bytes;
 
+mod pairing;
+mod session;
 mod timer;
 
+use pairing::PairingWindow;
+
 use timer::RollingRtcTimer;
 
 use blinq::{consts, patterns, Blinq};
@@ -42,6 +62,18 @@ use blinq::{consts, patterns, Blinq};
 // Panic provider crate
 use panic_persist;
 
+/// Messages for a device that hasn't polled within this many ticks are dropped
+const COMMAND_TTL: u32 = timer::TICKS_PER_SECOND * 5;
+
+/// Line errors on the UART are reported to the PC at most this often
+const LINE_ERROR_INTERVAL: u32 = timer::TICKS_PER_SECOND;
+
+/// The hardware ID of the device on each pipe, which was provisioned with
+/// a key derived from ours, see `fleet_keys::derive`. Pipes without an ID
+/// use our key directly. Devices paired with `fleet-cli pair` are added to
+/// these from flash at boot.
+const PIPE_DEVICES: [Option<u64>; NUM_PIPES] = [None; NUM_PIPES];
+
 static BUFFER: EsbBuffer<U8192, U8192> = EsbBuffer {
     app_to_radio_buf: BBBuffer(ConstBBBuffer::new()),
     radio_to_app_buf: BBBuffer(ConstBBBuffer::new()),
@@ -51,17 +83,24 @@ static BUFFER: EsbBuffer<U8192, U8192> = EsbBuffer {
 #[rtic::app(device = crate::hal::pac, peripherals = true, monotonic = crate::timer::RollingRtcTimer)]
 const APP: () = {
     struct Resources {
-        esb_app: FleetRadioPrx<U8192, U8192>,
+        esb_app: FleetRadioPrx<EsbApp<U8192, U8192>>,
         esb_irq: EsbIrq<U8192, U8192, TIMER0, StatePRX>,
         esb_timer: IrqTimer<TIMER0>,
         esb_wdog: WatchdogHandle<HdlN>,
 
         uarte_timer: fleet_uarte::irq::UarteTimer<TIMER2>,
-        uarte_irq: fleet_uarte::irq::UarteIrq<U1024, U1024, Ppi0>,
+        uarte_irq: fleet_uarte::irq::UarteIrq<U1024, U1024, Ppi0, UARTE0>,
         uarte_app: fleet_uarte::app::UarteApp<U1024, U1024>,
         uarte_wdog: WatchdogHandle<HdlN>,
 
-        cobs_buf: CobsBuffer<U256>,
+        frame_buf: FrameDecoder<U256>,
+
+        rng: Rng,
+
+        // The hardware ID of the device on each pipe, and the key their
+        // keys are derived from, if any
+        devices: [Option<u64>; NUM_PIPES],
+        master: Option<KeyBlob>,
 
         rtc: Rtc<RTC0, Started>,
         rtc_timer: RollingRtcTimer,
@@ -83,12 +122,16 @@ const APP: () = {
 
         let uart = ctx.device.UARTE0;
 
+        let mut prefixes0 = [0u8; 4];
+        let mut prefixes1 = [0u8; 4];
+        prefixes0.copy_from_slice(&ADDRESS_PREFIXES[..4]);
+        prefixes1.copy_from_slice(&ADDRESS_PREFIXES[4..]);
         let addresses = Addresses::new(
-            [0xE7, 0xE7, 0xE7, 0xE7], // default
-            [0xC2, 0xC2, 0xC2, 0xC2], // default
-            [0xE7, 0xC2, 0xC3, 0xC4], // default
-            [0xC5, 0xC6, 0xC7, 0xC8], // default
-            8,                        // default: 2
+            BASE_ADDRESSES[0],
+            BASE_ADDRESSES[1],
+            prefixes0,
+            prefixes1,
+            RF_CHANNEL,
         )
         .unwrap();
 
@@ -108,6 +151,8 @@ const APP: () = {
                 txd_buf: BBBuffer(ConstBBBuffer::new()),
                 rxd_buf: BBBuffer(ConstBBBuffer::new()),
                 timeout_flag: AtomicBool::new(false),
+                line_errors: fleet_uarte::errors::LineErrors::new(),
+                reconfig: fleet_uarte::config::Reconfig::new(),
             };
 
         // Create a new watchdog instance
@@ -165,7 +210,56 @@ const APP: () = {
             rprintln!("panic: {}", msg);
         }
 
-        let esb_app = FleetRadioPrx::new(esb_app, KEY.key());
+        // The key is provisioned per node, see `fleet_keys::blob`. Without
+        // one, the radio refuses all traffic.
+        let key = KeyBlob::from_uicr();
+        match key {
+            Ok(ref blob) => rprintln!("Using key {:08X}", blob.key_id()),
+            Err(ref e) => rprintln!("No key provisioned: {:?}", e),
+        }
+
+        let mut rng = Rng::new(ctx.device.RNG);
+
+        // Devices use the same 32.768kHz RTC ticks as we do
+        let mut esb_app = FleetRadioPrx::new(
+            esb_app,
+            None,
+            FLEET_NETWORK_ID,
+            timer::TICKS_PER_SECOND * 2,
+            &mut rng,
+        );
+
+        // Devices paired with `fleet-cli pair`, stored in the slot of their pipe
+        let mut devices = PIPE_DEVICES;
+        for (slot, device) in devices.iter_mut().enumerate() {
+            if let Ok(rec) = PairingRecord::from_flash(slot) {
+                if usize::from(rec.pipe()) == slot {
+                    rprintln!("Pipe {}: device {:016X}", slot, rec.device_id());
+                    *device = Some(rec.device_id());
+                }
+            }
+        }
+
+        // New devices are paired with keys derived from the key we send in
+        let mut master = None;
+        if let Ok(blob) = key {
+            esb_app.set_master_key(blob.epoch(), Some(blob.key().key()));
+            add_device_keys(&mut esb_app, &blob, &devices);
+
+            // If the key of the next epoch has been provisioned, start the
+            // rollover. Devices switch to the new epoch as soon as they hear
+            // from us, and frames in the old epoch are still accepted.
+            master = match KeyBlob::next_from_uicr() {
+                Ok(next) if next.epoch() != blob.epoch() => {
+                    rprintln!("Rolling over to key {:08X}", next.key_id());
+                    esb_app.add_master_key(next.epoch(), next.key().key());
+                    add_device_keys(&mut esb_app, &next, &devices);
+                    esb_app.switch_epoch(next.epoch()).ok();
+                    Some(next)
+                }
+                _ => Some(blob),
+            };
+        }
 
         let rxd = p0.p0_11.into_floating_input().degrade();
         let txd = p0.p0_05.into_push_pull_output(Level::Low).degrade();
@@ -188,7 +282,8 @@ const APP: () = {
                 ctx.device.TIMER2,
                 channel0,
                 uart,
-                255,
+                // The largest block the chip can receive in one DMA transfer
+                EASY_DMA_SIZE,
                 50_000,
             )
             .unwrap();
@@ -229,7 +324,10 @@ const APP: () = {
             uarte_irq: ue.irq,
             uarte_app: ue.app,
             uarte_wdog,
-            cobs_buf: CobsBuffer::new(),
+            frame_buf: FrameDecoder::new(),
+            rng,
+            devices,
+            master,
             rtc,
             rtc_timer: RollingRtcTimer::new(),
 
@@ -240,14 +338,18 @@ const APP: () = {
         }
     }
 
-    #[idle(resources = [esb_app, uarte_app, cobs_buf, esb_wdog, uarte_wdog, blinq0, blinq1, blinq2, blinq3])]
+    #[idle(resources = [esb_app, uarte_app, frame_buf, rng, devices, master, esb_wdog, uarte_wdog, blinq0, blinq1, blinq2, blinq3])]
     fn idle(mut ctx: idle::Context) -> ! {
         let esb_app = ctx.resources.esb_app;
         let uarte_app = ctx.resources.uarte_app;
-        let cobs_buf = ctx.resources.cobs_buf;
+        let frame_buf = ctx.resources.frame_buf;
+        let rng = ctx.resources.rng;
+        let devices = ctx.resources.devices;
+        let master = ctx.resources.master;
         let uarte_wdog = ctx.resources.uarte_wdog;
         let esb_wdog = ctx.resources.esb_wdog;
         let mut blinq2 = ctx.resources.blinq2;
+        let rtc_timer = RollingRtcTimer::new();
 
         let mut broker = anachro_server::Broker::default();
 
@@ -256,28 +358,68 @@ const APP: () = {
             0x03, 0x04,
         ]);
 
-        let pipe0_uuid = Uuid::from_bytes([
-            0x04, 0x04, 0x04, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00,
-        ]);
+        // Open while `fleet-cli pair` is waiting for a device
+        let mut window: Option<PairingWindow> = None;
+
+        let mut reported_errors = uarte_app.line_errors();
+        let mut last_report = rtc_timer.get_current_tick();
 
         rprintln!("Start!");
 
         broker.register_client(&uarte_uuid).unwrap();
-        broker.register_client(&pipe0_uuid).unwrap();
+        broker.register_client(&pipe_uuid(0)).unwrap();
+        for (pipe, device) in devices.iter().enumerate().skip(1) {
+            if device.is_some() {
+                broker.register_client(&pipe_uuid(pipe as u8)).ok();
+            }
+        }
 
         loop {
             let rx = esb_app.receive_with();
+            let now = rtc_timer.get_current_tick();
+
+            // Pairing messages aren't for the broker
+            let rx = match rx {
+                Ok(msg) if msg.pipe() == PAIRING_PIPE => {
+                    let pmsg = from_bytes::<PairingDeviceMessage>(msg.payload());
+                    if let (Some(win), Ok(pmsg)) = (window.as_mut(), pmsg) {
+                        let (event, record) = win.handle(esb_app, rng, pmsg, now);
+                        if let Some(rec) = record {
+                            pairing::add_device(esb_app, devices, &rec);
+                            broker.register_client(&pipe_uuid(rec.pipe())).ok();
+                            if let Some(win) = window.take() {
+                                win.close(esb_app);
+                            }
+                        }
+                        if let Some(event) = event {
+                            uarte_app.send_msg(&ModemToPc::Pairing(event)).ok();
+                        }
+                    }
+                    None
+                }
+                // Nor are session handshakes
+                Ok(msg) if msg.is_handshake() => {
+                    let pipe = msg.pipe();
+                    if pipe == 0
+                        || devices
+                            .get(usize::from(pipe))
+                            .map_or(false, Option::is_some)
+                    {
+                        session::handle(esb_app, rng, pipe, msg.payload());
+                    }
+                    None
+                }
+                rx => rx.ok(),
+            };
 
-            if let Ok(mut msg) = rx {
+            if let Some(msg) = rx {
                 let _ = msg.view_with(|msg: BorrowRxMessage<Component>| {
-                    let source = match msg.meta.pipe {
-                        0 => pipe0_uuid.clone(),
-                        i => {
-                            rprintln!("pipe {}?", i);
-                            return;
-                        }
-                    };
+                    let pipe = msg.meta.pipe;
+                    if pipe != 0 && devices.get(usize::from(pipe)).map_or(true, Option::is_none) {
+                        rprintln!("pipe {}?", pipe);
+                        return;
+                    }
+                    let source = pipe_uuid(pipe);
 
                     // Decoded a wireless message - pet the dog
                     esb_wdog.pet();
@@ -291,15 +433,25 @@ const APP: () = {
                         Ok(responses) => {
                             for resp in responses.iter() {
                                 match resp.dest {
-                                    x if x == pipe0_uuid => {
-                                        esb_app.send(&resp.msg, 0).ok();
-                                    }
                                     x if x == uarte_uuid => {
-                                        try_send(uarte_app, &resp.msg).ok();
-                                    }
-                                    _ => {
-                                        rprintln!("WHO DAT");
+                                        uarte_app.send_msg(&resp.msg).ok();
                                     }
+                                    x => match uuid_pipe(&x) {
+                                        Some(pipe) => {
+                                            esb_app
+                                                .enqueue(
+                                                    &resp.msg,
+                                                    pipe,
+                                                    priority(&resp.msg),
+                                                    Some(COMMAND_TTL),
+                                                    now,
+                                                )
+                                                .ok();
+                                        }
+                                        None => {
+                                            rprintln!("WHO DAT");
+                                        }
+                                    },
                                 }
                             }
                         }
@@ -308,19 +460,20 @@ const APP: () = {
                         }
                     }
                 });
+            }
 
-                // TODO
-                msg.fgr.release();
+            // Hand queued messages to the radio, dropping stale ones
+            match esb_app.service(now) {
+                Ok(0) => {}
+                Ok(n) => rprintln!("Dropped {} stale messages", n),
+                Err(e) => rprintln!("Queue error: {:?}", e),
             }
 
-            // Check for uart messages
-            if let Ok(rgr) = uarte_app.read() {
-                let mut buf: &[u8] = &rgr;
-                loop {
-                    if buf.is_empty() {
-                        break;
-                    }
-                    match cobs_buf.feed_with(buf, |msg: Component| {
+            // Check for uart messages. Pairing commands from `fleet-cli`
+            // share the UART with the broker
+            loop {
+                let result = uarte_app.recv_with(frame_buf, |uarte_app, msg| {
+                    if let Ok(msg) = from_bytes::<Component>(msg) {
                         rprintln!("From the UARTE: {:?}", msg);
                         if let Ok(msgs) = broker.process_msg(&Request {
                             msg,
@@ -331,14 +484,22 @@ const APP: () = {
                                 if msg.dest == uarte_uuid {
                                     rprintln!("TO THE UARTE: {:?};{:?}", msg.dest, msg.msg);
                                     // Send it to uarte
-                                    if try_send(uarte_app, &msg.msg).is_err() {
+                                    if uarte_app.send_msg(&msg.msg).is_err() {
                                         blinq2.lock(|b| {
                                             b.enqueue(patterns::blinks::LONG_ON_OFF);
                                         });
                                     }
-                                } else if msg.dest == pipe0_uuid {
+                                } else if let Some(pipe) = uuid_pipe(&msg.dest) {
                                     rprintln!("TO THE RADIO: {:?};{:?}", msg.dest, msg.msg);
-                                    esb_app.send(&msg.msg, 0).ok();
+                                    esb_app
+                                        .enqueue(
+                                            &msg.msg,
+                                            pipe,
+                                            priority(&msg.msg),
+                                            Some(COMMAND_TTL),
+                                            now,
+                                        )
+                                        .ok();
                                 } else {
                                     rprintln!("TO ???");
                                 }
@@ -346,52 +507,95 @@ const APP: () = {
                         } else {
                             rprintln!("broker said :(");
                         }
-                    }) {
-                        // Consumed all data, still pending
-                        WithResult::Consumed => {
-                            ctx.resources.blinq1.lock(|b| {
-                                b.enqueue(patterns::blinks::MEDIUM_ON_OFF);
-                            });
-                            break;
-                        }
+                        return true;
+                    }
 
-                        // Buffer was filled. Contains remaining section of input, if any
-                        WithResult::OverFull(new_buf) => {
-                            ctx.resources.blinq1.lock(|b| {
-                                b.enqueue(patterns::blinks::SHORT_ON_OFF);
-                            });
-                            rprintln!("Overfull");
-                            buf = new_buf;
+                    if let Ok(PcToModem::Pairing(cmd)) = from_bytes::<PcToModem>(msg) {
+                        let event = match cmd {
+                            PairingCommand::Start => {
+                                if let Some(win) = window.take() {
+                                    win.close(esb_app);
+                                }
+                                window = Some(PairingWindow::open(esb_app, now));
+                                Some(PairingEvent::Started)
+                            }
+                            PairingCommand::Confirm { code } => {
+                                match (window.as_mut(), master.as_ref()) {
+                                    (Some(win), Some(master)) => {
+                                        win.confirm(esb_app, code, master, devices, now).err()
+                                    }
+                                    _ => Some(PairingEvent::Failed),
+                                }
+                            }
+                            PairingCommand::Cancel => {
+                                if let Some(win) = window.take() {
+                                    win.close(esb_app);
+                                }
+                                None
+                            }
+                        };
+                        if let Some(event) = event {
+                            uarte_app.send_msg(&ModemToPc::Pairing(event)).ok();
                         }
+                        return true;
+                    }
 
-                        // Reached end of chunk, but deserialization failed. Contains
-                        // remaining section of input, if any
-                        WithResult::DeserError(new_buf) => {
-                            ctx.resources.blinq1.lock(|b| {
-                                b.enqueue(patterns::blinks::SHORT_ON_OFF);
-                            });
-                            rprintln!("Deser Error");
-                            buf = new_buf;
-                        }
+                    false
+                });
 
-                        // Deserialization complete. Contains deserialized data and
-                        // remaining section of input, if any
-                        WithResult::SuccessWith { result, remaining } => {
-                            ctx.resources.blinq1.lock(|b| {
-                                b.enqueue(patterns::blinks::LONG_ON_OFF);
-                            });
-                            // On successful decode, pet the watchdog
-                            uarte_wdog.pet();
+                match result {
+                    // Nothing more to decode until more data arrives
+                    Ok(None) => break,
 
-                            rprintln!("Success said: {:?}", result);
+                    Ok(Some(true)) => {
+                        ctx.resources.blinq1.lock(|b| {
+                            b.enqueue(patterns::blinks::LONG_ON_OFF);
+                        });
+                        // On successful decode, pet the watchdog
+                        uarte_wdog.pet();
+                    }
 
-                            buf = remaining;
-                        }
+                    Ok(Some(false)) => {
+                        ctx.resources.blinq1.lock(|b| {
+                            b.enqueue(patterns::blinks::SHORT_ON_OFF);
+                        });
+                        rprintln!("Deser Error");
+                    }
+
+                    Err(e) => {
+                        ctx.resources.blinq1.lock(|b| {
+                            b.enqueue(patterns::blinks::SHORT_ON_OFF);
+                        });
+                        rprintln!("Frame error: {:?}", e);
                     }
                 }
+            }
 
-                let len = rgr.len();
-                rgr.release(len);
+            // Let the PC know its link to us is degrading, rather than just
+            // dropping bytes
+            let errors = uarte_app.line_errors();
+            if errors != reported_errors && now.wrapping_sub(last_report) >= LINE_ERROR_INTERVAL {
+                rprintln!("UART errors: {:?}", errors);
+                let msg = ModemToPc::LineErrors(UartErrors {
+                    overrun: errors.overrun,
+                    parity: errors.parity,
+                    framing: errors.framing,
+                    break_condition: errors.break_condition,
+                });
+                if uarte_app.send_msg(&msg).is_ok() {
+                    reported_errors = errors;
+                    last_report = now;
+                }
+            }
+
+            if window.as_ref().map_or(false, |win| win.expired(now)) {
+                if let Some(win) = window.take() {
+                    win.close(esb_app);
+                }
+                rprintln!("Pairing timed out");
+                uarte_app
+                    .send_msg(&ModemToPc::Pairing(PairingEvent::Failed))
+                    .ok();
             }
         }
     }
@@ -411,7 +615,7 @@ const APP: () = {
     #[task(binds = RADIO, resources = [esb_irq], priority = 3)]
     fn radio(ctx: radio::Context) {
         match ctx.resources.esb_irq.radio_interrupt() {
-            Err(Error::MaximumAttempts) => {}
+            Err(Error::MaximumAttempts) => fleet_esb::stats::record_max_attempts(),
             Err(e) => {
                 bkpt();
                 panic!("Found error {:?}", e);
@@ -463,22 +667,65 @@ unsafe fn DefaultHandler(_irqn: i16) -> ! {
     SCB::sys_reset()
 }
 
-fn try_send(
-    uarte: &mut fleet_uarte::app::UarteApp<U1024, U1024>,
-    msg: &Arbitrator,
-) -> Result<(), ()> {
-    match uarte.write_grant(128) {
-        Ok(mut wgr) => {
-            let used: usize = to_slice_cobs(&msg, &mut wgr)
-                .map(|buf| buf.len())
-                .unwrap_or(0);
-
-            wgr.commit(used);
-            Ok(())
+/// Derive the keys of the devices on each pipe, for the epoch of the blob
+fn add_device_keys<R: Transport>(
+    esb_app: &mut FleetRadioPrx<R>,
+    blob: &KeyBlob,
+    devices: &[Option<u64>; NUM_PIPES],
+) {
+    for (pipe, device) in devices.iter().enumerate() {
+        if let Some(id) = device {
+            let device_key = derive_device_key(blob.key(), *id);
+            esb_app.set_device_key(pipe as u8, blob.epoch(), Some(device_key.key()));
         }
-        Err(e) => {
-            rprintln!("uartetxerr: {:?}", e);
-            Err(())
+    }
+}
+
+/// The broker client of the device on the given pipe
+fn pipe_uuid(pipe: u8) -> Uuid {
+    Uuid::from_bytes([
+        0x04, 0x04, 0x04, 0x04, pipe, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ])
+}
+
+/// The pipe of the given broker client, if it is a device
+fn uuid_pipe(uuid: &Uuid) -> Option<u8> {
+    (0..PAIRING_PIPE).find(|pipe| *uuid == pipe_uuid(*pipe))
+}
+
+/// How urgently a message from the broker is sent to a device. Published
+/// messages are commands, and go ahead of the broker's own replies. Switching
+/// a relay off goes ahead of everything else.
+fn priority(msg: &Arbitrator) -> Priority {
+    match msg {
+        Arbitrator::PubSub(Ok(PubSubResponse::SubMsg(sub))) if is_relay_off(sub) => {
+            Priority::Safety
         }
+        Arbitrator::PubSub(Ok(PubSubResponse::SubMsg(_))) => Priority::Urgent,
+        _ => Priority::Routine,
+    }
+}
+
+/// The relay commands of every plant light, see `fleet_icd::radio2`
+const RELAY_PATH: &str = "lights/plants/+/set";
+
+/// The size of a serialized `RelayCommand`
+const RELAY_COMMAND_SIZE: usize = 2;
+
+fn is_relay_off(msg: &SubMsg) -> bool {
+    // Short paths are only known to the device that registered them, so
+    // those are told apart by their payload alone
+    let relay = match msg.path {
+        PubSubPath::Long(ref path) => matches(RELAY_PATH, path.as_str()),
+        PubSubPath::Short(_) => true,
+    };
+    if !relay || msg.payload.len() != RELAY_COMMAND_SIZE {
+        return false;
+    }
+
+    match from_bytes::<RelayCommand>(msg.payload) {
+        Ok(cmd) => cmd.state == RelayState::Off,
+        Err(_) => false,
     }
 }
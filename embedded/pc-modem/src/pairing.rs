@@ -0,0 +1,213 @@
+//! Pairing new devices, see `fleet_esb::pairing`
+//!
+//! `fleet-cli pair` opens a pairing window. A device in pairing mode asks to
+//! pair on `PAIRING_PIPE`, and prints a code over RTT, which the user enters
+//! into `fleet-cli`. If the codes match, the device is given the first free
+//! pipe, and a key derived from ours. Both sides store the result in flash.
+
+use {
+    crate::{hal::Rng, timer},
+    esb::{consts::*, EsbApp},
+    fleet_esb::{
+        pairing::{Assignment, HostPairing, PAIRING_KEY, PAIRING_PIPE},
+        prx::FleetRadioPrx,
+        queue::Priority,
+        NUM_PIPES,
+    },
+    fleet_icd::{
+        modem::PairingEvent,
+        radio::{PairingDeviceMessage, PairingHostMessage, ADDRESS_PREFIXES, BASE_ADDRESSES},
+    },
+    fleet_keys::{blob::KeyBlob, derive::derive_device_key, pairing::PairingRecord},
+    rtt_target::rprintln,
+};
+
+/// How long pairing requests are accepted, in RTC ticks
+const PAIRING_TIMEOUT: u32 = timer::TICKS_PER_SECOND * 120;
+
+/// Pairing messages that aren't picked up by the device in time are dropped
+const PAIRING_TTL: u32 = timer::TICKS_PER_SECOND * 5;
+
+type Radio = FleetRadioPrx<EsbApp<U8192, U8192>>;
+
+pub struct PairingWindow {
+    state: HostPairing,
+    opened: u32,
+
+    // What we gave the device, once the user confirmed its code
+    assigned: Option<Assignment>,
+}
+
+impl PairingWindow {
+    /// Start accepting pairing requests
+    pub fn open(esb_app: &mut Radio, now: u32) -> Self {
+        // The last device to pair may have used this pipe
+        esb_app.reset_pipe(PAIRING_PIPE);
+        esb_app.clear_queue(PAIRING_PIPE);
+        esb_app.set_device_key(PAIRING_PIPE, 0, Some(&PAIRING_KEY));
+
+        Self {
+            state: HostPairing::new(),
+            opened: now,
+            assigned: None,
+        }
+    }
+
+    /// Stop accepting pairing requests
+    pub fn close(self, esb_app: &mut Radio) {
+        esb_app.clear_queue(PAIRING_PIPE);
+        esb_app.set_device_key(PAIRING_PIPE, 0, None);
+    }
+
+    pub fn expired(&self, now: u32) -> bool {
+        now.wrapping_sub(self.opened) > PAIRING_TIMEOUT
+    }
+
+    /// Handle a message from the device being paired. Once the device has
+    /// stored its assignment, it is returned so we can store it too.
+    pub fn handle(
+        &mut self,
+        esb_app: &mut Radio,
+        rng: &mut Rng,
+        msg: PairingDeviceMessage,
+        now: u32,
+    ) -> (Option<PairingEvent>, Option<PairingRecord>) {
+        let reply = match msg {
+            PairingDeviceMessage::Request { device_id, public } => {
+                // The device starts over if it missed our answer. Each
+                // attempt gets a new key and nonce, see `fleet_esb::pairing`
+                let mut secret = [0u8; 32];
+                let mut nonce = [0u8; 16];
+                rng.random(&mut secret);
+                rng.random(&mut nonce);
+
+                self.assigned = None;
+                let (public, commitment) = self.state.request(device_id, &public, secret, nonce);
+                PairingHostMessage::Commit { public, commitment }
+            }
+            PairingDeviceMessage::Nonce { nonce } => match self.state.reveal(&nonce) {
+                Ok(nonce) => {
+                    let reply = PairingHostMessage::Reveal { nonce: *nonce };
+                    esb_app
+                        .enqueue(
+                            &reply,
+                            PAIRING_PIPE,
+                            Priority::Urgent,
+                            Some(PAIRING_TTL),
+                            now,
+                        )
+                        .ok();
+                    let device_id = self.state.device_id().unwrap_or(0);
+                    return (Some(PairingEvent::Requested { device_id }), None);
+                }
+                Err(e) => {
+                    rprintln!("Pairing error: {:?}", e);
+                    return (None, None);
+                }
+            },
+            PairingDeviceMessage::Paired { confirm } => {
+                let assigned = match self.assigned {
+                    Some(assigned) => assigned,
+                    None => return (None, None),
+                };
+                if let Err(e) = self.state.finish(&confirm) {
+                    rprintln!("Bad pairing confirmation: {:?}", e);
+                    return (Some(PairingEvent::Failed), None);
+                }
+
+                let device_id = self.state.device_id().unwrap_or(0);
+                let record = PairingRecord::new(
+                    assigned.pipe,
+                    assigned.base,
+                    assigned.prefix,
+                    device_id,
+                    KeyBlob::new(assigned.key_id, assigned.key),
+                );
+                let event = PairingEvent::Paired {
+                    device_id,
+                    pipe: assigned.pipe,
+                };
+                return (Some(event), Some(record));
+            }
+        };
+
+        esb_app
+            .enqueue(
+                &reply,
+                PAIRING_PIPE,
+                Priority::Urgent,
+                Some(PAIRING_TTL),
+                now,
+            )
+            .ok();
+        (None, None)
+    }
+
+    /// The user has entered the code shown by the device. If it matches, the
+    /// device is assigned a pipe and its own key, derived from `master`.
+    pub fn confirm(
+        &mut self,
+        esb_app: &mut Radio,
+        code: u32,
+        master: &KeyBlob,
+        devices: &[Option<u64>; NUM_PIPES],
+        now: u32,
+    ) -> Result<(), PairingEvent> {
+        let device_id = self.state.device_id().ok_or(PairingEvent::Failed)?;
+        let pipe = free_pipe(devices, device_id).ok_or(PairingEvent::Failed)?;
+
+        let assignment = Assignment {
+            pipe,
+            base: BASE_ADDRESSES[if pipe == 0 { 0 } else { 1 }],
+            prefix: ADDRESS_PREFIXES[usize::from(pipe)],
+            key_id: master.key_id(),
+            key: *derive_device_key(master.key(), device_id).key(),
+        };
+
+        let sealed = self.state.seal(code, &assignment).map_err(|e| {
+            rprintln!("Pairing error: {:?}", e);
+            PairingEvent::Failed
+        })?;
+        self.assigned = Some(assignment);
+
+        let msg = PairingHostMessage::Assign {
+            pipe: sealed.pipe,
+            base: sealed.base,
+            prefix: sealed.prefix,
+            key_id: sealed.key_id,
+            key: sealed.key,
+            tag: sealed.tag,
+        };
+        esb_app
+            .enqueue(&msg, PAIRING_PIPE, Priority::Urgent, Some(PAIRING_TTL), now)
+            .map_err(|_| PairingEvent::Failed)
+    }
+}
+
+/// Store a newly paired device, and start talking to it with its key
+pub fn add_device(
+    esb_app: &mut Radio,
+    devices: &mut [Option<u64>; NUM_PIPES],
+    record: &PairingRecord,
+) {
+    let pipe = record.pipe();
+
+    // Nothing else writes to flash
+    unsafe { fleet_keys::pairing::store(usize::from(pipe), Some(record)) };
+
+    devices[usize::from(pipe)] = Some(record.device_id());
+    esb_app.reset_pipe(pipe);
+    esb_app.set_device_key(pipe, record.key().epoch(), Some(record.key().key().key()));
+}
+
+/// The pipe for the given device: the one it had before, or the first free
+/// one. Pipe 0 is kept for devices using the shared key, and the last pipe
+/// for pairing.
+fn free_pipe(devices: &[Option<u64>; NUM_PIPES], device_id: u64) -> Option<u8> {
+    let pipes = 1..usize::from(PAIRING_PIPE);
+    pipes
+        .clone()
+        .find(|p| devices[*p] == Some(device_id))
+        .or_else(|| pipes.clone().find(|p| devices[*p].is_none()))
+        .map(|p| p as u8)
+}
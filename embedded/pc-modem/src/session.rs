@@ -0,0 +1,48 @@
+//! Accepting sessions from devices, see `fleet_esb::session`
+//!
+//! Devices start a session with a handshake at boot, and again whenever
+//! their session needs rotating. Every handshake is answered with a new
+//! session, which replaces the previous one on that pipe.
+
+use {
+    crate::hal::Rng,
+    esb::{consts::*, EsbApp},
+    fleet_esb::{
+        prx::FleetRadioPrx,
+        session::{accept_session, DEFAULT_SESSION_MESSAGES, SESSION_NONCE_SIZE},
+    },
+    fleet_icd::radio::{DeviceToHost, GeneralDeviceMessage, GeneralHostMessage, HostToDevice},
+    postcard::from_bytes,
+    rtt_target::rprintln,
+};
+
+type Radio = FleetRadioPrx<EsbApp<U8192, U8192>>;
+
+/// Handle a handshake message from the device on the given pipe
+pub fn handle(esb_app: &mut Radio, rng: &mut Rng, pipe: u8, payload: &[u8]) {
+    let device_nonce = match from_bytes::<DeviceToHost>(payload) {
+        Ok(DeviceToHost::General(GeneralDeviceMessage::InitializeSession { nonce })) => nonce,
+        _ => return,
+    };
+
+    // Sessions are derived from the key the device was provisioned with
+    let key = match esb_app.pipe_key(pipe) {
+        Some(key) => *key,
+        None => return,
+    };
+
+    let mut host_nonce = [0u8; SESSION_NONCE_SIZE];
+    rng.random(&mut host_nonce);
+
+    // The answer is a handshake too, so it is sealed with the pipe key,
+    // not the new session
+    let reply = HostToDevice::General(GeneralHostMessage::SessionAccepted { nonce: host_nonce });
+    if let Err(e) = esb_app.send_handshake(&reply, pipe) {
+        rprintln!("Handshake tx err: {:?}", e);
+        return;
+    }
+
+    let session = accept_session(&key, &device_nonce, &host_nonce, DEFAULT_SESSION_MESSAGES);
+    esb_app.start_session(pipe, session);
+    rprintln!("Session started on pipe {}", pipe);
+}
@@ -2,7 +2,7 @@ use core::sync::atomic::{AtomicU32, Ordering};
 use fleet_esb::RollingTimer;
 use rtic::{Fraction, Monotonic};
 
-// pub const TICKS_PER_SECOND: u32 = 32768;
+pub const TICKS_PER_SECOND: u32 = 32768;
 pub const SIGNED_TICKS_PER_SECOND: i32 = 32768;
 
 static RTC_STORE: AtomicU32 = AtomicU32::new(0);
@@ -2,9 +2,10 @@ use anachro_client::{Client, ClientError, ClientIo, RecvMsg};
 use anachro_icd::{arbitrator::Arbitrator, component::Component, ManagedString, PubSubPath};
 use fleet_esb::{BorrowRxMessage, RollingTimer};
 use {
-    crate::timer::RollingRtcTimer,
+    crate::{session::Sessions, timer::RollingRtcTimer},
     blinq::patterns,
-    esb::consts::*,
+    esb::{consts::*, payload::PayloadR, EsbApp},
+    fleet_esb::GrantWrap,
     fleet_esb::{ptx::FleetRadioPtx, RxMessage},
     fleet_icd::radio::{DeviceToHost, GeneralDeviceMessage, HostToDevice},
     fleet_icd::radio2::PlantLightTable,
@@ -15,22 +16,32 @@ use {
 use heapless::{consts, ArrayLength, Vec};
 
 use anachro_client::from_bytes;
-use fleet_esb::ptx::PayloadR;
+
+use core::cmp::max;
 
 struct IoHandler<'a> {
-    esb_app: &'a mut FleetRadioPtx<U2048, U2048, RollingRtcTimer>,
-    rgr: Option<PayloadR<U2048>>,
+    esb_app: &'a mut FleetRadioPtx<EsbApp<U2048, U2048>, RollingRtcTimer>,
+    rgr: Option<GrantWrap<PayloadR<U2048>>>,
+    pipe: u8,
+    sessions: Option<&'a mut Sessions>,
 }
 
 impl<'a> ClientIo for IoHandler<'a> {
     fn recv(&mut self) -> Result<Option<Arbitrator>, ClientError> {
         self.drop_grant();
 
-        match self.esb_app.just_gimme_frame() {
+        match self.esb_app.receive_with() {
+            // Handshakes are for us, not the client
+            Ok(msg) if msg.is_handshake() => {
+                if let Some(sessions) = self.sessions.as_mut() {
+                    sessions.handle(self.esb_app, msg.payload());
+                }
+                return Ok(None);
+            }
             Ok(msg) => {
                 self.rgr = Some(msg);
                 if let Some(ref msg) = self.rgr {
-                    if let Ok(msg) = from_bytes::<Arbitrator>(msg) {
+                    if let Ok(msg) = from_bytes::<Arbitrator>(msg.payload()) {
                         return Ok(Some(msg));
                     } else {
                         return Ok(None);
@@ -44,8 +55,9 @@ impl<'a> ClientIo for IoHandler<'a> {
         }
     }
     fn send(&mut self, msg: &Component) -> Result<(), ClientError> {
+        // Small messages are sent together, by the next flush or poll
         self.esb_app
-            .send(msg, 0)
+            .batch(msg, self.pipe)
             .map_err(|_| ClientError::OutputFull)
     }
 }
@@ -57,12 +69,24 @@ impl<'a> IoHandler<'a> {
 }
 
 pub fn publish(ctx: crate::publish::Context, msg: &PlantLightTable) {
+    // Nobody to publish to until we're paired
+    if ctx.resources.pairing.is_some() {
+        return;
+    }
+
     let esb_app = ctx.resources.esb_app;
     let client = ctx.resources.client;
+    let pipe = *ctx.resources.pipe;
 
-    let mut io = IoHandler { esb_app, rgr: None };
+    let mut io = IoHandler {
+        esb_app,
+        rgr: None,
+        pipe,
+        sessions: ctx.resources.sessions.as_mut(),
+    };
 
-    let mut buf = [0u8; 128];
+    // The radio fragments large messages, so this doesn't need to fit in one frame
+    let mut buf = [0u8; 512];
 
     //  TODO - can I do this automatically?
     let pubby = match msg.serialize(&mut buf) {
@@ -74,18 +98,40 @@ pub fn publish(ctx: crate::publish::Context, msg: &PlantLightTable) {
         Ok(_) => rprintln!("Sent Pub!"),
         Err(_) => rprintln!("Pub Send Error!"),
     }
+
+    if let Err(e) = io.esb_app.flush() {
+        rprintln!("Flush err: {:?}", e);
+    }
 }
 
 pub fn rx_periodic(ctx: crate::rx_periodic::Context) {
     // Roughly 10ms
     const INTERVAL: i32 = crate::timer::SIGNED_TICKS_PER_SECOND / 100;
-    // Roughly 100ms
-    const POLL_PRX_INTERVAL: u32 = crate::timer::TICKS_PER_SECOND / 10;
+    const PAIRING_INTERVAL: i32 = crate::timer::SIGNED_TICKS_PER_SECOND / 10;
 
     let esb_app = ctx.resources.esb_app;
     let client = ctx.resources.client;
+    let pipe = *ctx.resources.pipe;
+
+    if let Some(pairing) = ctx.resources.pairing.as_mut() {
+        pairing.step(esb_app);
+        ctx.schedule
+            .rx_periodic(ctx.scheduled + PAIRING_INTERVAL)
+            .ok();
+        return;
+    }
 
-    let mut io = IoHandler { esb_app, rgr: None };
+    let mut sessions = ctx.resources.sessions.as_mut();
+    if let Some(sessions) = sessions.as_mut() {
+        sessions.step(esb_app, pipe);
+    }
+
+    let mut io = IoHandler {
+        esb_app,
+        rgr: None,
+        pipe,
+        sessions,
+    };
 
     match client.process_one::<_, PlantLightTable>(&mut io) {
         Ok(Some(RecvMsg {
@@ -106,12 +152,23 @@ pub fn rx_periodic(ctx: crate::rx_periodic::Context) {
 
     io.drop_grant();
 
-    if esb_app.ticks_since_last_tx() > POLL_PRX_INTERVAL {
-        match esb_app.send(&(), 0) {
-            Ok(_) => { /*rprintln!("Sent {:?}", msg) */ }
-            Err(e) => rprintln!("Send err: {:?}", e),
+    // The PRX can only reply to us, so poll it. This backs off when the
+    // link is idle, and speeds up when the PRX has messages waiting.
+    let polled = match esb_app.poll(pipe) {
+        Ok(polled) => polled,
+        Err(e) => {
+            rprintln!("Poll err: {:?}", e);
+            false
         }
-    }
+    };
+
+    // Check back soon for the answer to a poll, otherwise sleep until
+    // the next poll is due
+    let next = if polled {
+        INTERVAL
+    } else {
+        max(esb_app.ticks_until_poll() as i32, INTERVAL)
+    };
 
-    ctx.schedule.rx_periodic(ctx.scheduled + INTERVAL).ok();
+    ctx.schedule.rx_periodic(ctx.scheduled + next).ok();
 }
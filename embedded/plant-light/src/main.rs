@@ -2,7 +2,9 @@
 #![no_main]
 
 mod comms;
+mod pairing;
 mod relays;
+mod session;
 mod timer;
 
 // Import the right HAL/PAC crate, depending on the target chip
@@ -22,14 +24,18 @@ use {
     core::{default::Default, sync::atomic::AtomicBool},
     cortex_m::peripheral::SCB,
     cortex_m_rt::exception,
+    embedded_hal::digital::v2::InputPin,
     esb::{
         consts::*, irq::StatePTX, Addresses, BBBuffer, ConfigBuilder, ConstBBBuffer, Error,
-        EsbBuffer, EsbIrq, IrqTimer, TxPower,
+        EsbApp, EsbBuffer, EsbIrq, IrqTimer, TxPower,
+    },
+    fleet_esb::{pairing::PAIRING_KEY, ptx::FleetRadioPtx},
+    fleet_icd::radio::{
+        DeviceToHost, PlantLightDeviceMessage, PlantLightHostMessage, ADDRESS_PREFIXES,
+        BASE_ADDRESSES, FLEET_NETWORK_ID, RF_CHANNEL,
     },
-    fleet_esb::ptx::FleetRadioPtx,
-    fleet_icd::radio::{DeviceToHost, PlantLightDeviceMessage, PlantLightHostMessage},
     fleet_icd::radio2::{PlantLightTable, RelayCommand},
-    fleet_keys::keys::KEY,
+    fleet_keys::{blob::KeyBlob, derive::device_id, pairing::PairingRecord},
     hal::{
         clocks::LfOscConfiguration,
         gpio::{Level, Output, Pin, PushPull},
@@ -39,15 +45,17 @@ use {
         Rng, Rtc,
     },
     panic_persist::get_panic_message_utf8,
+    pairing::Pairing,
     relays::Relays,
     rtt_target::{rprintln, rtt_init_print},
+    session::Sessions,
     timer::RollingRtcTimer,
 };
 
 #[rtic::app(device = crate::hal::pac, peripherals = true, monotonic = crate::timer::RollingRtcTimer)]
 const APP: () = {
     struct Resources {
-        esb_app: FleetRadioPtx<U2048, U2048, RollingRtcTimer>,
+        esb_app: FleetRadioPtx<EsbApp<U2048, U2048>, RollingRtcTimer>,
         esb_irq: EsbIrq<U2048, U2048, TIMER0, StatePTX>,
         esb_timer: IrqTimer<TIMER0>,
         relays: Relays<RollingRtcTimer>,
@@ -61,6 +69,12 @@ const APP: () = {
         red_led: Blinq<consts::U8, Pin<Output<PushPull>>>,
 
         client: Client,
+
+        // The pipe we talk to the modem on, and our pairing state while in
+        // pairing mode, or our session with the modem otherwise
+        pipe: u8,
+        pairing: Option<Pairing>,
+        sessions: Option<Sessions>,
     }
 
     #[init(spawn = [relay_periodic, rx_periodic, relay_status, led_periodic])]
@@ -107,12 +121,31 @@ const APP: () = {
             radio_to_app_buf: BBBuffer(ConstBBBuffer::new()),
             timer_flag: AtomicBool::new(false),
         };
+
+        // Hold the pairing button at boot to pair again, see `pairing`
+        let pair_button = p1.p1_02.into_pullup_input();
+        let pair_held = pair_button.is_low().unwrap_or(false);
+
+        // Paired devices use the pipe, address and key assigned by the modem
+        let record = match PairingRecord::from_flash(0) {
+            Ok(rec) if !pair_held => Some(rec),
+            _ => None,
+        };
+
+        let mut bases = BASE_ADDRESSES;
+        let mut prefixes = ADDRESS_PREFIXES;
+        if let Some(ref rec) = record {
+            let pipe = usize::from(rec.pipe()) % prefixes.len();
+            bases[if pipe == 0 { 0 } else { 1 }] = *rec.base();
+            prefixes[pipe] = rec.prefix();
+        }
+
         let addresses = Addresses::new(
-            [0xE7, 0xE7, 0xE7, 0xE7], // default
-            [0xC2, 0xC2, 0xC2, 0xC2], // default
-            [0xE7, 0xC2, 0xC3, 0xC4], // default
-            [0xC5, 0xC6, 0xC7, 0xC8], // default
-            8,                        // default: 2
+            bases[0],
+            bases[1],
+            [prefixes[0], prefixes[1], prefixes[2], prefixes[3]],
+            [prefixes[4], prefixes[5], prefixes[6], prefixes[7]],
+            RF_CHANNEL,
         )
         .unwrap();
         let config = ConfigBuilder::default()
@@ -181,14 +214,56 @@ const APP: () = {
 
         let mut rng = Rng::new(ctx.device.RNG);
 
-        let radio = FleetRadioPtx::new(
+        // Needed to provision a key for this device, see `fleet_keys::derive`
+        let ficr = &ctx.device.FICR;
+        let id = device_id([
+            ficr.deviceid[0].read().bits(),
+            ficr.deviceid[1].read().bits(),
+        ]);
+        rprintln!("Device ID {:016X}", id);
+
+        // The key is provisioned per node, see `fleet_keys::blob`, or
+        // assigned by the modem when pairing. Without one, we pair.
+        let key = KeyBlob::from_uicr();
+        match (&record, &key) {
+            (Some(rec), _) => rprintln!(
+                "Paired on pipe {}, using key {:08X}",
+                rec.pipe(),
+                rec.key().key_id()
+            ),
+            (None, Ok(blob)) => rprintln!("Using key {:08X}", blob.key_id()),
+            (None, Err(e)) => rprintln!("No key provisioned: {:?}", e),
+        }
+        let pair = pair_held || (record.is_none() && key.is_err());
+
+        let mut radio = FleetRadioPtx::new(
             esb_app,
-            KEY.key(),
+            None,
+            FLEET_NETWORK_ID,
             RollingRtcTimer::new(),
             timer::TICKS_PER_SECOND * 2,
             &mut rng,
         );
 
+        let mut pipe = 0;
+        if pair {
+            rprintln!("Pairing!");
+            radio.set_master_key(0, Some(&PAIRING_KEY));
+        } else if let Some(ref rec) = record {
+            let blob = rec.key();
+            radio.set_master_key(blob.epoch(), Some(blob.key().key()));
+            pipe = rec.pipe();
+        } else if let Ok(ref blob) = key {
+            radio.set_master_key(blob.epoch(), Some(blob.key().key()));
+
+            // Keep sending in the current epoch until the modem switches to
+            // the next one
+            if let Ok(next) = KeyBlob::next_from_uicr() {
+                rprintln!("Holding next key {:08X}", next.key_id());
+                radio.add_master_key(next.epoch(), next.key().key());
+            }
+        }
+
         let mut rtc = Rtc::new(ctx.device.RTC0);
         rtc.set_prescaler(0).ok();
         rtc.enable_interrupt(RtcInterrupt::Tick, None);
@@ -237,6 +312,14 @@ const APP: () = {
             Some(100),
         );
 
+        // Pairing needs the RNG for as long as it lasts, and restarts once
+        // done. Otherwise, it provides the nonces for our sessions.
+        let (pairing, sessions) = if pair {
+            (Some(Pairing::new(id, rng)), None)
+        } else {
+            (None, Some(Sessions::new(rng)))
+        };
+
         init::LateResources {
             esb_app: radio,
             esb_irq,
@@ -250,6 +333,9 @@ const APP: () = {
             red_led: red,
             green_led: green,
             client,
+            pipe,
+            pairing,
+            sessions,
         }
     }
 
@@ -284,7 +370,10 @@ const APP: () = {
     #[task(binds = RADIO, resources = [esb_irq], priority = 3)]
     fn radio_interrupt(ctx: radio_interrupt::Context) {
         match ctx.resources.esb_irq.radio_interrupt() {
-            Err(Error::MaximumAttempts) => rprintln!("max attempts!"),
+            Err(Error::MaximumAttempts) => {
+                fleet_esb::stats::record_max_attempts();
+                rprintln!("max attempts!");
+            }
             Err(e) => panic!("Found error {:?}", e),
             Ok(_state) => {}
         }
@@ -308,7 +397,7 @@ const APP: () = {
             .ok();
     }
 
-    #[task(resources = [esb_app, client], capacity = 5)]
+    #[task(resources = [esb_app, client, pipe, pairing, sessions], capacity = 5)]
     fn publish(ctx: publish::Context, msg: PlantLightTable) {
         comms::publish(ctx, &msg);
     }
@@ -350,7 +439,7 @@ const APP: () = {
     ///
     /// We also also check to see if we haven't heard from the remote device in
     /// a while. If so, we reboot.
-    #[task(schedule = [rx_periodic], spawn = [relay_command], resources = [esb_app, esb_wdog, blue_led, client])]
+    #[task(schedule = [rx_periodic], spawn = [relay_command], resources = [esb_app, esb_wdog, blue_led, client, pipe, pairing, sessions])]
     fn rx_periodic(ctx: rx_periodic::Context) {
         comms::rx_periodic(ctx);
     }
@@ -0,0 +1,150 @@
+//! Pairing with the modem, see `fleet_esb::pairing`
+//!
+//! Pairing mode is entered at boot when the pairing button is held, or when
+//! no key has been provisioned. Once the modem has assigned us a pipe and
+//! key, they are stored in flash and we restart with them.
+
+use {
+    crate::{hal::Rng, timer::RollingRtcTimer},
+    cortex_m::peripheral::SCB,
+    esb::{consts::*, EsbApp},
+    fleet_esb::{
+        pairing::{DevicePairing, SealedAssignment, PAIRING_PIPE},
+        ptx::FleetRadioPtx,
+    },
+    fleet_icd::radio::{PairingDeviceMessage, PairingHostMessage},
+    fleet_keys::{blob::KeyBlob, pairing::PairingRecord},
+    rtt_target::rprintln,
+};
+
+/// How many steps to wait for the modem, before asking again
+const RETRY_STEPS: u32 = 10;
+
+pub struct Pairing {
+    state: DevicePairing,
+
+    // For a new nonce every time the modem commits to one of its own
+    rng: Rng,
+    committed: bool,
+    paired: bool,
+    steps: u32,
+}
+
+impl Pairing {
+    pub fn new(device_id: u64, mut rng: Rng) -> Self {
+        let mut secret = [0u8; 32];
+        rng.random(&mut secret);
+
+        Self {
+            state: DevicePairing::new(device_id, secret),
+            rng,
+            committed: false,
+            paired: false,
+            steps: 0,
+        }
+    }
+
+    /// Handle any messages from the modem, and ask again if it hasn't
+    /// answered yet
+    pub fn step(&mut self, esb_app: &mut FleetRadioPtx<EsbApp<U2048, U2048>, RollingRtcTimer>) {
+        // Our assignment went out with the last step, start using it
+        if self.paired {
+            rprintln!("Paired, restarting!");
+            SCB::sys_reset();
+        }
+
+        loop {
+            let msg = match esb_app.receive::<PairingHostMessage>() {
+                Ok(Some(msg)) if msg.meta.pipe == PAIRING_PIPE => msg.msg,
+                Ok(Some(_)) => continue,
+                Ok(None) => break,
+                Err(e) => {
+                    rprintln!("Pairing rx err: {:?}", e);
+                    break;
+                }
+            };
+
+            if let Some(reply) = self.handle(msg) {
+                if let Err(e) = esb_app.send(&reply, PAIRING_PIPE) {
+                    rprintln!("Pairing tx err: {:?}", e);
+                }
+            }
+        }
+
+        if !self.committed && self.steps % RETRY_STEPS == 0 {
+            let request = PairingDeviceMessage::Request {
+                device_id: self.state.device_id(),
+                public: *self.state.public_key(),
+            };
+            esb_app.send(&request, PAIRING_PIPE).ok();
+        }
+        self.steps = self.steps.wrapping_add(1);
+
+        // The modem can only answer our frames
+        if let Err(e) = esb_app.poll(PAIRING_PIPE) {
+            rprintln!("Poll err: {:?}", e);
+        }
+    }
+
+    fn handle(&mut self, msg: PairingHostMessage) -> Option<PairingDeviceMessage> {
+        match msg {
+            PairingHostMessage::Commit { public, commitment } => {
+                self.committed = true;
+                let mut nonce = [0u8; 16];
+                self.rng.random(&mut nonce);
+                let nonce = *self.state.commit(&public, &commitment, nonce);
+                Some(PairingDeviceMessage::Nonce { nonce })
+            }
+            PairingHostMessage::Reveal { nonce } => {
+                match self.state.reveal(&nonce) {
+                    Ok(code) => rprintln!("Pairing code: {:06}", code),
+                    Err(e) => {
+                        // Start over
+                        rprintln!("Pairing failed: {:?}", e);
+                        self.committed = false;
+                    }
+                }
+                None
+            }
+            PairingHostMessage::Assign {
+                pipe,
+                base,
+                prefix,
+                key_id,
+                key,
+                tag,
+            } => {
+                let sealed = SealedAssignment {
+                    pipe,
+                    base,
+                    prefix,
+                    key_id,
+                    key,
+                    tag,
+                };
+                let (assigned, confirm) = match self.state.open(&sealed) {
+                    Ok(opened) => opened,
+                    Err(e) => {
+                        rprintln!("Bad assignment: {:?}", e);
+                        return None;
+                    }
+                };
+
+                let record = PairingRecord::new(
+                    assigned.pipe,
+                    assigned.base,
+                    assigned.prefix,
+                    self.state.device_id(),
+                    KeyBlob::new(assigned.key_id, assigned.key),
+                );
+
+                // Nothing else writes to flash
+                unsafe { fleet_keys::pairing::store(0, Some(&record)) };
+                rprintln!("Assigned pipe {}", assigned.pipe);
+
+                self.paired = true;
+                Some(PairingDeviceMessage::Paired { confirm })
+            }
+        }
+    }
+}
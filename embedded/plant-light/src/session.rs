@@ -0,0 +1,119 @@
+//! Keeping a session with the modem, see `fleet_esb::session`
+//!
+//! A session is started at boot, and a new one whenever the current one has
+//! protected enough messages. The modem answers our handshakes with the
+//! master key, so they are also how we recover when it restarts and loses
+//! our session.
+
+use {
+    crate::{
+        hal::Rng,
+        timer::{RollingRtcTimer, TICKS_PER_SECOND},
+    },
+    esb::{consts::*, EsbApp},
+    fleet_esb::{
+        ptx::FleetRadioPtx,
+        session::{DeviceHandshake, DEFAULT_SESSION_MESSAGES, SESSION_NONCE_SIZE},
+    },
+    fleet_icd::radio::{DeviceToHost, GeneralDeviceMessage, GeneralHostMessage, HostToDevice},
+    postcard::from_bytes,
+    rtt_target::rprintln,
+};
+
+/// How long to wait for the modem to accept a handshake, before asking again
+const RETRY_TICKS: u32 = TICKS_PER_SECOND * 2;
+
+/// If we haven't heard from the modem for this long, it may have restarted
+/// and lost our session, so we start a new one
+const SILENT_TICKS: u32 = TICKS_PER_SECOND * 30;
+
+pub struct Sessions {
+    // For a new nonce with every session
+    rng: Rng,
+
+    // Our nonce for the current handshake, when it was last sent, and
+    // whether the modem has answered it
+    nonce: Option<[u8; SESSION_NONCE_SIZE]>,
+    sent: u32,
+    accepted: bool,
+}
+
+impl Sessions {
+    pub fn new(rng: Rng) -> Self {
+        Self {
+            rng,
+            nonce: None,
+            sent: 0,
+            accepted: false,
+        }
+    }
+
+    /// Start a new session if we don't have one, the current one needs
+    /// rotating, or the modem seems to have lost it. Handshakes the modem
+    /// hasn't answered are sent again.
+    pub fn step(
+        &mut self,
+        esb_app: &mut FleetRadioPtx<EsbApp<U2048, U2048>, RollingRtcTimer>,
+        pipe: u8,
+    ) {
+        let now = esb_app.current_tick();
+        let retry = self.nonce.is_some() && !self.accepted;
+        let due = match esb_app.session() {
+            _ if retry => now.wrapping_sub(self.sent) >= RETRY_TICKS,
+            Some(session) => {
+                session.needs_rotation() || esb_app.ticks_since_last_rx() >= SILENT_TICKS
+            }
+            None => true,
+        };
+        if !due {
+            return;
+        }
+
+        // A retry keeps its nonce, so an answer to an earlier copy is
+        // still derived from the nonce we hold
+        let nonce = match self.nonce {
+            Some(nonce) if retry => nonce,
+            _ => {
+                let mut nonce = [0u8; SESSION_NONCE_SIZE];
+                self.rng.random(&mut nonce);
+                self.nonce = Some(nonce);
+                self.accepted = false;
+                nonce
+            }
+        };
+        self.sent = now;
+
+        // Until the modem answers, we keep using the old session, if any
+        let msg = DeviceToHost::General(GeneralDeviceMessage::InitializeSession { nonce });
+        if let Err(e) = esb_app.send_handshake(&msg, pipe) {
+            rprintln!("Handshake tx err: {:?}", e);
+        }
+    }
+
+    /// Handle a handshake message from the modem
+    ///
+    /// The modem starts a new session for every copy of our handshake it
+    /// receives, and answers them in order, so every answer replaces the
+    /// session, and the last one matches the modem's.
+    pub fn handle(
+        &mut self,
+        esb_app: &mut FleetRadioPtx<EsbApp<U2048, U2048>, RollingRtcTimer>,
+        payload: &[u8],
+    ) {
+        let host_nonce = match from_bytes::<HostToDevice>(payload) {
+            Ok(HostToDevice::General(GeneralHostMessage::SessionAccepted { nonce })) => nonce,
+            _ => return,
+        };
+        let (nonce, master) = match (self.nonce, esb_app.master_key()) {
+            (Some(nonce), Some(key)) => (nonce, *key),
+            _ => return,
+        };
+
+        let handshake = DeviceHandshake::new(nonce);
+        esb_app.start_session(handshake.complete(&master, &host_nonce, DEFAULT_SESSION_MESSAGES));
+        if !self.accepted {
+            rprintln!("Session started");
+        }
+        self.accepted = true;
+    }
+}
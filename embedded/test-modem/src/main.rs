@@ -51,7 +51,7 @@ const APP: () = {
         timer: Timer<TIMER1>,
 
         uarte_timer: fleet_uarte::irq::UarteTimer<TIMER2>,
-        uarte_irq: fleet_uarte::irq::UarteIrq<U1024, U1024, Ppi0>,
+        uarte_irq: fleet_uarte::irq::UarteIrq<U1024, U1024, Ppi0, UARTE0>,
         uarte_app: fleet_uarte::app::UarteApp<U1024, U1024>,
     }
 
@@ -81,6 +81,8 @@ const APP: () = {
                 txd_buf: BBBuffer(ConstBBBuffer::new()),
                 rxd_buf: BBBuffer(ConstBBBuffer::new()),
                 timeout_flag: AtomicBool::new(false),
+                line_errors: fleet_uarte::errors::LineErrors::new(),
+                reconfig: fleet_uarte::config::Reconfig::new(),
             };
 
         rtt_init_print!();
@@ -17,8 +17,14 @@ use structopt::StructOpt;
 use toml::{from_str, to_string};
 use fleet_icd::{
     consts::*, Buffer, FeedResult,
-    radio::{DeviceToHost, HostToDevice, GeneralHostMessage, PlantLightHostMessage, RelayIdx, RelayState},
-    modem::{PcToModem, ModemToPc},
+    radio::{DeviceToHost, HostToDevice, GeneralHostMessage, PlantLightHostMessage, RelayIdx, RelayState, FLEET_NETWORK_ID},
+    modem::{PcToModem, ModemToPc, PairingCommand, PairingEvent},
+};
+use fleet_esb::{cipher::ChaCha8Poly1305, frame::FrameCodec};
+use fleet_keys::{
+    blob::{KeyBlob, UICR_CUSTOMER, UICR_NEXT},
+    derive::derive_device_key,
+    keys::KEY,
 };
 
 #[derive(StructOpt, Debug)]
@@ -27,6 +33,45 @@ enum SubCommands {
     Reset,
     Log,
     Debug,
+
+    /// Pair a new device with the modem. Hold the device's pairing button
+    /// while it boots, then enter the code it prints over RTT.
+    Pair,
+
+    /// Decrypt a captured radio frame, given as hex
+    DecodeFrame {
+        /// The ESB pipe the frame was captured on
+        #[structopt(long, default_value = "0")]
+        pipe: u8,
+
+        frame: String,
+    },
+
+    /// Generate a key blob, to be provisioned into a node's UICR
+    KeyBlob {
+        /// Identifies the key. The low four bits are its key epoch.
+        #[structopt(long)]
+        key_id: u32,
+
+        /// The key as 64 hex digits. A random key is generated if not given.
+        #[structopt(long)]
+        key: Option<String>,
+
+        /// Provision the device with this hardware ID (in hex) with its own key,
+        /// derived from the given master key
+        #[structopt(long, requires = "key")]
+        device_id: Option<String>,
+
+        /// Write the raw blob, instead of an Intel HEX file for the UICR
+        #[structopt(long)]
+        raw: bool,
+
+        /// Provision the key of the next epoch, to roll over to
+        #[structopt(long)]
+        next: bool,
+
+        out: String,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -57,6 +102,23 @@ pub type Result<T> = std::result::Result<T, Error>;
 fn main() -> Result<()> {
     let opt = SubCommands::from_args();
 
+    // Doesn't need the modem
+    if let SubCommands::DecodeFrame { pipe, frame } = opt {
+        return decode_frame(pipe, &frame);
+    }
+    if let SubCommands::KeyBlob {
+        key_id,
+        key,
+        device_id,
+        raw,
+        next,
+        out,
+    } = opt
+    {
+        let addr = if next { UICR_NEXT } else { UICR_CUSTOMER };
+        return key_blob(key_id, key.as_deref(), device_id.as_deref(), raw, addr, &out);
+    }
+
     let mut settings: SerialPortSettings = Default::default();
     settings.timeout = Duration::from_millis(50);
     settings.baud_rate = 230_400;
@@ -69,7 +131,10 @@ fn main() -> Result<()> {
         }
     };
 
-    let ret = log(&mut port);
+    let ret = match opt {
+        SubCommands::Pair => pair(&mut port),
+        _ => log(&mut port),
+    };
 
     // let ret = match opt {
     //     SubCommands::Reset => {
@@ -88,6 +153,119 @@ fn main() -> Result<()> {
     ret
 }
 
+fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    let hex = hex.trim();
+
+    // Anything else can't be sliced two bytes at a time
+    if !hex.is_ascii() {
+        return Err(Error::from("Expected only hex digits"));
+    }
+    if hex.len() % 2 != 0 {
+        return Err(Error::from("Expected an even number of hex digits"));
+    }
+
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()?;
+    Ok(bytes)
+}
+
+fn decode_frame(pipe: u8, frame: &str) -> Result<()> {
+    let mut bytes = parse_hex(frame)?;
+
+    let codec: FrameCodec<ChaCha8Poly1305> = FrameCodec::new(KEY.key(), FLEET_NETWORK_ID);
+
+    match codec.open(pipe, &mut bytes) {
+        Ok((nonce, len)) => {
+            println!("tick:      {}", nonce.tick());
+            println!("msg_count: {}", nonce.msg_count());
+            println!("sender:    {:?}", nonce.sender());
+            let version = nonce.version();
+            println!(
+                "version:   {}.{}.{}",
+                version.major, version.minor, version.trivial
+            );
+            println!("payload:   {:02X?}", &bytes[..len]);
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("Failed to decode frame: {:?}", e);
+            Err(Error::from("BAD FRAME"))
+        }
+    }
+}
+
+fn key_blob(
+    key_id: u32,
+    key: Option<&str>,
+    device_id: Option<&str>,
+    raw: bool,
+    addr: usize,
+    out: &str,
+) -> Result<()> {
+    let mut key_bytes = [0u8; 32];
+    match key {
+        Some(hex) => {
+            let bytes = parse_hex(hex)?;
+            if bytes.len() != key_bytes.len() {
+                return Err(Error::from("The key must be 32 bytes"));
+            }
+            key_bytes.copy_from_slice(&bytes);
+        }
+        None => File::open("/dev/urandom")?.read_exact(&mut key_bytes)?,
+    }
+
+    let mut blob = KeyBlob::new(key_id, key_bytes);
+    if let Some(id) = device_id {
+        let id = u64::from_str_radix(id.trim_start_matches("0x"), 16)?;
+        let device_key = derive_device_key(blob.key(), id);
+        blob = KeyBlob::new(key_id, *device_key.key());
+        println!("Derived the key of device {:016X}", id);
+    }
+
+    let blob = blob.to_bytes();
+    let mut file = File::create(out)?;
+    if raw {
+        file.write_all(&blob)?;
+    } else {
+        file.write_all(intel_hex(addr as u32, &blob).as_bytes())?;
+    }
+
+    println!("Wrote key {:08X} to {}", key_id, out);
+    Ok(())
+}
+
+/// Format data at the given address as Intel HEX, e.g. for `nrfjprog --program`
+fn intel_hex(addr: u32, data: &[u8]) -> String {
+    fn record(kind: u8, offset: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend_from_slice(&offset.to_be_bytes());
+        bytes.push(kind);
+        bytes.extend_from_slice(data);
+
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(sum.wrapping_neg());
+
+        let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        format!(":{}\n", hex)
+    }
+
+    let mut out = String::new();
+    let mut upper = None;
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let chunk_addr = addr + (i * 16) as u32;
+        let chunk_upper = (chunk_addr >> 16) as u16;
+        if upper != Some(chunk_upper) {
+            out += &record(0x04, 0, &chunk_upper.to_be_bytes());
+            upper = Some(chunk_upper);
+        }
+        out += &record(0x00, chunk_addr as u16, chunk);
+    }
+    out += &record(0x01, 0, &[]);
+    out
+}
+
 fn reset(port: &mut Box<dyn SerialPort>) -> Result<()> {
     // let mut raw_buf = [0u8; 256];
     // let msg = HostToDeviceMessages::Reset;
@@ -103,6 +281,88 @@ fn reset(port: &mut Box<dyn SerialPort>) -> Result<()> {
     todo!()
 }
 
+fn send_cmd(port: &mut Box<dyn SerialPort>, cmd: PairingCommand) -> Result<()> {
+    let mut raw_buf = [0u8; 64];
+    let slice = postcard::to_slice_cobs(&PcToModem::Pairing(cmd), &mut raw_buf)
+        .map_err(|_| Error::from("Failed to encode command"))?;
+    port.write_all(slice)?;
+    port.flush()?;
+    Ok(())
+}
+
+/// Wait for the next pairing event from the modem
+fn next_event(
+    port: &mut Box<dyn SerialPort>,
+    cobs_buf: &mut Buffer<U256>,
+    events: &mut VecDeque<PairingEvent>,
+) -> Result<PairingEvent> {
+    let mut raw_buf = [0u8; 256];
+
+    loop {
+        if let Some(event) = events.pop_front() {
+            return Ok(event);
+        }
+
+        let ct = match port.read(&mut raw_buf) {
+            Ok(ct) => ct,
+            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                return Err(Error::from("BAD SERIAL ERROR"));
+            }
+        };
+
+        let mut window = &raw_buf[..ct];
+        'cobs: while !window.is_empty() {
+            use FeedResult::*;
+            window = match cobs_buf.feed::<ModemToPc>(&window) {
+                Consumed => break 'cobs,
+                OverFull(new_wind) => new_wind,
+                DeserError(new_wind) => new_wind,
+                Success { data, remaining } => {
+                    if let ModemToPc::Pairing(event) = data {
+                        events.push_back(event);
+                    }
+                    remaining
+                }
+            };
+        }
+    }
+}
+
+fn pair(port: &mut Box<dyn SerialPort>) -> Result<()> {
+    let mut cobs_buf: Buffer<U256> = Buffer::new();
+    let mut events = VecDeque::new();
+
+    send_cmd(port, PairingCommand::Start)?;
+    println!("Waiting for a device in pairing mode...");
+
+    loop {
+        match next_event(port, &mut cobs_buf, &mut events)? {
+            PairingEvent::Started => {}
+            PairingEvent::Requested { device_id } => {
+                print!("Enter the code printed by device {:016X}: ", device_id);
+                io::stdout().flush()?;
+
+                let mut line = String::new();
+                io::stdin().read_line(&mut line)?;
+                match line.trim().parse::<u32>() {
+                    Ok(code) => send_cmd(port, PairingCommand::Confirm { code })?,
+                    Err(_) => {
+                        send_cmd(port, PairingCommand::Cancel)?;
+                        return Err(Error::from("Expected a numeric code"));
+                    }
+                }
+            }
+            PairingEvent::Paired { device_id, pipe } => {
+                println!("Paired device {:016X} on pipe {}", device_id, pipe);
+                return Ok(());
+            }
+            PairingEvent::Failed => return Err(Error::from("Pairing failed")),
+        }
+    }
+}
+
 fn log(port: &mut Box<dyn SerialPort>) -> Result<()> {
     let mut cobs_buf: Buffer<U256> = Buffer::new();
     let mut raw_buf = [0u8; 256];
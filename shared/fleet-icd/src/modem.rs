@@ -5,10 +5,61 @@ use serde::{Deserialize, Serialize};
 pub enum PcToModem {
     Outgoing { pipe: u8, msg: HostToDevice },
     Ping,
+    Pairing(PairingCommand),
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub enum ModemToPc {
-    Incoming { pipe: u8, msg: DeviceToHost },
+    Incoming {
+        pipe: u8,
+        msg: DeviceToHost,
+    },
     Pong,
+    Pairing(PairingEvent),
+
+    /// The modem's UART has seen line errors since the last report
+    LineErrors(UartErrors),
+}
+
+/// UART line errors seen by the modem on its link to the PC, since startup.
+/// The counters wrap on overflow.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+pub struct UartErrors {
+    pub overrun: u32,
+    pub parity: u32,
+    pub framing: u32,
+    pub break_condition: u32,
+}
+
+/// Sent by `fleet-cli pair` to pair a new device
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub enum PairingCommand {
+    /// Accept pairing requests for a while
+    Start,
+
+    /// The code printed by the device over RTT
+    Confirm {
+        code: u32,
+    },
+
+    Cancel,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub enum PairingEvent {
+    Started,
+
+    /// A device has asked to pair, and is showing its code
+    Requested {
+        device_id: u64,
+    },
+
+    /// The device has stored its assignment
+    Paired {
+        device_id: u64,
+        pipe: u8,
+    },
+
+    /// The code didn't match, no pipes are free, or pairing timed out
+    Failed,
 }
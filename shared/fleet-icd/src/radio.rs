@@ -1,6 +1,18 @@
 use core::convert::TryFrom;
 use serde::{Deserialize, Serialize};
 
+/// Identifies this fleet on the air. Authenticated as part of every radio frame,
+/// so frames from another fleet sharing the same key are rejected.
+pub const FLEET_NETWORK_ID: u32 = 0xF1EE_7001;
+
+/// The ESB base addresses: the first for pipe 0, the second for all others
+pub const BASE_ADDRESSES: [[u8; 4]; 2] = [[0xE7, 0xE7, 0xE7, 0xE7], [0xC2, 0xC2, 0xC2, 0xC2]];
+
+/// The ESB address prefix of each pipe
+pub const ADDRESS_PREFIXES: [u8; 8] = [0xE7, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8];
+
+pub const RF_CHANNEL: u8 = 8;
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub enum HostToDevice {
     General(GeneralHostMessage),
@@ -16,15 +28,63 @@ pub enum DeviceToHost {
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub enum GeneralHostMessage {
     Ping,
+
+    /// Response to `InitializeSession`, containing the host's session nonce
+    SessionAccepted {
+        nonce: [u8; 16],
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub enum GeneralDeviceMessage {
     Pong,
-    InitializeSession,
+
+    /// Request a new session, containing the device's session nonce
+    InitializeSession {
+        nonce: [u8; 16],
+    },
     MessageRequest,
 }
 
+/// Sent by a device on the pairing pipe, see `fleet_esb::pairing`
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub enum PairingDeviceMessage {
+    Request {
+        device_id: u64,
+        public: [u8; 32],
+    },
+    Nonce {
+        nonce: [u8; 16],
+    },
+
+    /// The device has stored its assignment
+    Paired {
+        confirm: [u8; 16],
+    },
+}
+
+/// Sent by the modem on the pairing pipe, see `fleet_esb::pairing`
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub enum PairingHostMessage {
+    Commit {
+        public: [u8; 32],
+        commitment: [u8; 32],
+    },
+    Reveal {
+        nonce: [u8; 16],
+    },
+
+    /// The pipe, address and encrypted key assigned to the device
+    Assign {
+        pipe: u8,
+        base: [u8; 4],
+        prefix: u8,
+        key_id: u32,
+        key: [u8; 32],
+        tag: [u8; 16],
+    },
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub enum PlantLightDeviceMessage {
     Status(ShelfStatus),